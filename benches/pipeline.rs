@@ -0,0 +1,191 @@
+// Throughput of the defenestrator processing, single threaded and
+// threaded (see pipeline.rs), with the flush policy defenestrator uses by default
+// (glom::DEFAULT_BATCH_FLUSH).  For comparison, the baseline is the loop defenestrator
+// had before the pipeline:  each item converted in turn with the event flushed at the
+// end of each frame and the sink flushed after every item.
+//
+// Usage:
+//    cargo bench --bench pipeline [-- frames]
+//
+// Synthetic mikumari frame ring items (frames of them, default 20000) are made in memory
+// and run through both paths writing to a sink that throws the items away and to a
+// file in the temporary directory.  The rate of each is printed.
+//
+use mikumarimaker::{glom, mikumari_format, pipeline};
+use frib_datasource::{data_sink_factory, DataSink, DataSource};
+use rust_ringitem_format::{body_header_size, RingItem, BEGIN_RUN, END_RUN};
+use std::mem::size_of;
+use std::time::Instant;
+
+const HITS_PER_FRAME : u32 = 200;
+const DEFAULT_FRAMES : usize = 20000;
+
+// Gives out ring items made in memory.
+
+struct VecSource {
+    items : std::vec::IntoIter<RingItem>,
+}
+impl DataSource for VecSource {
+    fn open(&mut self, _uri: &str) -> Result<(), String> {Ok(())}
+    fn read(&mut self) -> Option<RingItem> {
+        self.items.next()
+    }
+    fn close(&mut self) {}
+}
+// Throws items away.
+
+struct NullSink {}
+impl DataSink for NullSink {
+    fn open(&mut self, _uri: &str) -> Result<(), String> {Ok(())}
+    fn write(&mut self, _item : &RingItem) -> Result<(), String> {Ok(())}
+    fn close(&mut self) {}
+}
+
+// Make a run of frames with hits at pseudo random times (a linear
+// congruential generator keeps the runs the same).
+
+fn make_run(frames : usize) -> Vec<RingItem> {
+    let mut seed = 12345u64;
+    let mut random = move || {
+        seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        (seed >> 33) as u32
+    };
+    let mut items = vec![RingItem::new_with_body_header(BEGIN_RUN, 0, 1, 1)];
+    for frame in 0..frames as u64 {
+        let mut item = RingItem::new_with_body_header(
+            mikumari_format::MIKUMARI_FRAME_ITEM_TYPE, frame << 19, 1, 0
+        );
+        item.add(frame);
+        item.add(mikumari_format::Delimeter1::new(0, frame as u32).get());
+        for _ in 0..HITS_PER_FRAME {
+            let chan = (random() % 64) as u8;
+            let time = random() % (1 << 19);
+            if random() % 2 == 0 {
+                item.add(mikumari_format::HRTDCLeading::new(chan, 100, time).get());
+            } else {
+                item.add(mikumari_format::HRTDCTrailing::new(chan, 100, time).get());
+            }
+        }
+        items.push(item);
+    }
+    items.push(RingItem::new_with_body_header(END_RUN, 0, 1, 2));
+    items
+}
+
+fn make_builder(sink : Box<dyn DataSink>) -> pipeline::EventBuilder {
+    let mut glom = glom::Glom::new(sink, 1, 1000);
+    glom.set_out_of_order_policy(glom::OutOfOrderPolicy::Drop);
+    glom.set_flush_policy(glom::DEFAULT_BATCH_FLUSH);
+    pipeline::EventBuilder::new(glom, None)
+}
+
+// The original defenestrator conversion of one item.
+
+fn convert_item(item : &RingItem, glom : &mut glom::Glom) {
+    let item_type = item.type_id();
+    if item_type != mikumari_format::MIKUMARI_FRAME_ITEM_TYPE {
+        if item_type == BEGIN_RUN {
+            if let Some(bh) = item.get_bodyheader() {
+                glom.set_sid(bh.source_id);
+            }
+        } else if item_type == END_RUN {
+            glom.flush().expect("Baseline flush failed");
+        }
+        glom.write_item(item).expect("Baseline write failed");
+    } else {
+        let bh = item.get_bodyheader().unwrap();
+        let t0 = bh.timestamp;
+        let payload = item.payload();
+        glom.set_sid(bh.source_id);
+
+        let mut cursor = body_header_size();
+        let absolute_fno = u64::from_ne_bytes(payload[cursor..cursor+size_of::<u64>()].try_into().unwrap());
+        glom.add_frame_boundary(absolute_fno, t0, 0);
+
+        let mut orderer = glom::Orderer::new();
+        cursor += size_of::<u64>();
+        while cursor < payload.len() {
+            let raw = u64::from_ne_bytes(payload[cursor..cursor+size_of::<u64>()].try_into().unwrap());
+            match mikumari_format::MikumariDatum::from_u64(raw) {
+                mikumari_format::MikumariDatum::LeadingEdge(le)  => {
+                    orderer.add_hit(true, le.channel() as u16, le.time() as u64 + t0, le.tot());
+                },
+                mikumari_format::MikumariDatum::TrailingEdge(te) => {
+                    orderer.add_hit(false, te.channel() as u16, te.time() as u64 + t0, te.tot());
+                },
+                _ => {},
+            }
+            cursor += size_of::<u64>();
+        }
+        for (rising, chan, time, tot) in orderer.order() {
+            glom.add_hit(rising, glom::ChannelId::new(bh.source_id, chan), time, tot).expect("Baseline hit failed");
+        }
+        glom.flush().expect("Baseline flush failed");
+    }
+}
+
+// Time one pass; returns the seconds taken and the events written.
+
+fn baseline(items : Vec<RingItem>, sink : Box<dyn DataSink>) -> (f64, u64) {
+    let start = Instant::now();
+    let mut glom = glom::Glom::new(sink, 1, 1000);
+    glom.set_out_of_order_policy(glom::OutOfOrderPolicy::Drop);
+    glom.set_flush_policy(glom::FlushPolicy::EveryItem);
+    for item in &items {
+        convert_item(item, &mut glom);
+    }
+    let events = glom.statistics().events_written;
+    drop(glom);
+    (start.elapsed().as_secs_f64(), events)
+}
+
+fn single(items : Vec<RingItem>, sink : Box<dyn DataSink>) -> (f64, u64) {
+    let start = Instant::now();
+    let mut source = VecSource { items : items.into_iter() };
+    let mut builder = make_builder(sink);
+    pipeline::run_single(&mut source, &mut pipeline::Decoder::new(), &mut builder).expect("Single threaded run failed");
+    let events = builder.statistics().events_written;
+    drop(builder);
+    (start.elapsed().as_secs_f64(), events)
+}
+fn threaded<K>(items : Vec<RingItem>, open_sink : K) -> (f64, u64)
+where K : FnOnce() -> Result<Box<dyn DataSink>, String> + Send
+{
+    let start = Instant::now();
+    let stats = pipeline::run_threaded(
+        move || Ok(Box::new(VecSource { items : items.into_iter() }) as Box<dyn DataSource>),
+        open_sink,
+        &mut pipeline::Decoder::new(), make_builder, pipeline::DEFAULT_QUEUE_DEPTH
+    ).expect("Threaded run failed");
+    (start.elapsed().as_secs_f64(), stats.glom.events_written)
+}
+
+fn report(name : &str, frames : usize, bytes : u64, (seconds, events) : (f64, u64)) {
+    println!(
+        "{:<24} {:>8.3} s  {:>10.0} frames/s  {:>8.1} MB/s  {} events",
+        name, seconds, frames as f64 / seconds, bytes as f64 / seconds / 1.0e6, events
+    );
+}
+
+fn main() {
+    let frames = std::env::args().skip(1)
+        .find_map(|a| a.parse::<usize>().ok())
+        .unwrap_or(DEFAULT_FRAMES);
+    let bytes : u64 = make_run(frames).iter().map(|i| i.size() as u64).sum();
+    println!("{} frames of {} hits, {} bytes in", frames, HITS_PER_FRAME, bytes);
+
+    let path = std::env::temp_dir().join("defenestrator-bench.evt");
+    let uri = format!("file://{}", path.display());
+
+    report("baseline, null sink", frames, bytes, baseline(make_run(frames), Box::new(NullSink {})));
+    report("single, null sink", frames, bytes, single(make_run(frames), Box::new(NullSink {})));
+    report("threaded, null sink", frames, bytes,
+        threaded(make_run(frames), || Ok(Box::new(NullSink {}) as Box<dyn DataSink>)));
+    report("baseline, file sink", frames, bytes,
+        baseline(make_run(frames), data_sink_factory(&uri).expect("Could not open the output file")));
+    report("single, file sink", frames, bytes,
+        single(make_run(frames), data_sink_factory(&uri).expect("Could not open the output file")));
+    report("threaded, file sink", frames, bytes, threaded(make_run(frames), || data_sink_factory(&uri)));
+
+    let _ = std::fs::remove_file(&path);
+}
@@ -0,0 +1,14 @@
+#![no_main]
+// Decodes arbitrary bytes as the body of an event.  The first 8 bytes are the
+// body header timestamp the hit times are relative to.  Decoding must never
+// panic:  bodies that can't be decoded are errors.
+
+use libfuzzer_sys::fuzz_target;
+use mikumarimaker::glom;
+
+fuzz_target!(|data: &[u8]| {
+    let Some((stamp, body)) = data.split_first_chunk::<8>() else {
+        return;
+    };
+    let _ = glom::decode_event(body, u64::from_ne_bytes(*stamp));
+});
@@ -0,0 +1,27 @@
+#![no_main]
+// Decodes arbitrary bytes as the body of a frame item.  The first byte picks
+// whether the item has a body header and whether the decoder is in multi source
+// mode.  Decoding must never panic:  bad items are GlomOp::BadItem.
+
+use libfuzzer_sys::fuzz_target;
+use mikumarimaker::mikumari_format::MIKUMARI_FRAME_ITEM_TYPE;
+use mikumarimaker::pipeline::Decoder;
+use rust_ringitem_format::RingItem;
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&mode, body)) = data.split_first() else {
+        return;
+    };
+    let mut item = if mode & 1 != 0 {
+        RingItem::new_with_body_header(MIKUMARI_FRAME_ITEM_TYPE, 0, 1, 0)
+    } else {
+        RingItem::new(MIKUMARI_FRAME_ITEM_TYPE)
+    };
+    for b in body {
+        item.add(*b);
+    }
+    let mut decoder = Decoder::new();
+    decoder.set_multi_source(mode & 2 != 0);
+    decoder.decode(item);
+    decoder.finish();
+});
@@ -0,0 +1,64 @@
+#![no_main]
+// Decodes arbitrary bytes as a stream of ring items, the way defenestrator reads
+// its input, and builds events from them.  The first byte picks multi source mode
+// and pairing.  Nothing may panic and every event written must decode.
+
+use frib_datasource::DataSink;
+use libfuzzer_sys::fuzz_target;
+use mikumarimaker::glom;
+use mikumarimaker::pipeline::{self, Decoder, EventBuilder};
+use rust_ringitem_format::{body_header_size, RingItem, PHYSICS_EVENT};
+
+// A sink that checks the events it's given decode:
+
+struct CheckingSink {}
+impl DataSink for CheckingSink {
+    fn open(&mut self, _uri: &str) -> Result<(), String> {Ok(())}
+    fn write(&mut self, item : &RingItem) -> Result<(), String> {
+        if item.type_id() == PHYSICS_EVENT {
+            let stamp = pipeline::body_header(item).expect("Events have body headers").timestamp;
+            let payload = item.payload();
+            glom::decode_event(&payload[body_header_size()..], stamp).expect("Events written must decode");
+        }
+        Ok(())
+    }
+    fn close(&mut self) {}
+}
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&mode, mut stream)) = data.split_first() else {
+        return;
+    };
+    let mut decoder = Decoder::new();
+    decoder.set_multi_source(mode & 1 != 0);
+    let mut glom = glom::Glom::new(Box::new(CheckingSink {}), 0, 100);
+    glom.set_out_of_order_policy(glom::OutOfOrderPolicy::Drop);
+    let pairer = if mode & 2 != 0 { Some(glom::Pairer::new(30)) } else { None };
+    let mut builder = EventBuilder::new(glom, pairer);
+    builder.set_warning_handler(Box::new(|_| {}));
+
+    loop {
+        // Don't let a huge size field allocate more than is left:
+
+        let Some(size) = stream.get(..4).map(|s| u32::from_ne_bytes(s.try_into().unwrap())) else {
+            break;
+        };
+        if size as usize > stream.len() {
+            break;
+        }
+        let Ok(item) = RingItem::read_item(&mut stream) else {
+            break;
+        };
+        if apply(&mut builder, decoder.decode(item)).is_err() {
+            return;                 // e.g. a hit too far from its event for the layout.
+        }
+    }
+    let _ = apply(&mut builder, decoder.finish());
+});
+
+fn apply(builder : &mut EventBuilder, ops : Vec<pipeline::GlomOp>) -> pipeline::PipelineResult<()> {
+    for op in ops {
+        builder.apply(op)?;
+    }
+    Ok(())
+}
@@ -1,23 +1,77 @@
-use mikumarimaker::{mikumari_format, glom};
-use rust_ringitem_format::{RingItem, BEGIN_RUN, END_RUN};
+use mikumarimaker::{glom, calibration, dnl, mikumari_format, pipeline, report};
 use frib_datasource::{data_source_factory,  data_sink_factory};
-use std::mem::size_of;
-use clap::{value_parser, Arg, ArgAction, Command};
+use std::fs::File;
+use std::io::Write;
+use std::process::exit;
+use std::time::Duration;
+use clap::{value_parser, Arg, ArgAction, ArgMatches, Command};
 
 
 // Ring items generated will  be PHYSICS_EVENT 
 // Output will be 
-// | Absolute frame number | 64 bits.
+// | layout id             | 16 bits (see glom::HitLayout, this is for --layout legacy).
+// | header size           | 16 bits, bytes in the header (32).
+// | event flags           | 32 bits, 1 - hits in more than one frame, 2 - has out of order hits.
+// | hit count             | 32 bits, frame boundaries are not counted.
+// | reserved              | 32 bits.
+// | first frame           | 64 bits, absolute frame number of the first hit.
+// | last frame            | 64 bits, absolute frame number of the last hit.
 // zero or more repetitions of hits of the form:
-// | r/f channel           | 16 bits, top bit is 1 for trailing edge.
+// | flags                 | 16 bits, top bit is 1 for trailing edge, 0xffff for a frame boundary.
+// | channel               | 16 bits.
+// | source id             | 32 bits, source id of the frame the hit came from.
 // | absolute-time         | 64 bits. computed by adding the timestamp to the hit time.
+// | time over threshold   | 32 bits.
+// Frame boundaries are hits with flags, channel and source all ones
+// followed by the absolute frame number (64 bits), the frame timestamp (64 bits) and
+// frame flags (32 bits: heartbeat delimiter 1 flags | delimiter 2 user flags << 16).
 //
 //   If I've done arithmetic properly, it's 213 days before the absolute time should
 //   wrap.
 // Usage:
-//    defenestrator --dt coincidence-interval sourced sink.
+//    defenestrator [--mode mode] --dt coincidence-interval [--out-of-order policy] sourced sink.
+//    defenestrator --mode trigger --trigger-channels c1,c2... [--pre ticks] [--post ticks] source sink.
 //
-// Source and sink are URI's --dt is in tdc units.
+// Source and sink are URI's --dt, --pre and --post are in tdc units.
+// --mode is one of fixed, sliding or trigger (default fixed).
+// --min-hits, --max-hits, --require-channels and --ignore-channels select which events are written.
+// Channels are given as channel (any source) or source:channel.
+// --pair-width pairs leading and trailing edges no further apart than its value (tdc units).
+// --out-of-order is one of error, drop or include (default glom::OutOfOrderPolicy::default(), drop).
+// --layout is one of legacy, compact or aligned (default legacy) see glom::HitLayout.
+// --crossing-frames-only leaves frame boundaries out of events that are all in one frame.
+// --calibration is a calibration file (see calibration.rs) applied to hit times and TOTs
+//   before they are ordered.  The calibration is written as a MONITORED_VARIABLES item
+//   after each begin run.
+// --dnl is a DNL lookup table file made by mikumaridnl.  The fine times of the hits are
+//   corrected with it before the calibration is applied.
+// --source-id forces the source id of the events written; by default it's that of the input.
+// --sources is a comma separated list of the source ids whose frames are converted (default all).
+// --drop-other-items drops items that are not converted (other than begin, end, pause and
+//   resume run) rather than passing them through.
+// --frame-type is the ring item type of the frames (default 51, MIKUMARI_FRAME_ITEM_TYPE).
+// Frame items that can't be decoded are reported, counted and skipped.  --strict stops
+//   at the first one instead, giving its byte offset in the input.
+// --multi-source merges frames from several source ids into one time ordered stream
+//   so events are built across boards.  Each hit keeps its source id.  End runs are
+//   written once all sources have ended their runs.  --max-source-lag is how many frames
+//   a source can fall behind the others before it is no longer waited for (default 2048).
+// Events are written at pauses, end runs and the end of the input.  The counts of each run
+//   are written as a MONITORED_VARIABLES item before its end run (see pipeline::EventBuilder).
+// Reading, decoding, event building and writing each run in their own thread
+//   (see pipeline.rs).  --single-thread does them all on one thread and --queue-depth
+//   is the number of items queued between the threads (default 64).
+// --flush is when the sink is flushed: always (every item, for tcp rings), items:n, bytes:n
+//   (the default is bytes:1048576, see glom::DEFAULT_BATCH_FLUSH) or ms:n.  With ms:n the
+//   sink is also flushed when no input has come for n ms (not with --single-thread).  The sink
+//   is always flushed after end runs and at exit.
+// A report of what was done (frames, hits by channel and edge, events, multiplicities...
+//   see report.rs) is written to stderr at the end of each run and, for all runs, at exit.
+//   --report-json also writes the reports to a file as one JSON object per line;
+//   the one for all runs is last and has a null run number.
+// --monitor writes a status line with the frame, hit (total and per channel) and event rates,
+//   the hits held (backlog) and the dropped/rejected counts to stderr every so many seconds.
+//   It can't be used with --single-thread, which can't write status lines while waiting for input.
 //
 fn main() {
     // Define the command line parameter for clap:
@@ -25,10 +79,160 @@ fn main() {
     let parser = Command::new("defenestrator")
         .version("0.2.0").about("Defenestrates mikumari time data (AMANEQ)")
         .arg(Arg::new("dt")
-            .short('t').long("dt").required(true).help("Coincidence interval")
+            .short('t').long("dt").required(false).help("Coincidence interval (fixed and sliding modes)")
             .action(ArgAction::Set)
             .value_parser(value_parser!(u64))
         )
+        .arg(Arg::new("mode")
+            .short('m').long("mode").required(false).default_value("fixed")
+            .help("Coincidence mode")
+            .action(ArgAction::Set)
+            .value_parser(["fixed", "sliding", "trigger"])
+        )
+        .arg(Arg::new("trigger-channels")
+            .long("trigger-channels").required(false)
+            .help("Comma separated channels (channel or source:channel) that open events in trigger mode")
+            .action(ArgAction::Append).value_delimiter(',')
+            .value_parser(|s: &str| s.parse::<glom::ChannelId>())
+        )
+        .arg(Arg::new("pre")
+            .long("pre").required(false).default_value("0")
+            .help("Ticks before a trigger that are in the event")
+            .action(ArgAction::Set)
+            .value_parser(value_parser!(u64))
+        )
+        .arg(Arg::new("post")
+            .long("post").required(false).default_value("0")
+            .help("Ticks after a trigger that are in the event")
+            .action(ArgAction::Set)
+            .value_parser(value_parser!(u64))
+        )
+        .arg(Arg::new("min-hits")
+            .long("min-hits").required(false).default_value("0")
+            .help("Fewest hits in an event that is written")
+            .action(ArgAction::Set)
+            .value_parser(value_parser!(usize))
+        )
+        .arg(Arg::new("max-hits")
+            .long("max-hits").required(false)
+            .help("Most hits in an event that is written")
+            .action(ArgAction::Set)
+            .value_parser(value_parser!(usize))
+        )
+        .arg(Arg::new("require-channels")
+            .long("require-channels").required(false)
+            .help("Comma separated channels (channel or source:channel) that must all have hits in an event that is written")
+            .action(ArgAction::Append).value_delimiter(',')
+            .value_parser(|s: &str| s.parse::<glom::ChannelId>())
+        )
+        .arg(Arg::new("ignore-channels")
+            .long("ignore-channels").required(false)
+            .help("Comma separated channels that don't count towards --min-hits, --max-hits and --require-channels")
+            .action(ArgAction::Append).value_delimiter(',')
+            .value_parser(|s: &str| s.parse::<glom::ChannelId>())
+        )
+        .arg(Arg::new("pair-width")
+            .long("pair-width").required(false)
+            .help("Pair leading and trailing edges at most this many ticks apart")
+            .action(ArgAction::Set)
+            .value_parser(value_parser!(u32))
+        )
+        .arg(Arg::new("out-of-order")
+            .long("out-of-order").required(false)
+            .help("What to do with out of order hits (default drop, see glom::OutOfOrderPolicy)")
+            .action(ArgAction::Set)
+            .value_parser(["error", "drop", "include"])
+        )
+        .arg(Arg::new("calibration")
+            .short('c').long("calibration").required(false)
+            .help("Per channel time offset and TOT calibration file")
+            .action(ArgAction::Set)
+        )
+        .arg(Arg::new("dnl")
+            .long("dnl").required(false)
+            .help("HR TDC DNL lookup table file (from mikumaridnl)")
+            .action(ArgAction::Set)
+        )
+        .arg(Arg::new("layout")
+            .short('l').long("layout").required(false).default_value("legacy")
+            .help("How hits are encoded in the events")
+            .action(ArgAction::Set)
+            .value_parser(["legacy", "compact", "aligned"])
+        )
+        .arg(Arg::new("crossing-frames-only")
+            .long("crossing-frames-only").required(false)
+            .help("Only write frame boundaries in events whose hits are in more than one frame")
+            .action(ArgAction::SetTrue)
+        )
+        .arg(Arg::new("flush")
+            .long("flush").required(false)
+            .help("When the sink is flushed: always, items:n, bytes:n or ms:n (default bytes:1048576)")
+            .action(ArgAction::Set)
+            .value_parser(|s: &str| s.parse::<glom::FlushPolicy>())
+        )
+        .arg(Arg::new("source-id")
+            .short('s').long("source-id").required(false)
+            .help("Source id of the events written (default: that of the input)")
+            .action(ArgAction::Set)
+            .value_parser(value_parser!(u32))
+        )
+        .arg(Arg::new("sources")
+            .long("sources").required(false)
+            .help("Comma separated source ids whose frames are converted (default: all)")
+            .action(ArgAction::Append).value_delimiter(',')
+            .value_parser(value_parser!(u32))
+        )
+        .arg(Arg::new("drop-other-items")
+            .long("drop-other-items").required(false)
+            .help("Drop items that are not converted rather than passing them through")
+            .action(ArgAction::SetTrue)
+        )
+        .arg(Arg::new("frame-type")
+            .long("frame-type").required(false)
+            .default_value("51")
+            .help("Ring item type of the mikumari frame items")
+            .action(ArgAction::Set)
+            .value_parser(value_parser!(u32))
+        )
+        .arg(Arg::new("strict")
+            .long("strict").required(false)
+            .help("Stop at the first frame item that can't be decoded rather than skipping it")
+            .action(ArgAction::SetTrue)
+        )
+        .arg(Arg::new("multi-source")
+            .long("multi-source").required(false)
+            .help("Merge the frames of all source ids into one time ordered stream")
+            .action(ArgAction::SetTrue)
+        )
+        .arg(Arg::new("max-source-lag")
+            .long("max-source-lag").required(false).default_value("2048")
+            .help("With --multi-source, frames a source can fall behind the others before it is not waited for")
+            .action(ArgAction::Set)
+            .value_parser(value_parser!(u64).range(1..=1 << 32))
+        )
+        .arg(Arg::new("single-thread")
+            .long("single-thread").required(false)
+            .help("Do all of the processing on one thread")
+            .action(ArgAction::SetTrue)
+        )
+        .arg(Arg::new("queue-depth")
+            .long("queue-depth").required(false).default_value("64")
+            .help("Number of items queued between the processing threads")
+            .action(ArgAction::Set)
+            .value_parser(value_parser!(u64).range(1..))
+        )
+        .arg(Arg::new("report-json")
+            .long("report-json").required(false)
+            .help("File the run reports are written to as JSON (one object per line)")
+            .action(ArgAction::Set)
+        )
+        .arg(Arg::new("monitor")
+            .long("monitor").required(false)
+            .help("Seconds between status lines with the current rates (not with --single-thread)")
+            .action(ArgAction::Set)
+            .conflicts_with("single-thread")
+            .value_parser(value_parser!(u64).range(1..))
+        )
         .arg(Arg::new("source").required(true).help("Data Source URI"))
         .arg(Arg::new("sink").required(true).help("Data Sink URI"));
 
@@ -39,22 +243,103 @@ fn main() {
 
     let ring_uri = matches.get_one::<String>("source").expect("No data source given");
     let out_path = matches.get_one::<String>("sink").expect("No data sink given");
-    let glom_dt = matches.get_one::<u64>("dt").expect("No --dt given for gloming");
+    let mode = get_mode(&matches);
+    let glom_dt = match (matches.get_one::<u64>("dt"), &mode) {
+        (Some(dt), _) => *dt,
+        (None, glom::CoincidenceMode::Trigger { .. }) => 0,
+        (None, _) => {
+            eprintln!("--dt is required in fixed and sliding modes");
+            exit(-1);
+        }
+    };
+    let policy = match matches.get_one::<String>("out-of-order").map(|p| p.as_str()) {
+        Some("error") => glom::OutOfOrderPolicy::Error,
+        Some("include") => glom::OutOfOrderPolicy::Include,
+        Some(_) => glom::OutOfOrderPolicy::Drop,
+        None => glom::OutOfOrderPolicy::default(),
+    };
+    let layout = match matches.get_one::<String>("layout").expect("No default layout").as_str() {
+        "compact" => glom::HitLayout::Compact,
+        "aligned" => glom::HitLayout::Aligned,
+        _ => glom::HitLayout::Legacy,
+    };
 
-    // open the source:
+    let mut decoder = pipeline::Decoder::new();
+    decoder.set_multi_source(matches.get_flag("multi-source"));
+    let max_lag = *matches.get_one::<u64>("max-source-lag").expect("No default max-source-lag");
+    decoder.set_max_source_lag(max_lag * mikumari_format::HEARTBEAT_PERIOD_TICKS);
+    decoder.set_frame_type(*matches.get_one::<u32>("frame-type").expect("No default frame-type"));
+    decoder.set_sources(matches.get_many::<u32>("sources").map(|s| s.copied().collect()));
+    decoder.set_output_sid(matches.get_one::<u32>("source-id").copied());
+    decoder.set_drop_others(matches.get_flag("drop-other-items"));
+    let sid = matches.get_one::<u32>("source-id").copied().unwrap_or(0);
+    if let Some(path) = matches.get_one::<String>("calibration") {
+        decoder.set_calibration(calibration::Calibration::from_file(path).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            exit(-1);
+        }));
+    }
+    if let Some(path) = matches.get_one::<String>("dnl") {
+        decoder.set_dnl(dnl::DnlCorrection::from_file(path).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            exit(-1);
+        }));
+    }
 
-    let mut source = data_source_factory(&ring_uri).expect("Could not open ring item source");
-    let sink   = data_sink_factory(&out_path).expect("Could not open ring item sink");
-    
-    // Create the glommer:
+    // How to create the glommer given its sink:
 
-    let mut glom = glom::Glom::new(sink, 0, *glom_dt);
+    let crossing_frames_only = matches.get_flag("crossing-frames-only");
+    let flush_policy = matches.get_one::<glom::FlushPolicy>("flush").copied().unwrap_or(glom::DEFAULT_BATCH_FLUSH);
+    let filter = get_filter(&matches);
+    let pair_width = matches.get_one::<u32>("pair-width").copied();
+    let strict = matches.get_flag("strict");
+    let mut json = matches.get_one::<String>("report-json").map(|path| {
+        File::create(path).unwrap_or_else(|e| {
+            eprintln!("Could not create {}: {}", path, e);
+            exit(-1);
+        })
+    });
+    let monitor = matches.get_one::<u64>("monitor").map(|s| Duration::from_secs(*s));
+    let mut run_json = json.as_ref().map(|f| f.try_clone().expect("Could not share the report file"));
+    let make_builder = move |sink| {
+        let mut glom = glom::Glom::new(sink, sid, glom_dt);
+        glom.set_out_of_order_policy(policy);
+        glom.set_layout(layout);
+        glom.set_crossing_frames_only(crossing_frames_only);
+        glom.set_flush_policy(flush_policy);
+        glom.set_mode(mode);
+        glom.set_filter(filter);
+        let mut builder = pipeline::EventBuilder::new(glom, pair_width.map(glom::Pairer::new));
+        builder.set_strict(strict);
+        builder.set_report_handler(Box::new(move |run| write_report(run, run_json.as_mut())));
+        if let Some(interval) = monitor {
+            builder.set_monitor(interval, Box::new(|line| eprintln!("Status: {}", line)));
+        }
+        builder
+    };
 
     // Process the items.
 
-    while let Some(item) = source.read() {
-        convert_item(&item, &mut glom);
-    }
+    let result = if matches.get_flag("single-thread") {
+        let mut source = data_source_factory(ring_uri).expect("Could not open ring item source");
+        let sink   = data_sink_factory(out_path).expect("Could not open ring item sink");
+        let mut builder = make_builder(sink);
+        pipeline::run_single(source.as_mut(), &mut decoder, &mut builder)
+            .map(|_| builder.report())
+            .map_err(|e| e.to_string())
+    } else {
+        pipeline::run_threaded(
+            || data_source_factory(ring_uri),
+            || data_sink_factory(out_path),
+            &mut decoder, make_builder,
+            *matches.get_one::<u64>("queue-depth").expect("No default queue-depth") as usize
+        )
+    };
+    let report = result.unwrap_or_else(|e| {
+        eprintln!("defenestrator failed: {}", e);
+        exit(-1);
+    });
+    write_report(&report, json.as_mut());
 
 
     // For mikumari data, each frame -> a defenestrated frame.
@@ -64,77 +349,47 @@ fn main() {
 
 }
 
+// Write a report as text on stderr and, if there's a file, as JSON to it.
 
-fn convert_item(item : &RingItem, glom  : &mut glom::Glom) {
-    // if the ring item is not a MIKUMARI frame, just pass it unaltered.
-
-    let item_type = item.type_id();
-    if item_type != mikumari_format::MIKUMARI_FRAME_ITEM_TYPE {
-        // some are special:
+fn write_report(report : &report::RunReport, json : Option<&mut File>) {
+    eprint!("{}", report.to_text());
+    if let Some(file) = json && let Err(e) = writeln!(file, "{}", report.to_json()) {
+        eprintln!("Could not write the JSON report: {}", e);
+    }
+}
 
-        
-        if item_type == BEGIN_RUN {
-            // Set the source id from the ring item.
+// Build the coincidence mode from the --mode, --trigger-channels, --pre and --post options.
 
-            if let Some(bh) = item.get_bodyheader() {
-                glom.set_sid(bh.source_id);
+fn get_mode(matches : &ArgMatches) -> glom::CoincidenceMode {
+    match matches.get_one::<String>("mode").expect("No default mode").as_str() {
+        "sliding" => glom::CoincidenceMode::Sliding,
+        "trigger" => {
+            let channels : Vec<glom::ChannelId> = matches.get_many::<glom::ChannelId>("trigger-channels")
+                .map(|c| c.copied().collect())
+                .unwrap_or_default();
+            if channels.is_empty() {
+                eprintln!("--trigger-channels is required in trigger mode");
+                exit(-1);
             }
-
-        } else if item_type == END_RUN {     // Flush event being built at end run.
-            glom.flush();
-        }
-        glom.write_item(item)
-
-    } else {
-
-        let bh = item.get_bodyheader().unwrap();
-        let t0 = bh.timestamp;
-        let payload = item.payload();    // Vec<u8>
-        glom.set_sid(bh.source_id);      // In case there's no BEGIN e.g.
-
-        
-        // We are assured there's an absolute frame number (64 bits)
-        // Payload includes the body header.
-
-        let mut cursor = size_of::<u64>() + 2 * size_of::<u32>(); // skip body header.
-        let absolute_fno = u64::from_ne_bytes(payload[cursor..cursor+size_of::<u64>()].try_into().unwrap());
-        glom.add_frame_boundary(absolute_fno);
-
-        // Sort the hits and add them to the glommer:
-
-        let mut orderer = glom::Orderer::new();  // last channel not num
-
-        cursor += size_of::<u64>();   // First (if any) data item:
-        while cursor < payload.len() {
-            let raw = u64::from_ne_bytes(payload[cursor..cursor+size_of::<u64>()].try_into().unwrap());
-            
-            match mikumari_format::MikumariDatum::from_u64(raw) {
-                mikumari_format::MikumariDatum::LeadingEdge(le)  => {
-                    let t : u64 = le.time() as u64 + t0;
-                    orderer.add_hit(true, le.channel() as u16, t, le.tot());
-                    
-                },
-                mikumari_format::MikumariDatum::TrailingEdge(te) => {
-                    let t : u64 = te.time() as u64 + t0;
-                    orderer.add_hit(false, te.channel() as u16, t, te.tot());
-                },
-                _ => {},              // ANything else is not passed through.
+            glom::CoincidenceMode::Trigger {
+                channels,
+                pre  : *matches.get_one::<u64>("pre").expect("No default pre"),
+                post : *matches.get_one::<u64>("post").expect("No default post"),
             }
+        },
+        _ => glom::CoincidenceMode::Fixed,
+    }
+}
+// Build the event filter from the --min-hits, --max-hits, --require-channels and
+// --ignore-channels options.
 
-            cursor += size_of::<u64>();
-        }
-        // Get hits from the orderer and put them in glom
-        // which will merge into events:
-
-        let merged_hits = orderer.order();
-        for (rising, chan, time, tot) in &merged_hits {
-            glom.add_hit(*rising, *chan as u8, *time, *tot);
-        }
-
-        // In case there's on end run:
-
-        glom.flush();
-        
+fn get_filter(matches : &ArgMatches) -> glom::EventFilter {
+    glom::EventFilter {
+        min_multiplicity  : *matches.get_one::<usize>("min-hits").expect("No default min-hits"),
+        max_multiplicity  : matches.get_one::<usize>("max-hits").copied(),
+        required_channels : matches.get_many::<glom::ChannelId>("require-channels")
+            .map(|c| c.copied().collect()).unwrap_or_default(),
+        ignored_channels  : matches.get_many::<glom::ChannelId>("ignore-channels")
+            .map(|c| c.copied().collect()).unwrap_or_default(),
     }
-        
-}
\ No newline at end of file
+}
@@ -0,0 +1,119 @@
+use mikumarimaker::check::{Checker, ProblemKind};
+use mikumarimaker::mikumari_format::{MikumariReader, HEARTBEAT_PERIOD_TICKS};
+use std::cell::Cell;
+use std::fs::File;
+use std::io::{self, stdin, BufReader, Read};
+use std::mem::size_of;
+use std::process::exit;
+use std::rc::Rc;
+use clap::{value_parser, Arg, ArgAction, Command};
+
+// Checks the integrity of a raw mikumari data file.
+//
+// Usage:
+//    mikumaricheck [--ignore kind1,kind2...] [--max-problems n] [--frame-period ticks] file
+//
+// Each problem found (see check::Checker for the kinds) is written to stdout with
+// the byte offset of the word, followed by a summary.  The exit status is 0 if no
+// problems were found, 1 if there were problems and -1 if the file could not be read,
+// so this can gate data transfer scripts.
+//
+// --ignore - kinds of problems that are neither reported nor counted against the file.
+// --max-problems - the most problems written (default 100); all are counted in the summary
+//   but only these are kept in memory.
+// --frame-period - the heartbeat frame period in TDC ticks; hits later than this are problems.
+//   No TDC time is later than the default, so time problems need a shorter period.
+//
+fn main() {
+    let parser = Command::new("mikumaricheck")
+        .version("0.1.0").about("Checks the integrity of raw mikumari data")
+        .arg(Arg::new("ignore")
+            .short('i').long("ignore").required(false)
+            .help("Comma separated kinds of problems to ignore: continuity, order, pairing, size, unknown, time, flags, partial")
+            .action(ArgAction::Append).value_delimiter(',')
+            .value_parser(value_parser!(ProblemKind))
+        )
+        .arg(Arg::new("max-problems")
+            .short('m').long("max-problems").required(false).default_value("100")
+            .help("Most problems to print")
+            .action(ArgAction::Set)
+            .value_parser(value_parser!(usize))
+        )
+        .arg(Arg::new("frame-period")
+            .short('p').long("frame-period").required(false)
+            .help("Heartbeat frame period in TDC ticks (default 2^29, 524.288us); time problems are only found with a shorter period")
+            .action(ArgAction::Set)
+            .value_parser(value_parser!(u64))
+        )
+        .arg(Arg::new("file").required(true).help("Raw mikumari data file, - for stdin"));
+    let matches = parser.get_matches();
+
+    let ignore : Vec<ProblemKind> = matches.get_many::<ProblemKind>("ignore").map_or(Vec::new(), |k| k.copied().collect());
+    let max_problems = *matches.get_one::<usize>("max-problems").expect("No default max-problems");
+    let period = matches.get_one::<u64>("frame-period").copied().unwrap_or(HEARTBEAT_PERIOD_TICKS);
+    let fname = matches.get_one::<String>("file").expect("No file given");
+
+    let raw : Box<dyn Read> = if fname == "-" {
+        Box::new(stdin())
+    } else {
+        match File::open(fname) {
+            Ok(f) => Box::new(BufReader::new(f)),
+            Err(e) => {
+                eprintln!("Unable to open {}: {}", fname, e);
+                exit(-1);
+            }
+        }
+    };
+    // Count the bytes read so that a partial word at the end can be found:
+
+    let bytes = Rc::new(Cell::new(0u64));
+    let mut reader = MikumariReader::new(Box::new(CountingReader { source: raw, count: bytes.clone() }));
+
+    let mut checker = Checker::new();
+    checker.set_frame_period(period);
+    checker.set_ignore(&ignore);
+    checker.set_max_problems(max_problems);
+    loop {
+        match reader.read() {
+            Ok(datum) => checker.check(&datum),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => {
+                eprintln!("Failed to read {}: {}", fname, e);
+                exit(-1);
+            }
+        }
+    }
+    checker.finish((bytes.get() - checker.words() * size_of::<u64>() as u64) as usize);
+
+    // Report:
+
+    for problem in checker.problems() {
+        println!("{}", problem);
+    }
+    let total = checker.problem_count();
+    let shown = checker.problems().len() as u64;
+    if total > shown {
+        println!("... {} more problems not shown (see --max-problems)", total - shown);
+    }
+    println!("{}: {} words in {} frames, {} problems", fname, checker.words(), checker.frames(), total);
+    for (kind, count) in checker.counts() {
+        println!("  {:<10} {}", kind, count);
+    }
+    if total > 0 {
+        exit(1);
+    }
+}
+
+// A reader that counts the bytes read through it:
+
+struct CountingReader {
+    source : Box<dyn Read>,
+    count  : Rc<Cell<u64>>,
+}
+impl Read for CountingReader {
+    fn read(&mut self, buf : &mut [u8]) -> io::Result<usize> {
+        let n = self.source.read(buf)?;
+        self.count.set(self.count.get() + n as u64);
+        Ok(n)
+    }
+}
@@ -0,0 +1,138 @@
+use mikumarimaker::{mikumari_format, glom, dnl, pipeline};
+use rust_ringitem_format::{body_header_size, RingItem};
+use frib_datasource::data_source_factory;
+use std::fs::File;
+use std::io::{stdin, BufReader, Read, Write};
+use std::mem::size_of;
+use std::process::exit;
+use clap::{value_parser, Arg, ArgAction, Command};
+
+// Builds HR TDC fine time DNL lookup tables from a calibration run
+// (hits that are random with respect to the TDC clock).
+//
+// Usage:
+//    mikumaridnl [--fine-bits n] [--source-id id] source output-file
+//
+// source is either a raw mikumari data file (- for stdin) or, if it is a URI
+// (e.g. file:///path or tcp://host/ring), ring items as made by mikumarimaker
+// of which only the type 51 frame items are used.  Frame items that are too short
+// to have a frame number are reported and skipped.
+// --source-id is the source id given to hits from a raw file (ring items have body headers).
+// The lookup tables are written to output-file (see dnl.rs for the format) and can be
+// given to defenestrator --dnl.  A summary of the counts and DNL of each channel is written to stdout.
+//
+fn main() {
+    let parser = Command::new("mikumaridnl")
+        .version("0.1.0").about("Makes HR TDC DNL lookup tables from a calibration run")
+        .arg(Arg::new("fine-bits")
+            .short('f').long("fine-bits").required(false)
+            .default_value("11")
+            .help("Number of low order TDC time bits that are the fine time (11 bits is 2ns)")
+            .action(ArgAction::Set)
+            .value_parser(value_parser!(u32).range(1..=dnl::MAX_FINE_BITS as i64))
+        )
+        .arg(Arg::new("source-id")
+            .short('s').long("source-id").required(false).default_value("0")
+            .help("Source id of hits read from a raw mikumari file")
+            .action(ArgAction::Set)
+            .value_parser(value_parser!(u32))
+        )
+        .arg(Arg::new("source").required(true).help("Raw mikumari file or ring item source URI"))
+        .arg(Arg::new("output").required(true).help("Lookup table file to write"));
+    let matches = parser.get_matches();
+
+    let source    = matches.get_one::<String>("source").expect("No data source given");
+    let output    = matches.get_one::<String>("output").expect("No output file given");
+    let fine_bits = *matches.get_one::<u32>("fine-bits").expect("No default fine-bits");
+    let sid       = *matches.get_one::<u32>("source-id").expect("No default source-id");
+
+    let mut density = dnl::CodeDensity::new(fine_bits);
+    if source.contains("://") {
+        let mut items = data_source_factory(source).unwrap_or_else(|e| {
+            eprintln!("Could not open {}: {}", source, e);
+            exit(-1);
+        });
+        let mut number = 0u64;                // Item number for messages.
+        let mut bad = 0u64;
+        while let Some(item) = items.read() {
+            if let Err(e) = count_item(&item, &mut density) {
+                eprintln!("Skipping item {}: {}", number, e);
+                bad += 1;
+            }
+            number += 1;
+        }
+        if bad > 0 {
+            eprintln!("Skipped {} frame items that could not be decoded", bad);
+        }
+    } else {
+        let raw : Box<dyn Read> = if source == "-" {
+            Box::new(stdin())
+        } else {
+            match File::open(source) {
+                Ok(f) => Box::new(BufReader::new(f)),
+                Err(e) => {
+                    eprintln!("Unable to open {}: {}", source, e);
+                    exit(-1);
+                }
+            }
+        };
+        let mut reader = mikumari_format::MikumariReader::new(raw);
+        while let Ok(datum) = reader.read() {
+            count_datum(&datum, sid, &mut density);
+        }
+    }
+
+    // Summarize and write the tables:
+
+    for (id, counts) in density.iter() {
+        let dnl = density.dnl(id).unwrap();
+        let worst = dnl.iter().fold(0.0f64, |w, d| w.max(d.abs()));
+        println!("channel {} : {} hits, max |DNL| {:.3} LSB", id, counts.iter().sum::<u64>(), worst);
+    }
+    let mut f = File::create(output).unwrap_or_else(|e| {
+        eprintln!("Unable to create {}: {}", output, e);
+        exit(-1);
+    });
+    if let Err(e) = write!(f, "{}", density.correction()) {
+        eprintln!("Unable to write {}: {}", output, e);
+        exit(-1);
+    }
+}
+// Count the hits in a mikumari datum.
+
+fn count_datum(datum : &mikumari_format::MikumariDatum, sid : u32, density : &mut dnl::CodeDensity) {
+    match datum {
+        mikumari_format::MikumariDatum::LeadingEdge(le) => {
+            density.add(glom::ChannelId::new(sid, le.channel() as u16), le.time() as u64);
+        },
+        mikumari_format::MikumariDatum::TrailingEdge(te) => {
+            density.add(glom::ChannelId::new(sid, te.channel() as u16), te.time() as u64);
+        },
+        _ => {},
+    }
+}
+// Count the hits in a ring item; only frame items have any.  Errors if a frame
+// item is too short to have a frame number.
+
+fn count_item(item : &RingItem, density : &mut dnl::CodeDensity) -> Result<(), String> {
+    if item.type_id() != mikumari_format::MIKUMARI_FRAME_ITEM_TYPE {
+        return Ok(());
+    }
+    let sid = pipeline::body_header(item).map_or(0, |bh| bh.source_id);
+    let payload = item.payload();
+
+    // Skip the body header (if any) and absolute frame number.  An item whose body
+    // header is cut short has no frame number either:
+
+    let start = if item.has_body_header() { body_header_size() } else { 0 };
+    if payload.len() < start + size_of::<u64>() {
+        return Err(format!("Frame item of {} bytes has no frame number", payload.len()));
+    }
+    let mut cursor = start + size_of::<u64>();
+    while cursor + size_of::<u64>() <= payload.len() {
+        let raw = u64::from_ne_bytes(payload[cursor..cursor+size_of::<u64>()].try_into().unwrap());
+        count_datum(&mikumari_format::MikumariDatum::from_u64(raw), sid, density);
+        cursor += size_of::<u64>();
+    }
+    Ok(())
+}
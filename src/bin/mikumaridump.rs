@@ -0,0 +1,313 @@
+use mikumarimaker::{mikumari_format, glom, pipeline};
+use mikumari_format::MikumariDatum;
+use rust_ringitem_format::{
+    body_header_size, FromRaw, RingItem, RingVersion,
+    BEGIN_RUN, END_RUN, PAUSE_RUN, RESUME_RUN, ABNORMAL_END, PACKET_TYPES, MONITORED_VARIABLES,
+    FORMAT_ITEM, PERIODIC_SCALERS, PHYSICS_EVENT, PHYSICS_EVENT_COUNT, GLOM_INFO,
+};
+use rust_ringitem_format::state_change::StateChange;
+use rust_ringitem_format::text_item::TextItem;
+use frib_datasource::data_source_factory;
+use std::fs::File;
+use std::io::{stdin, stdout, BufReader, BufWriter, Read, Write};
+use std::mem::size_of;
+use std::ops::RangeInclusive;
+use std::process::exit;
+use clap::{value_parser, Arg, ArgAction, ArgMatches, Command};
+
+// Prints raw mikumari data or ring items with the fields of each word decoded.
+//
+// Usage:
+//    mikumaridump [--channels c1,c2...] [--frames first:last] [--types t1,t2...] [--source-id id] source
+//
+// source is either a raw mikumari data file (- for stdin) or, if it is a URI
+// (e.g. file:///path or tcp://host/ring), ring items.  Each word of a raw file is printed
+// with its decoded fields.  Ring items are printed with their headers and:
+//
+// *  Type 51 frame items - the frame number and each word decoded.
+// *  Physics events (defenestrator output) - the event header and each hit, pair and
+//    frame boundary (see glom::decode_event).
+// *  State changes and text items (e.g. calibrations, run statistics) - their contents.
+//
+// --channels only prints hits on the channels given (source:channel or channel for any source).
+// --frames only prints data in frames first through last (a single frame number is one frame).
+//   Physics events are printed if any of their frames are in the range.
+// --types only prints ring items of these types.
+// --source-id is the source id of hits in a raw file (for --channels).
+//
+fn main() {
+    let parser = Command::new("mikumaridump")
+        .version("0.1.0").about("Prints mikumari raw data or ring items with decoded fields")
+        .arg(Arg::new("channels")
+            .short('c').long("channels").required(false)
+            .help("Comma separated channels whose hits are printed (default all)")
+            .action(ArgAction::Append).value_delimiter(',')
+            .value_parser(value_parser!(glom::ChannelId))
+        )
+        .arg(Arg::new("frames")
+            .short('f').long("frames").required(false)
+            .help("Range of frame numbers to print: first:last or a single frame")
+            .action(ArgAction::Set)
+            .value_parser(parse_frames)
+        )
+        .arg(Arg::new("types")
+            .short('t').long("types").required(false)
+            .help("Comma separated ring item types to print (default all)")
+            .action(ArgAction::Append).value_delimiter(',')
+            .value_parser(value_parser!(u32))
+        )
+        .arg(Arg::new("source-id")
+            .short('s').long("source-id").required(false).default_value("0")
+            .help("Source id of hits read from a raw mikumari file")
+            .action(ArgAction::Set)
+            .value_parser(value_parser!(u32))
+        )
+        .arg(Arg::new("source").required(true).help("Raw mikumari file or ring item source URI"));
+    let matches = parser.get_matches();
+
+    let source = matches.get_one::<String>("source").expect("No data source given");
+    let filter = Filter::new(&matches);
+    let sid = *matches.get_one::<u32>("source-id").expect("No default source-id");
+
+    // Output errors (e.g. piping into head) just stop the dump:
+
+    let mut out = BufWriter::new(stdout().lock());
+    if dump(source, &filter, sid, &mut out).and_then(|_| out.flush()).is_err() {
+        exit(0);
+    }
+}
+
+// Dump the source to out.
+
+fn dump(source : &str, filter : &Filter, sid : u32, out : &mut impl Write) -> std::io::Result<()> {
+    if source.contains("://") {
+        let mut items = data_source_factory(source).unwrap_or_else(|e| {
+            eprintln!("Could not open {}: {}", source, e);
+            exit(-1);
+        });
+        let mut number = 0u64;
+        while let Some(item) = items.read() {
+            if filter.types.as_ref().is_none_or(|t| t.contains(&item.type_id())) {
+                dump_item(number, &item, filter, out)?;
+            }
+            number += 1;
+        }
+    } else {
+        let raw : Box<dyn Read> = if source == "-" {
+            Box::new(stdin())
+        } else {
+            match File::open(source) {
+                Ok(f) => Box::new(BufReader::new(f)),
+                Err(e) => {
+                    eprintln!("Unable to open {}: {}", source, e);
+                    exit(-1);
+                }
+            }
+        };
+        let mut reader = mikumari_format::MikumariReader::new(raw);
+        let mut frame = None;
+        let mut offset = 0usize;
+        while let Ok(datum) = reader.read() {
+            if let MikumariDatum::Heartbeat0(d1) = &datum {
+                frame = Some(d1.frame());
+            }
+            if filter.in_frame(frame) && filter.shows(&datum, sid) {
+                writeln!(out, "{:>10} {}", offset, datum)?;
+            }
+            offset += size_of::<u64>();
+        }
+    }
+    Ok(())
+}
+
+// What to print:
+
+struct Filter {
+    channels : Option<Vec<glom::ChannelId>>,
+    frames   : Option<RangeInclusive<u64>>,
+    types    : Option<Vec<u32>>,
+}
+impl Filter {
+    fn new(matches : &ArgMatches) -> Filter {
+        Filter {
+            channels : matches.get_many::<glom::ChannelId>("channels").map(|c| c.copied().collect()),
+            frames   : matches.get_one::<RangeInclusive<u64>>("frames").cloned(),
+            types    : matches.get_many::<u32>("types").map(|t| t.copied().collect()),
+        }
+    }
+    // Data in an unknown frame is only printed if there's no frame range.
+
+    fn in_frame(&self, frame : Option<u64>) -> bool {
+        match (&self.frames, frame) {
+            (None, _) => true,
+            (Some(range), Some(frame)) => range.contains(&frame),
+            (Some(_), None) => false,
+        }
+    }
+    fn channel(&self, id : glom::ChannelId) -> bool {
+        self.channels.as_ref().is_none_or(|c| c.iter().any(|spec| spec.matches(&id)))
+    }
+    // Everything but hits on other channels is shown:
+
+    fn shows(&self, datum : &MikumariDatum, sid : u32) -> bool {
+        match datum {
+            MikumariDatum::LeadingEdge(le) => self.channel(glom::ChannelId::new(sid, le.channel() as u16)),
+            MikumariDatum::TrailingEdge(te) => self.channel(glom::ChannelId::new(sid, te.channel() as u16)),
+            _ => true,
+        }
+    }
+    fn shows_record(&self, record : &glom::EventRecord) -> bool {
+        match record {
+            glom::EventRecord::Hit { channel, .. } | glom::EventRecord::Pair { channel, .. } => self.channel(*channel),
+            glom::EventRecord::FrameBoundary { .. } => true,
+        }
+    }
+}
+
+// Parse a frame range: first:last or a single frame.
+
+fn parse_frames(s : &str) -> Result<RangeInclusive<u64>, String> {
+    let bad = || format!("'{}' is not a frame range, expected first:last or a frame number", s);
+    let (first, last) = s.split_once(':').unwrap_or((s, s));
+    let first = first.trim().parse::<u64>().map_err(|_| bad())?;
+    let last = last.trim().parse::<u64>().map_err(|_| bad())?;
+    if last < first {
+        return Err(bad());
+    }
+    Ok(first..=last)
+}
+
+fn type_name(type_id : u32) -> &'static str {
+    match type_id {
+        BEGIN_RUN => "begin run",
+        END_RUN => "end run",
+        PAUSE_RUN => "pause run",
+        RESUME_RUN => "resume run",
+        ABNORMAL_END => "abnormal end",
+        PACKET_TYPES => "packet types",
+        MONITORED_VARIABLES => "monitored variables",
+        FORMAT_ITEM => "ring format",
+        PERIODIC_SCALERS => "scalers",
+        PHYSICS_EVENT => "physics event",
+        PHYSICS_EVENT_COUNT => "event count",
+        GLOM_INFO => "glom parameters",
+        mikumari_format::MIKUMARI_FRAME_ITEM_TYPE => "mikumari frame",
+        _ => "unknown",
+    }
+}
+
+// Frame items written before the delimiters were kept have only hits after
+// the frame number; the current layout starts with the first delimiter.
+
+fn old_layout(words : &[u8]) -> bool {
+    !words.get(0..size_of::<u64>()).is_some_and(
+        |w| matches!(MikumariDatum::from_u64(u64::from_ne_bytes(w.try_into().unwrap())), MikumariDatum::Heartbeat0(_))
+    )
+}
+
+// Print a ring item:
+
+fn dump_item(number : u64, item : &RingItem, filter : &Filter, out : &mut impl Write) -> std::io::Result<()> {
+    let type_id = item.type_id();
+    let header = pipeline::body_header(item);
+    let payload = item.payload();
+    let body = if item.has_body_header() { payload.get(body_header_size()..).unwrap_or_default() } else { &payload[..] };
+
+    // Frame range filtering for items that have frames:
+
+    let frame_number = body.get(0..size_of::<u64>()).map(|b| u64::from_ne_bytes(b.try_into().unwrap()));
+    if type_id == mikumari_format::MIKUMARI_FRAME_ITEM_TYPE && !filter.in_frame(frame_number) {
+        return Ok(());
+    }
+    let event = if type_id == PHYSICS_EVENT {
+        Some(glom::decode_event(body, header.map_or(0, |h| h.timestamp)))
+    } else {
+        None
+    };
+    if let (Some(event), Some(range)) = (&event, &filter.frames)
+        && !event.as_ref().is_ok_and(|(h, records)| event_in_frames(h, records, range)) {
+        return Ok(());
+    }
+
+    write!(out, "Item {}: type {} ({}) size {}", number, type_id, type_name(type_id), item.size())?;
+    match header {
+        Some(h) => writeln!(out, " timestamp {} source {} barrier {}", h.timestamp, h.source_id, h.barrier_type)?,
+        None if item.has_body_header() => writeln!(out, " body header cut short")?,
+        None => writeln!(out)?,
+    }
+    match type_id {
+        mikumari_format::MIKUMARI_FRAME_ITEM_TYPE => {
+            let words = body.get(size_of::<u64>()..).unwrap_or(&[]);
+            match frame_number {
+                Some(frame) if old_layout(words) => writeln!(out, "  frame {} (old layout, no delimiters)", frame)?,
+                Some(frame) => writeln!(out, "  frame {}", frame)?,
+                None => writeln!(out, "  too short to have a frame number")?,
+            }
+            for word in words.chunks(size_of::<u64>()) {
+                match word.try_into() {
+                    Ok(w) => {
+                        let datum = MikumariDatum::from_u64(u64::from_ne_bytes(w));
+                        if filter.shows(&datum, header.map_or(0, |h| h.source_id)) {
+                            writeln!(out, "  {}", datum)?;
+                        }
+                    },
+                    Err(_) => writeln!(out, "  {} bytes left over", word.len())?,
+                }
+            }
+        },
+        PHYSICS_EVENT => match event.expect("Physics events are decoded") {
+            Ok((header, records)) => {
+                writeln!(
+                    out, "  layout {:?} flags 0x{:x} hits {} frames {} to {}", header.layout, header.flags,
+                    header.hit_count, frame_text(header.first_frame), frame_text(header.last_frame)
+                )?;
+                for record in records.iter().filter(|r| filter.shows_record(r)) {
+                    writeln!(out, "  {}", describe_record(record))?;
+                }
+            },
+            Err(e) => writeln!(out, "  not a defenestrated event: {}", e)?,
+        },
+        BEGIN_RUN | END_RUN | PAUSE_RUN | RESUME_RUN => {
+            if let Some(state) = item.to_specific(RingVersion::V12) as Option<StateChange> {
+                writeln!(out, "  run {} offset {} s title '{}'", state.run_number(), state.time_offset(), state.title())?;
+            }
+        },
+        MONITORED_VARIABLES | PACKET_TYPES => {
+            if let Some(text) = item.to_specific(RingVersion::V12) as Option<TextItem> {
+                for s in text.get_strings() {
+                    writeln!(out, "  {}", s)?;
+                }
+            }
+        },
+        _ => {},
+    }
+    Ok(())
+}
+
+// Is any frame of an event in a range?  Its frames are the frames from its first to last hit
+// and those of the frame boundaries in it.
+
+fn event_in_frames(header : &glom::EventHeader, records : &[glom::EventRecord], range : &RangeInclusive<u64>) -> bool {
+    let hits = header.first_frame != glom::NO_FRAME && header.last_frame != glom::NO_FRAME
+        && header.first_frame <= *range.end() && header.last_frame >= *range.start();
+    hits || records.iter().any(|r| matches!(r, glom::EventRecord::FrameBoundary { frame, .. } if range.contains(frame)))
+}
+
+fn frame_text(frame : u64) -> String {
+    if frame == glom::NO_FRAME { String::from("unknown") } else { frame.to_string() }
+}
+
+fn describe_record(record : &glom::EventRecord) -> String {
+    let edge = |time : Option<u64>| time.map_or(String::from("none"), |t| t.to_string());
+    match record {
+        glom::EventRecord::Hit { trailing, channel, time, tot } => format!(
+            "{} {:<10} time {} tot {}", if *trailing { "trailing" } else { "leading " }, channel.to_string(), time, tot
+        ),
+        glom::EventRecord::Pair { channel, leading, trailing, width } => format!(
+            "pair     {:<10} leading {} trailing {} width {}", channel.to_string(), edge(*leading), edge(*trailing), width
+        ),
+        glom::EventRecord::FrameBoundary { frame, timestamp, flags } => format!(
+            "frame    {:<10} timestamp {} flags 0x{:x}", frame, timestamp, flags
+        ),
+    }
+}
@@ -0,0 +1,106 @@
+use mikumarimaker::mikumari_format::{self, MikumariFrame, MikumariWriter};
+use mikumarimaker::pipeline;
+use frib_datasource::data_source_factory;
+use std::fs::File;
+use std::io::{stdout, BufWriter, Write};
+use std::process::exit;
+use clap::{value_parser, Arg, ArgAction, Command};
+
+// Makes mikumarimaker frame ring items back into raw mikumari data.
+//
+// Usage:
+//    mikumariraw [--source-id id] source-uri outfile
+//
+// Each MIKUMARI_FRAME_ITEM_TYPE item is written as its first and second delimiters
+// followed by its data words.  The sizes in the second delimiters are recomputed from
+// the data in the frame.  Other ring items are ignored.  Frames that are not valid are
+// reported and skipped.
+//
+// A raw stream has the frames of one board, so only frames from one source id are
+// written:  --source-id or, by default, the source id of the first frame.
+//
+// outfile is the raw file to write (- for stdout).
+//
+fn main() {
+    let parser = Command::new("mikumariraw")
+        .version("0.1.0").about("Makes mikumari frame ring items back into raw mikumari data")
+        .arg(Arg::new("source-id")
+            .short('s').long("source-id").required(false)
+            .help("Source id of the frames to write (default: that of the first frame)")
+            .action(ArgAction::Set)
+            .value_parser(value_parser!(u32))
+        )
+        .arg(Arg::new("source").required(true).help("Ring item source URI"))
+        .arg(Arg::new("outfile").required(true).help("Raw mikumari file to write, - for stdout"));
+    let matches = parser.get_matches();
+
+    let source_uri = matches.get_one::<String>("source").expect("No data source given");
+    let outfile = matches.get_one::<String>("outfile").expect("No output file given");
+    let mut sid = matches.get_one::<u32>("source-id").copied();
+
+    let mut source = data_source_factory(source_uri).unwrap_or_else(|e| {
+        eprintln!("Could not open {}: {}", source_uri, e);
+        exit(-1);
+    });
+    let sink : Box<dyn Write> = if outfile == "-" {
+        Box::new(BufWriter::new(stdout()))
+    } else {
+        match File::create(outfile) {
+            Ok(f) => Box::new(BufWriter::new(f)),
+            Err(e) => {
+                eprintln!("Unable to create {}: {}", outfile, e);
+                exit(-1);
+            }
+        }
+    };
+    let mut writer = MikumariWriter::new(sink);
+
+    let mut number = 0u64;                    // Item number for messages.
+    let mut frames = 0u64;
+    let mut other_sources = 0u64;             // Frames skipped for their source id.
+    let mut bad = 0u64;
+    while let Some(item) = source.read() {
+        if item.type_id() == mikumari_format::MIKUMARI_FRAME_ITEM_TYPE {
+            // Decode first; a frame item that can't be decoded may not have a whole body header:
+
+            match MikumariFrame::from_item(&item) {
+                Ok((_, frame)) => {
+                    let item_sid = pipeline::body_header(&item).map(|bh| bh.source_id);
+                    if sid.is_none() {
+                        sid = item_sid;
+                    }
+                    if item_sid != sid {
+                        other_sources += 1;
+                    } else {
+                        if let Err(e) = writer.write_frame(&frame) {
+                            eprintln!("Failed to write frame from item {}: {}", number, e);
+                            exit(-1);
+                        }
+                        frames += 1;
+                    }
+                },
+                Err(e) => {
+                    eprintln!("Skipping item {}: {}", number, e);
+                    bad += 1;
+                },
+            }
+        }
+        number += 1;
+    }
+    if let Err(e) = writer.flush() {
+        eprintln!("Failed to write {}: {}", outfile, e);
+        exit(-1);
+    }
+    if other_sources > 0 {
+        eprintln!(
+            "Skipped {} frames that were not from source id {} (see --source-id)",
+            other_sources, sid.expect("A source id was picked")
+        );
+    }
+    if bad > 0 {
+        eprintln!("Skipped {} frame items that could not be decoded", bad);
+    }
+    if frames == 0 {
+        eprintln!("No frames were written");
+    }
+}
@@ -0,0 +1,215 @@
+//!
+//! Per channel calibration of hits.  A calibration gives, for each channel,
+//! a time offset that is added to the hit times and a gain and offset that
+//! are applied to the time over threshold.
+//!
+//! Calibration files are text.  Each line that is not blank or a comment
+//! (starting with #) describes one channel:
+//!
+//! ```text
+//! channel-spec time-offset [tot-gain [tot-offset]]
+//! ```
+//!
+//! *  channel-spec is source:channel or just channel for all sources
+//!    (see glom::ChannelId).
+//! *  time-offset is a signed number of TDC ticks added to the hit times.
+//! *  tot-gain (default 1.0) and tot-offset (default 0.0) calibrate the
+//!    time over threshold as  tot-gain * tot + tot-offset.
+//!
+//! Channels that are not in the file are not changed.
+//!
+use crate::glom::{ChannelId, ANY_SOURCE};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+
+/// The calibration of a single channel.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChannelCalibration {
+    pub time_offset : i64,        // TDC ticks added to hit times.
+    pub tot_gain    : f64,
+    pub tot_offset  : f64,
+}
+impl Default for ChannelCalibration {
+    fn default() -> Self {
+        ChannelCalibration { time_offset: 0, tot_gain: 1.0, tot_offset: 0.0 }
+    }
+}
+impl ChannelCalibration {
+    /// Calibrate a hit time.  The result is limited to the range of a u64.
+    pub fn time(&self, time : u64) -> u64 {
+        time.saturating_add_signed(self.time_offset)
+    }
+    /// Calibrate a time over threshold.  The result is rounded and limited
+    /// to the range of a u32.
+    pub fn tot(&self, tot : u32) -> u32 {
+        (self.tot_gain * tot as f64 + self.tot_offset).round().clamp(0.0, u32::MAX as f64) as u32
+    }
+}
+
+/// The calibrations of a set of channels.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Calibration {
+    channels : BTreeMap<ChannelId, ChannelCalibration>,
+}
+impl Calibration {
+    /// An empty calibration - no channels are changed.
+    pub fn new() -> Calibration {
+        Calibration::default()
+    }
+    /// Read a calibration file.
+    ///
+    /// ### Parameters:
+    /// *  path - path to the calibration file.
+    ///
+    /// ### Returns:
+    /// Result<Calibration, String> - the string describes why the file could not be
+    /// read or parsed.
+    pub fn from_file(path : &str) -> Result<Calibration, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Unable to read calibration file {}: {}", path, e))?;
+        Calibration::parse(&text).map_err(|e| format!("{}: {}", path, e))
+    }
+    /// Parse the text of a calibration file.
+    ///
+    /// ### Notes:
+    /// A channel can only be given once.  An error message includes the
+    /// line number of the bad line.
+    pub fn parse(text : &str) -> Result<Calibration, String> {
+        let mut result = Calibration::new();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (id, cal) = Calibration::parse_line(line)
+                .map_err(|e| format!("line {}: {}", n+1, e))?;
+            if result.channels.insert(id, cal).is_some() {
+                return Err(format!("line {}: channel {} is calibrated more than once", n+1, id));
+            }
+        }
+        Ok(result)
+    }
+    fn parse_line(line : &str) -> Result<(ChannelId, ChannelCalibration), String> {
+        let fields : Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 2 || fields.len() > 4 {
+            return Err(format!("'{}' must be channel time-offset [tot-gain [tot-offset]]", line));
+        }
+        let id = fields[0].parse::<ChannelId>()?;
+        let mut cal = ChannelCalibration {
+            time_offset : fields[1].parse::<i64>()
+                .map_err(|_| format!("Invalid time offset '{}'", fields[1]))?,
+            ..ChannelCalibration::default()
+        };
+        if let Some(gain) = fields.get(2) {
+            cal.tot_gain = gain.parse::<f64>().map_err(|_| format!("Invalid TOT gain '{}'", gain))?;
+        }
+        if let Some(offset) = fields.get(3) {
+            cal.tot_offset = offset.parse::<f64>().map_err(|_| format!("Invalid TOT offset '{}'", offset))?;
+        }
+        Ok((id, cal))
+    }
+    /// Set the calibration of a channel specification.
+    pub fn set(&mut self, id : ChannelId, cal : ChannelCalibration) {
+        self.channels.insert(id, cal);
+    }
+    /// Get the calibration that applies to a channel.  A source:channel
+    /// calibration is used in preference to one that applies to all sources.
+    /// Channels without a calibration get the default (no change).
+    pub fn get(&self, id : &ChannelId) -> ChannelCalibration {
+        self.channels.get(id)
+            .or_else(|| self.channels.get(&ChannelId::new(ANY_SOURCE, id.channel)))
+            .copied()
+            .unwrap_or_default()
+    }
+    /// Calibrate a hit.
+    ///
+    /// ### Returns:
+    /// (u64, u32) - the calibrated time and time over threshold.
+    pub fn apply(&self, id : &ChannelId, time : u64, tot : u32) -> (u64, u32) {
+        let cal = self.get(id);
+        (cal.time(time), cal.tot(tot))
+    }
+    /// Iterate over the channel specifications and their calibrations.
+    pub fn iter(&self) -> impl Iterator<Item = (&ChannelId, &ChannelCalibration)> {
+        self.channels.iter()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.channels.is_empty()
+    }
+}
+/// Writes the calibration in the calibration file format.
+impl fmt::Display for Calibration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (id, cal) in &self.channels {
+            writeln!(f, "{} {} {} {}", id, cal.time_offset, cal.tot_gain, cal.tot_offset)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod calibration_tests {
+    use super::*;
+
+    #[test]
+    fn parse_1() {
+        // Comments and blank lines are skipped, defaults fill in:
+
+        let cal = Calibration::parse("# a comment\n\n1 -10\n2:3 5 1.5   # gain only\n4 0 2 -3\n").unwrap();
+        assert_eq!(cal.iter().count(), 3);
+        assert_eq!(cal.get(&ChannelId::new(0, 1)), ChannelCalibration { time_offset: -10, tot_gain: 1.0, tot_offset: 0.0 });
+        assert_eq!(cal.get(&ChannelId::new(2, 3)), ChannelCalibration { time_offset: 5, tot_gain: 1.5, tot_offset: 0.0 });
+        assert_eq!(cal.get(&ChannelId::new(9, 4)), ChannelCalibration { time_offset: 0, tot_gain: 2.0, tot_offset: -3.0 });
+    }
+    #[test]
+    fn parse_2() {
+        // Errors give the line number:
+
+        assert_eq!(Calibration::parse("1 10\n2\n"), Err(String::from(
+            "line 2: '2' must be channel time-offset [tot-gain [tot-offset]]"
+        )));
+        assert!(Calibration::parse("x 10").is_err());
+        assert!(Calibration::parse("1 ten").is_err());
+        assert!(Calibration::parse("1 10 big").is_err());
+        assert!(Calibration::parse("1 10 1 0 extra").is_err());
+    }
+    #[test]
+    fn parse_3() {
+        // A channel can only be given once:
+
+        assert!(Calibration::parse("1 10\n1 20\n").is_err());
+        assert!(Calibration::parse("1 10\n0:1 20\n").is_ok());
+    }
+    #[test]
+    fn get_1() {
+        // source:channel wins over channel, unlisted channels are unchanged:
+
+        let cal = Calibration::parse("1 10\n2:1 20\n").unwrap();
+        assert_eq!(cal.get(&ChannelId::new(2, 1)).time_offset, 20);
+        assert_eq!(cal.get(&ChannelId::new(3, 1)).time_offset, 10);
+        assert_eq!(cal.get(&ChannelId::new(2, 2)), ChannelCalibration::default());
+    }
+    #[test]
+    fn apply_1() {
+        let cal = Calibration::parse("1 -10 2 0.4\n").unwrap();
+        assert_eq!(cal.apply(&ChannelId::new(0, 1), 100, 50), (90, 100));
+        assert_eq!(cal.apply(&ChannelId::new(0, 2), 100, 50), (100, 50));
+    }
+    #[test]
+    fn apply_2() {
+        // Results are limited to the range of their types:
+
+        let cal = Calibration::parse("1 -10 1 -100\n2 10 1e10\n").unwrap();
+        assert_eq!(cal.apply(&ChannelId::new(0, 1), 5, 50), (0, 0));
+        assert_eq!(cal.apply(&ChannelId::new(0, 2), u64::MAX - 5, 50), (u64::MAX, u32::MAX));
+    }
+    #[test]
+    fn display_1() {
+        // The display can be parsed back:
+
+        let cal = Calibration::parse("1 -10 2 0.5\n3:4 7\n").unwrap();
+        assert_eq!(cal.to_string(), "3:4 7 1 0\n1 -10 2 0.5\n");
+        assert_eq!(Calibration::parse(&cal.to_string()).unwrap(), cal);
+    }
+}
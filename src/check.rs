@@ -0,0 +1,459 @@
+//!
+//! Integrity checks of raw mikumari data (used by mikumaricheck).  A Checker is
+//! given the words of a raw stream in order and records the problems it finds:
+//!
+//! *  Continuity - a frame number after the next one expected (frames missing).
+//! *  Order      - a frame number that is the same as or before the previous frame's.
+//! *  Pairing    - a first delimiter not followed by a second delimiter or a
+//!    second delimiter that does not follow a first delimiter.
+//! *  Size       - a second delimiter whose transferred data size is not the size
+//!    of the data words that follow it in the frame, or whose generated size is
+//!    smaller than its transferred size.
+//! *  Unknown    - a word with an unknown data type.
+//! *  Time       - a hit whose time is not within the frame period.  The TDC time field
+//!    can't be later than the default (HEARTBEAT_PERIOD_TICKS) so this only finds
+//!    problems with a shorter period (set_frame_period).
+//! *  Flags      - a frame with first delimiter flags set (e.g. overflow, input
+//!    throttling) or an input throttling word.
+//! *  Partial    - bytes at the end of the stream that are not a whole word.
+//!
+//! Data before the first heartbeat (the partial frame) are only checked for
+//! unknown words and hit times.
+//!
+//! Every problem is counted by kind but only the first few (set_max_problems) are
+//! kept so that a badly damaged file does not fill memory.
+//!
+use crate::mikumari_format::{
+    self, MikumariDatum, HEARTBEAT_PERIOD_TICKS, INPUT_THROTTLE_T1_END, INPUT_THROTTLE_T1_START,
+    INPUT_THROTTLE_T2_START, MAX_FRAME_NUMBER,
+};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::mem::size_of;
+use std::str::FromStr;
+
+/// The kinds of problems a Checker finds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ProblemKind {
+    Continuity,
+    Order,
+    Pairing,
+    Size,
+    Unknown,
+    Time,
+    Flags,
+    Partial,
+}
+impl ProblemKind {
+    pub const ALL : [ProblemKind; 8] = [
+        ProblemKind::Continuity, ProblemKind::Order, ProblemKind::Pairing, ProblemKind::Size, ProblemKind::Unknown,
+        ProblemKind::Time, ProblemKind::Flags, ProblemKind::Partial,
+    ];
+    pub fn name(&self) -> &'static str {
+        match self {
+            ProblemKind::Continuity => "continuity",
+            ProblemKind::Order => "order",
+            ProblemKind::Pairing => "pairing",
+            ProblemKind::Size => "size",
+            ProblemKind::Unknown => "unknown",
+            ProblemKind::Time => "time",
+            ProblemKind::Flags => "flags",
+            ProblemKind::Partial => "partial",
+        }
+    }
+}
+impl fmt::Display for ProblemKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(self.name())
+    }
+}
+impl FromStr for ProblemKind {
+    type Err = String;
+    fn from_str(s : &str) -> Result<ProblemKind, String> {
+        ProblemKind::ALL.iter().find(|k| k.name() == s.trim()).copied().ok_or_else(|| {
+            let names : Vec<&str> = ProblemKind::ALL.iter().map(|k| k.name()).collect();
+            format!("'{}' is not a kind of problem, expected one of {}", s, names.join(", "))
+        })
+    }
+}
+
+/// Default number of problems a Checker keeps.
+pub const DEFAULT_MAX_PROBLEMS : usize = 100;
+
+/// A problem found in the data.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Problem {
+    pub kind    : ProblemKind,
+    pub offset  : u64,             // Byte offset of the word with the problem.
+    pub message : String,
+}
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:>10} {}: {}", self.offset, self.kind, self.message)
+    }
+}
+
+// The frame being checked:
+
+struct Frame {
+    number     : u64,
+    start      : u64,                // Offset of its first delimiter.
+    size       : Option<(u64, u64)>, // Offset and transferred size of its second delimiter.
+    data_bytes : u64,
+}
+
+/// Checks a raw mikumari data stream.
+pub struct Checker {
+    frame_period : u64,
+    offset       : u64,              // Offset of the next word.
+    frame        : Option<Frame>,
+    last_frame   : Option<u64>,      // Number of the previous frame.
+    awaiting_d2  : bool,             // The last word was a first delimiter.
+    frames       : u64,
+    problems     : Vec<Problem>,     // The first max_problems found.
+    max_problems : usize,
+    counts       : BTreeMap<ProblemKind, u64>,
+    ignore       : Vec<ProblemKind>,
+}
+impl Default for Checker {
+    fn default() -> Self {
+        Checker::new()
+    }
+}
+impl Checker {
+    fn problem(&mut self, kind : ProblemKind, offset : u64, message : String) {
+        if self.ignore.contains(&kind) {
+            return;
+        }
+        *self.counts.entry(kind).or_insert(0) += 1;
+        if self.problems.len() < self.max_problems {
+            self.problems.push(Problem { kind, offset, message });
+        }
+    }
+    // The end of a frame: check its size.
+
+    fn end_frame(&mut self) {
+        if let Some(frame) = self.frame.take()
+            && let Some((offset, size)) = frame.size
+            && size != frame.data_bytes {
+            self.problem(ProblemKind::Size, offset, format!(
+                "frame {} second delimiter has {} bytes of data, the frame has {}",
+                frame.number, size, frame.data_bytes
+            ));
+        }
+    }
+    fn start_frame(&mut self, d1 : &mikumari_format::Delimeter1) {
+        self.end_frame();
+        let number = d1.frame();
+        if let Some(last) = self.last_frame {
+            // Frame numbers wrap so a jump is backward if that's the shorter way:
+
+            let mask = MAX_FRAME_NUMBER as u64;
+            let expected = (last + 1) & mask;
+            let back = last.wrapping_sub(number) & mask;
+            if back < mask.div_ceil(2) {
+                self.problem(ProblemKind::Order, self.offset, format!(
+                    "frame {} follows frame {} ({} frames back)", number, last, back
+                ));
+            } else if number != expected {
+                let missing = number.wrapping_sub(expected) & mask;
+                self.problem(ProblemKind::Continuity, self.offset, format!(
+                    "frame {} follows frame {} ({} frames missing)", number, last, missing
+                ));
+            }
+        }
+        if d1.flags() != 0 {
+            self.problem(ProblemKind::Flags, self.offset, format!(
+                "frame {} {}", number, mikumari_format::flag_names(d1.flags())
+            ));
+        }
+        self.last_frame = Some(number);
+        self.frames += 1;
+        self.frame = Some(Frame { number, start: self.offset, size: None, data_bytes: 0 });
+    }
+    fn check_time(&mut self, edge : &str, channel : u8, time : u32) {
+        if time as u64 >= self.frame_period {
+            self.problem(ProblemKind::Time, self.offset, format!(
+                "{} edge on channel {} at {} is not within the frame period ({} ticks)",
+                edge, channel, time, self.frame_period
+            ));
+        }
+    }
+
+    pub fn new() -> Checker {
+        Checker {
+            frame_period : HEARTBEAT_PERIOD_TICKS,
+            offset       : 0,
+            frame        : None,
+            last_frame   : None,
+            awaiting_d2  : false,
+            frames       : 0,
+            problems     : Vec::new(),
+            max_problems : DEFAULT_MAX_PROBLEMS,
+            counts       : BTreeMap::new(),
+            ignore       : Vec::new(),
+        }
+    }
+    /// ### Parameters:
+    /// *  max - the most problems kept (default DEFAULT_MAX_PROBLEMS).  The rest are only counted.
+    pub fn set_max_problems(&mut self, max : usize) {
+        self.max_problems = max;
+    }
+    /// ### Parameters:
+    /// *  kinds - kinds of problems that are neither kept nor counted.
+    pub fn set_ignore(&mut self, kinds : &[ProblemKind]) {
+        self.ignore = kinds.to_vec();
+    }
+    /// ### Parameters:
+    /// *  ticks - the heartbeat frame period in TDC ticks (default HEARTBEAT_PERIOD_TICKS).
+    ///    Hits with times of at least this many ticks are problems.  No TDC time is that
+    ///    late with the default so there are only time problems with a shorter period.
+    pub fn set_frame_period(&mut self, ticks : u64) {
+        self.frame_period = ticks;
+    }
+    /// Check the next word of the stream.
+    pub fn check(&mut self, datum : &MikumariDatum) {
+        if self.awaiting_d2 && !matches!(datum, MikumariDatum::Heartbeat1(_)) {
+            let start = self.frame.as_ref().map_or(self.offset, |f| f.start);
+            self.problem(ProblemKind::Pairing, start, String::from(
+                "first delimiter is not followed by a second delimiter"
+            ));
+        }
+        self.awaiting_d2 = false;
+        match datum {
+            MikumariDatum::Heartbeat0(d1) => {
+                self.start_frame(d1);
+                self.awaiting_d2 = true;
+            },
+            MikumariDatum::Heartbeat1(d2) => {
+                match self.frame.as_mut() {
+                    Some(frame) if frame.size.is_none() && frame.data_bytes == 0 => {
+                        frame.size = Some((self.offset, d2.datasize()));
+                    },
+                    _ => self.problem(ProblemKind::Pairing, self.offset, String::from(
+                        "second delimiter does not follow a first delimiter"
+                    )),
+                }
+                if d2.generated_size() < d2.datasize() {
+                    self.problem(ProblemKind::Size, self.offset, format!(
+                        "generated data size {} is smaller than the transferred size {}",
+                        d2.generated_size(), d2.datasize()
+                    ));
+                }
+            },
+            MikumariDatum::LeadingEdge(le) => self.check_time("leading", le.channel(), le.time()),
+            MikumariDatum::TrailingEdge(te) => self.check_time("trailing", te.channel(), te.time()),
+            MikumariDatum::Other(word) => {
+                let dtype = (word >> (64 - 6)) as u8;
+                let kind = match dtype {
+                    INPUT_THROTTLE_T1_START | INPUT_THROTTLE_T1_END | INPUT_THROTTLE_T2_START => ProblemKind::Flags,
+                    _ => ProblemKind::Unknown,
+                };
+                self.problem(kind, self.offset, datum.to_string());
+            },
+        }
+        if !matches!(datum, MikumariDatum::Heartbeat0(_) | MikumariDatum::Heartbeat1(_))
+            && let Some(frame) = self.frame.as_mut() {
+            frame.data_bytes += size_of::<u64>() as u64;
+        }
+        self.offset += size_of::<u64>() as u64;
+    }
+    /// The end of the stream.
+    ///
+    /// ### Parameters:
+    /// *  leftover - the number of bytes after the last whole word.
+    pub fn finish(&mut self, leftover : usize) {
+        if self.awaiting_d2 {
+            let start = self.frame.as_ref().map_or(self.offset, |f| f.start);
+            self.problem(ProblemKind::Pairing, start, String::from(
+                "first delimiter is not followed by a second delimiter"
+            ));
+            self.awaiting_d2 = false;
+        }
+        self.end_frame();
+        if leftover != 0 {
+            self.problem(ProblemKind::Partial, self.offset, format!(
+                "{} bytes at the end are not a whole word", leftover
+            ));
+        }
+    }
+    /// The first problems found (see set_max_problems), in the order they were found.
+    pub fn problems(&self) -> &[Problem] {
+        &self.problems
+    }
+    /// The number of problems of each kind found so far.
+    pub fn counts(&self) -> &BTreeMap<ProblemKind, u64> {
+        &self.counts
+    }
+    /// The number of problems found so far.
+    pub fn problem_count(&self) -> u64 {
+        self.counts.values().sum()
+    }
+    /// The number of whole words checked.
+    pub fn words(&self) -> u64 {
+        self.offset / size_of::<u64>() as u64
+    }
+    /// The number of frames (first delimiters) seen.
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+}
+
+#[cfg(test)]
+mod check_tests {
+    use super::*;
+    use crate::mikumari_format::{Delimeter1, Delimeter2, HRTDCLeading, HRTDCTrailing};
+
+    fn d1(frame : u32) -> MikumariDatum {
+        MikumariDatum::Heartbeat0(Delimeter1::new(0, frame))
+    }
+    fn d2(size : u32) -> MikumariDatum {
+        MikumariDatum::Heartbeat1(Delimeter2::new(size))
+    }
+    fn le(time : u32) -> MikumariDatum {
+        MikumariDatum::LeadingEdge(HRTDCLeading::new(1, 10, time))
+    }
+    fn te(time : u32) -> MikumariDatum {
+        MikumariDatum::TrailingEdge(HRTDCTrailing::new(1, 10, time))
+    }
+    fn check(data : &[MikumariDatum], leftover : usize) -> Checker {
+        let mut checker = Checker::new();
+        for datum in data {
+            checker.check(datum);
+        }
+        checker.finish(leftover);
+        checker
+    }
+    fn kinds(checker : &Checker) -> Vec<(ProblemKind, u64)> {
+        checker.problems().iter().map(|p| (p.kind, p.offset)).collect()
+    }
+
+    #[test]
+    fn clean_1() {
+        // Good data, including the partial frame and an empty frame:
+
+        let c = check(&[le(5), d1(10), d2(16), le(1), te(2), d1(11), d2(0), d1(12), d2(8), le(3)], 0);
+        assert!(c.problems().is_empty(), "{:?}", c.problems());
+        assert_eq!(c.words(), 10);
+        assert_eq!(c.frames(), 3);
+    }
+    #[test]
+    fn continuity_1() {
+        // A gap and then a frame back aren't the same problem:
+
+        let c = check(&[d1(10), d2(0), d1(13), d2(0), d1(12), d2(0), d1(12), d2(0)], 0);
+        assert_eq!(kinds(&c), vec![(ProblemKind::Continuity, 16), (ProblemKind::Order, 32), (ProblemKind::Order, 48)]);
+        assert!(c.problems()[0].message.contains("frame 13 follows frame 10 (2 frames missing)"));
+        assert!(c.problems()[1].message.contains("frame 12 follows frame 13 (1 frames back)"));
+        assert!(c.problems()[2].message.contains("frame 12 follows frame 12 (0 frames back)"));
+    }
+    #[test]
+    fn continuity_2() {
+        // Frame numbers wrap:
+
+        let c = check(&[d1(MAX_FRAME_NUMBER), d2(0), d1(0), d2(0)], 0);
+        assert!(c.problems().is_empty(), "{:?}", c.problems());
+        let c = check(&[d1(1), d2(0), d1(MAX_FRAME_NUMBER), d2(0), d1(5), d2(0)], 0);
+        assert_eq!(kinds(&c), vec![(ProblemKind::Order, 16), (ProblemKind::Continuity, 32)]);
+        assert!(c.problems()[1].message.contains("(5 frames missing)"));
+    }
+    #[test]
+    fn pairing_1() {
+        // Missing second delimiters, including at the end:
+
+        let c = check(&[d1(1), le(1), d1(2), d2(0), d1(3)], 0);
+        assert_eq!(kinds(&c), vec![(ProblemKind::Pairing, 0), (ProblemKind::Pairing, 32)]);
+    }
+    #[test]
+    fn pairing_2() {
+        // Second delimiters not after a first:
+
+        let c = check(&[d2(0), d1(1), d2(8), le(1), d2(0)], 0);
+        assert_eq!(kinds(&c), vec![(ProblemKind::Pairing, 0), (ProblemKind::Pairing, 32)]);
+    }
+    #[test]
+    fn size_1() {
+        let c = check(&[d1(1), d2(8), le(1), le(2), d1(2), d2(8), le(3)], 0);
+        assert_eq!(kinds(&c), vec![(ProblemKind::Size, 8)]);
+        assert!(c.problems()[0].message.contains("has 8 bytes of data, the frame has 16"));
+    }
+    #[test]
+    fn size_2() {
+        // Generated smaller than transferred:
+
+        let bad = Delimeter2::fromu64(Delimeter2::new(8).get() & !(0xfffff << 20));
+        let c = check(&[d1(1), MikumariDatum::Heartbeat1(bad), le(1)], 0);
+        assert_eq!(kinds(&c), vec![(ProblemKind::Size, 8)]);
+    }
+    #[test]
+    fn unknown_1() {
+        // Unknown words are problems and are counted in the frame size:
+
+        let c = check(&[d1(1), d2(16), le(1), MikumariDatum::Other(5)], 0);
+        assert_eq!(kinds(&c), vec![(ProblemKind::Unknown, 24)]);
+        assert!(c.problems()[0].message.contains("Unknown"));
+    }
+    #[test]
+    fn flags_1() {
+        let throttle = MikumariDatum::Other((INPUT_THROTTLE_T1_END as u64) << 58);
+        let flagged = MikumariDatum::Heartbeat0(Delimeter1::fromu64(Delimeter1::new(0, 2).get() | (0x840 << 40)));
+        let c = check(&[d1(1), d2(8), throttle, flagged, d2(0)], 0);
+        assert_eq!(kinds(&c), vec![(ProblemKind::Flags, 16), (ProblemKind::Flags, 24)]);
+        assert!(c.problems()[1].message.contains("Overflow, Input throttling type2"));
+    }
+    #[test]
+    fn time_1() {
+        // The default period is the whole time field:
+
+        assert!(check(&[d1(1), d2(8), le(mikumari_format::MAX_TIME)], 0).problems().is_empty());
+
+        let mut c = Checker::new();
+        c.set_frame_period(1000);
+        for datum in [le(1000), d1(1), d2(16), le(999), te(1001)] {
+            c.check(&datum);
+        }
+        c.finish(0);
+        assert_eq!(kinds(&c), vec![(ProblemKind::Time, 0), (ProblemKind::Time, 32)]);
+    }
+    #[test]
+    fn partial_1() {
+        let c = check(&[d1(1), d2(0)], 3);
+        assert_eq!(kinds(&c), vec![(ProblemKind::Partial, 16)]);
+        assert_eq!(c.problems()[0].to_string(), "        16 partial: 3 bytes at the end are not a whole word");
+    }
+    #[test]
+    fn limit_1() {
+        // Only the first problems are kept but all are counted:
+
+        let mut c = Checker::new();
+        c.set_max_problems(2);
+        for _ in 0..5 {
+            c.check(&MikumariDatum::Other(5));
+        }
+        c.check(&d2(0));
+        c.finish(1);
+        assert_eq!(kinds(&c), vec![(ProblemKind::Unknown, 0), (ProblemKind::Unknown, 8)]);
+        assert_eq!(c.problem_count(), 7);
+        assert_eq!(
+            c.counts().iter().map(|(k, n)| (*k, *n)).collect::<Vec<_>>(),
+            vec![(ProblemKind::Pairing, 1), (ProblemKind::Unknown, 5), (ProblemKind::Partial, 1)]
+        );
+    }
+    #[test]
+    fn ignore_1() {
+        let mut c = Checker::new();
+        c.set_ignore(&[ProblemKind::Unknown, ProblemKind::Partial]);
+        c.check(&MikumariDatum::Other(5));
+        c.check(&d2(0));
+        c.finish(1);
+        assert_eq!(kinds(&c), vec![(ProblemKind::Pairing, 8)]);
+        assert_eq!(c.problem_count(), 1);
+    }
+    #[test]
+    fn kind_1() {
+        for kind in ProblemKind::ALL {
+            assert_eq!(kind.name().parse::<ProblemKind>(), Ok(kind));
+        }
+        assert!("bogus".parse::<ProblemKind>().is_err());
+    }
+}
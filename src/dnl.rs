@@ -0,0 +1,277 @@
+//!
+//! Differential non-linearity (DNL) correction of HR TDC fine times.
+//!
+//! The low fine_bits bits of the HR TDC time are the fine time.  The widths
+//! of the fine time codes are not all the same.  With hits that are random with respect
+//! to the TDC clock (a calibration run), the number of hits with each fine code
+//! (the code density) is proportional to the width of that code.  A CodeDensity
+//! accumulates the code densities of each channel and makes a DnlCorrection
+//! from them.  The DnlCorrection has a lookup table for each channel that maps a fine code
+//! to the time of the center of that code (in fine time units).
+//!
+//! Lookup table files are text.  Blank lines and anything following a # are
+//! ignored.  The first line is
+//!
+//! ```text
+//! fine-bits n
+//! ```
+//!
+//! Each remaining line is the lookup table of a channel: a channel specification
+//! (source:channel or channel, see glom::ChannelId) followed by the 2^n corrected
+//! fine times in code order.
+//!
+use crate::glom::{ChannelId, ANY_SOURCE};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+
+/// The number of fine time bits used when none is specified.
+/// 2^11 ticks of 0.9765625ps is 2ns.
+pub const DEFAULT_FINE_BITS : u32 = 11;
+/// The largest number of fine time bits; a lookup table has 2^fine_bits
+/// entries per channel.
+pub const MAX_FINE_BITS : u32 = 20;
+
+/// Accumulates the fine time code densities of channels.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CodeDensity {
+    fine_bits : u32,
+    counts    : BTreeMap<ChannelId, Vec<u64>>,
+}
+impl CodeDensity {
+    /// ### Parameters:
+    /// *  fine_bits - number of low order bits of the TDC time that are the fine time.
+    pub fn new(fine_bits : u32) -> CodeDensity {
+        CodeDensity { fine_bits, counts : BTreeMap::new() }
+    }
+    pub fn fine_bits(&self) -> u32 {
+        self.fine_bits
+    }
+    /// Count a hit.
+    ///
+    /// ### Parameters:
+    /// *  id   - channel the hit is in.
+    /// *  time - TDC time of the hit (only the fine time bits are used).
+    pub fn add(&mut self, id : ChannelId, time : u64) {
+        let codes = 1usize << self.fine_bits;
+        let code  = (time as usize) & (codes - 1);
+        self.counts.entry(id).or_insert_with(|| vec![0; codes])[code] += 1;
+    }
+    /// Get the code densities of a channel.
+    pub fn counts(&self, id : &ChannelId) -> Option<&[u64]> {
+        self.counts.get(id).map(|c| c.as_slice())
+    }
+    /// Iterate over the channels and their code densities.
+    pub fn iter(&self) -> impl Iterator<Item = (&ChannelId, &Vec<u64>)> {
+        self.counts.iter()
+    }
+    /// Compute the DNL of each code of a channel in units of the average code
+    /// width: count/average count - 1.
+    pub fn dnl(&self, id : &ChannelId) -> Option<Vec<f64>> {
+        let counts = self.counts.get(id)?;
+        let average = counts.iter().sum::<u64>() as f64 / counts.len() as f64;
+        Some(counts.iter().map(|c| *c as f64 / average - 1.0).collect())
+    }
+    /// Make the lookup tables from the code densities.
+    ///
+    /// ### Notes:
+    /// Each code maps to the center of its part of the fine time range where
+    /// the part is proportional to the number of counts in the code.
+    pub fn correction(&self) -> DnlCorrection {
+        let range = (1u64 << self.fine_bits) as f64;
+        let mut result = DnlCorrection::new(self.fine_bits);
+        for (id, counts) in &self.counts {
+            let total : u64 = counts.iter().sum();
+            let mut below = 0u64;
+            let table = counts.iter().map(|c| {
+                let center = (below as f64 + *c as f64 / 2.0) * range / total as f64;
+                below += c;
+                (center.floor() as u64).min(range as u64 - 1) as u32
+            }).collect();
+            result.tables.insert(*id, table);
+        }
+        result
+    }
+}
+
+/// Per channel fine time lookup tables.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DnlCorrection {
+    fine_bits : u32,
+    tables    : BTreeMap<ChannelId, Vec<u32>>,
+}
+impl DnlCorrection {
+    /// An empty correction - no channels are changed.
+    pub fn new(fine_bits : u32) -> DnlCorrection {
+        DnlCorrection { fine_bits, tables : BTreeMap::new() }
+    }
+    pub fn fine_bits(&self) -> u32 {
+        self.fine_bits
+    }
+    /// Get the lookup table of a channel.  A source:channel table is used
+    /// in preference to one for all sources.
+    pub fn table(&self, id : &ChannelId) -> Option<&[u32]> {
+        self.tables.get(id)
+            .or_else(|| self.tables.get(&ChannelId::new(ANY_SOURCE, id.channel)))
+            .map(|t| t.as_slice())
+    }
+    /// Correct a TDC time by replacing its fine time with the lookup table value.
+    /// Times of channels with no table are unchanged.
+    pub fn correct(&self, id : &ChannelId, time : u64) -> u64 {
+        match self.table(id) {
+            Some(table) => {
+                let mask = (1u64 << self.fine_bits) - 1;
+                (time & !mask) | table[(time & mask) as usize] as u64
+            },
+            None => time,
+        }
+    }
+    /// Read a lookup table file.
+    ///
+    /// ### Returns:
+    /// Result<DnlCorrection, String> - the string describes why the file could not be
+    /// read or parsed.
+    pub fn from_file(path : &str) -> Result<DnlCorrection, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Unable to read DNL lookup table file {}: {}", path, e))?;
+        DnlCorrection::parse(&text).map_err(|e| format!("{}: {}", path, e))
+    }
+    /// Parse the text of a lookup table file.
+    pub fn parse(text : &str) -> Result<DnlCorrection, String> {
+        let mut result : Option<DnlCorrection> = None;
+        for (n, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let fields : Vec<&str> = line.split_whitespace().collect();
+            match &mut result {
+                None => {
+                    if fields.len() != 2 || fields[0] != "fine-bits" {
+                        return Err(format!("line {}: expected 'fine-bits n'", n+1));
+                    }
+                    let bits = fields[1].parse::<u32>().ok().filter(|b| *b > 0 && *b <= MAX_FINE_BITS)
+                        .ok_or_else(|| format!("line {}: invalid number of fine bits '{}'", n+1, fields[1]))?;
+                    result = Some(DnlCorrection::new(bits));
+                },
+                Some(correction) => {
+                    let id = fields[0].parse::<ChannelId>().map_err(|e| format!("line {}: {}", n+1, e))?;
+                    let table = fields[1..].iter().map(|v| v.parse::<u32>())
+                        .collect::<Result<Vec<u32>, _>>()
+                        .map_err(|_| format!("line {}: invalid lookup table value", n+1))?;
+                    let codes = 1usize << correction.fine_bits;
+                    if table.len() != codes {
+                        return Err(format!("line {}: channel {} has {} values, {} are needed", n+1, id, table.len(), codes));
+                    }
+                    if table.iter().any(|v| *v as usize >= codes) {
+                        return Err(format!("line {}: channel {} has a value larger than the fine time range", n+1, id));
+                    }
+                    if correction.tables.insert(id, table).is_some() {
+                        return Err(format!("line {}: channel {} has more than one lookup table", n+1, id));
+                    }
+                }
+            }
+        }
+        result.ok_or_else(|| String::from("no 'fine-bits n' line"))
+    }
+}
+/// Writes the lookup tables in the file format.
+impl fmt::Display for DnlCorrection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "fine-bits {}", self.fine_bits)?;
+        for (id, table) in &self.tables {
+            write!(f, "{}", id)?;
+            for v in table {
+                write!(f, " {}", v)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod dnl_tests {
+    use super::*;
+
+    #[test]
+    fn density_1() {
+        // Only the fine bits select the code:
+
+        let mut d = CodeDensity::new(2);
+        let id = ChannelId::new(0, 1);
+        d.add(id, 0);
+        d.add(id, 5);
+        d.add(id, 0x1001);
+        d.add(id, 3);
+        assert_eq!(d.counts(&id), Some(&[1u64, 2, 0, 1][..]));
+        assert!(d.counts(&ChannelId::new(1, 1)).is_none());
+    }
+    #[test]
+    fn dnl_1() {
+        let mut d = CodeDensity::new(1);
+        let id = ChannelId::new(0, 1);
+        d.add(id, 0);
+        d.add(id, 0);
+        d.add(id, 0);
+        d.add(id, 1);
+        assert_eq!(d.dnl(&id), Some(vec![0.5, -0.5]));
+    }
+    #[test]
+    fn correction_1() {
+        // Uniform density is (nearly) the identity:
+
+        let mut d = CodeDensity::new(3);
+        let id = ChannelId::new(0, 1);
+        for t in 0..8*100 {
+            d.add(id, t);
+        }
+        let c = d.correction();
+        assert_eq!(c.table(&id), Some(&[0u32, 1, 2, 3, 4, 5, 6, 7][..]));
+    }
+    #[test]
+    fn correction_2() {
+        // Wide codes get more of the range:
+
+        let mut d = CodeDensity::new(2);
+        let id = ChannelId::new(0, 1);
+        for (code, n) in [(0u64, 6), (1, 1), (2, 0), (3, 1)] {
+            for _ in 0..n {
+                d.add(id, code);
+            }
+        }
+        // Centers are 1.5, 3.25, 3.5, 3.75 of a range of 4 in 8 counts.
+        let c = d.correction();
+        assert_eq!(c.table(&id), Some(&[1u32, 3, 3, 3][..]));
+        assert_eq!(c.correct(&id, 0x100), 0x101);
+        assert_eq!(c.correct(&id, 0x101), 0x103);
+        assert_eq!(c.correct(&ChannelId::new(0, 2), 0x101), 0x101);
+    }
+    #[test]
+    fn parse_1() {
+        let c = DnlCorrection::parse("# DNL\nfine-bits 2\n1 0 1 2 3\n2:1 3 2 1 0\n").unwrap();
+        assert_eq!(c.fine_bits(), 2);
+        assert_eq!(c.table(&ChannelId::new(2, 1)), Some(&[3u32, 2, 1, 0][..]));
+        assert_eq!(c.table(&ChannelId::new(0, 1)), Some(&[0u32, 1, 2, 3][..]));
+        assert!(c.table(&ChannelId::new(0, 2)).is_none());
+    }
+    #[test]
+    fn parse_2() {
+        assert!(DnlCorrection::parse("").is_err());
+        assert!(DnlCorrection::parse("1 0 1 2 3\n").is_err());
+        assert!(DnlCorrection::parse("fine-bits 40\n").is_err());
+        assert!(DnlCorrection::parse(&format!("fine-bits {}\n", MAX_FINE_BITS+1)).is_err());
+        assert!(DnlCorrection::parse("fine-bits 0\n").is_err());
+        assert!(DnlCorrection::parse("fine-bits 2\n1 0 1 2\n").is_err());
+        assert!(DnlCorrection::parse("fine-bits 2\n1 0 1 2 4\n").is_err());
+        assert!(DnlCorrection::parse("fine-bits 2\n1 0 1 2 3\n1 0 1 2 3\n").is_err());
+    }
+    #[test]
+    fn display_1() {
+        // The file written can be read back:
+
+        let c = DnlCorrection::parse("fine-bits 1\n3:4 1 1\n").unwrap();
+        assert_eq!(c.to_string(), "fine-bits 1\n3:4 1 1\n");
+        assert_eq!(DnlCorrection::parse(&c.to_string()).unwrap(), c);
+    }
+}
@@ -0,0 +1,303 @@
+//!
+//! Assembles raw mikumari data into frame ring items (MIKUMARI_FRAME_ITEM_TYPE)
+//! the way mikumarimaker does, so that other programs (e.g. online readouts) can
+//! make the same items.  Each frame item has:
+//!
+//! *  A body header whose timestamp is the frame's time relative to the first
+//!    frame (see hb_frame_to_ts).
+//! *  The absolute frame number (u64), which, unlike the frame number in the
+//!    delimiter, does not roll over.
+//! *  The frame's first and second delimiters and its TDC hits.
+//!
+//! Data before the first heartbeat (the partial frame) and words other than
+//! delimiters and hits (e.g. throttling words) are discarded.
+//!
+use crate::mikumari_format::{MikumariDatum, MikumariReader, MIKUMARI_FRAME_ITEM_TYPE};
+use frib_datasource::DataSink;
+use rust_ringitem_format::RingItem;
+
+/// Time between heartbeats.
+pub const HEART_BEAT_MICROSECONDS : f64 = 524.288;
+/// LSB value of the TDC.
+pub const TDC_TICK_PS : f64 = 0.9765625;
+
+/// Convert a frame number (relative to the first frame) to a mikumari timestamp.
+pub fn hb_frame_to_ts(frame: u64) -> f64 {
+    let frame_t : f64 = frame as f64 * HEART_BEAT_MICROSECONDS; // frame_time in usec.
+    (frame_t * (1.0e6)) / TDC_TICK_PS
+}
+
+/// Makes frame ring items from mikumari data and writes them to a data sink.
+///
+/// A frame item is written when the heartbeat that starts the next frame arrives
+/// or when finish is called.
+pub struct Framer {
+    sink           : Box<dyn DataSink>,
+    sid            : u32,
+    frame_no       : u64,              // Current frame relative to the first.
+    absolute_frame : u64,              // Current frame counted from the first delimiter's number.
+    item           : Option<RingItem>, // Frame being built; None before the first heartbeat.
+    frames         : u64,              // Frame items written.
+}
+impl Framer {
+    // Start the item for a frame given its first delimiter:
+
+    fn start_frame(&mut self, d1 : u64) {
+        let mut item = RingItem::new_with_body_header(
+            MIKUMARI_FRAME_ITEM_TYPE,
+            hb_frame_to_ts(self.frame_no) as u64,
+            self.sid, 0
+        );
+        item.add(self.absolute_frame);
+        item.add(d1);
+        self.item = Some(item);
+    }
+    fn write_frame(&mut self) -> Result<(), String> {
+        if let Some(item) = self.item.take() {
+            self.sink.write(&item)?;
+            self.frames += 1;
+        }
+        Ok(())
+    }
+
+    /// ### Parameters:
+    /// *  sink - where the frame items go.
+    pub fn new(sink : Box<dyn DataSink>) -> Framer {
+        Framer {
+            sink,
+            sid            : 0,
+            frame_no       : 0,
+            absolute_frame : 0,
+            item           : None,
+            frames         : 0,
+        }
+    }
+    /// ### Parameters:
+    /// *  sid - source id put in the body headers of frames started after this (default 0).
+    pub fn set_source_id(&mut self, sid : u32) {
+        self.sid = sid;
+    }
+    /// Add the next datum of the raw data.
+    ///
+    /// ### Returns:
+    /// An error from the sink if a completed frame could not be written.
+    pub fn add(&mut self, datum : &MikumariDatum) -> Result<(), String> {
+        match datum {
+            MikumariDatum::Heartbeat0(d) => {
+                // Heart beat means we write the item and start a new one:
+
+                if self.item.is_some() {
+                    self.write_frame()?;
+                    self.frame_no += 1;
+                    self.absolute_frame += 1;
+                } else if !self.started() {
+                    self.absolute_frame = d.frame();   // our t0 frame.
+                }
+                self.start_frame(d.get());
+            },
+            MikumariDatum::Heartbeat1(_) | MikumariDatum::LeadingEdge(_) | MikumariDatum::TrailingEdge(_) => {
+                if let Some(item) = self.item.as_mut() {
+                    item.add(datum.get());            // ISsue #11 Hits already include the TOT field.
+                }
+            },
+            MikumariDatum::Other(_) => (),
+        }
+        Ok(())
+    }
+    /// Add data from a reader until it has no more (end of file or a read error).
+    pub fn read_from(&mut self, src : &mut MikumariReader) -> Result<(), String> {
+        while let Ok(datum) = src.read() {
+            self.add(&datum)?;
+        }
+        Ok(())
+    }
+    /// Write the frame being built and flush the sink.  Data added after this
+    /// are discarded until the next heartbeat, which starts the next frame.
+    pub fn finish(&mut self) -> Result<(), String> {
+        if self.item.is_some() {
+            self.write_frame()?;
+            self.frame_no += 1;
+            self.absolute_frame += 1;
+        }
+        self.sink.flush();
+        Ok(())
+    }
+    /// Write some other item (e.g. a state change) to the sink.  It is written
+    /// before the frame being built.
+    pub fn write(&mut self, item : &RingItem) -> Result<(), String> {
+        self.sink.write(item)
+    }
+    /// True once the first heartbeat has been seen.
+    pub fn started(&self) -> bool {
+        self.item.is_some() || self.frames > 0
+    }
+    /// The number of frame items written.
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+}
+
+#[cfg(test)]
+mod framer_tests {
+    use super::*;
+    use crate::mikumari_format::{Delimeter1, Delimeter2, HRTDCLeading, HRTDCTrailing};
+    use rust_ringitem_format::body_header_size;
+    use std::cell::RefCell;
+    use std::io::Cursor;
+    use std::rc::Rc;
+
+    // What we keep of a written item:  type, timestamp, source id and body words.
+
+    type Written = (u32, u64, u32, Vec<u64>);
+    type Saved = Rc<RefCell<Vec<Written>>>;
+
+    struct TestSink {
+        items   : Saved,
+        flushed : Rc<RefCell<bool>>,
+    }
+    impl DataSink for TestSink {
+        fn open(&mut self, _uri: &str) -> Result<(), String> {Ok(())}
+        fn write(&mut self, item : &RingItem) -> Result<(), String> {
+            let bh = item.get_bodyheader().unwrap();
+            let words = item.payload()[body_header_size()..].chunks(8)
+                .map(|w| u64::from_ne_bytes(w.try_into().unwrap()))
+                .collect();
+            self.items.borrow_mut().push((item.type_id(), bh.timestamp, bh.source_id, words));
+            Ok(())
+        }
+        fn close(&mut self) {}
+        fn flush(&mut self) {
+            *self.flushed.borrow_mut() = true;
+        }
+    }
+    struct FailingSink {}
+    impl DataSink for FailingSink {
+        fn open(&mut self, _uri: &str) -> Result<(), String> {Ok(())}
+        fn write(&mut self, _item : &RingItem) -> Result<(), String> {
+            Err(String::from("sink failed"))
+        }
+        fn close(&mut self) {}
+    }
+    fn framer() -> (Framer, Saved, Rc<RefCell<bool>>) {
+        let items = Saved::default();
+        let flushed = Rc::new(RefCell::new(false));
+        let sink = TestSink { items: items.clone(), flushed: flushed.clone() };
+        (Framer::new(Box::new(sink)), items, flushed)
+    }
+    fn d1(frame : u32) -> MikumariDatum {
+        MikumariDatum::Heartbeat0(Delimeter1::new(0, frame))
+    }
+    fn d2(size : u32) -> MikumariDatum {
+        MikumariDatum::Heartbeat1(Delimeter2::new(size))
+    }
+    fn le(time : u32) -> MikumariDatum {
+        MikumariDatum::LeadingEdge(HRTDCLeading::new(1, 10, time))
+    }
+    fn te(time : u32) -> MikumariDatum {
+        MikumariDatum::TrailingEdge(HRTDCTrailing::new(1, 10, time))
+    }
+    fn add_all(framer : &mut Framer, data : &[MikumariDatum]) {
+        for datum in data {
+            framer.add(datum).unwrap();
+        }
+    }
+
+    #[test]
+    fn hb_frame_to_ts_1() {
+        assert_eq!(hb_frame_to_ts(0), 0.0);
+        assert_eq!(hb_frame_to_ts(1) as u64, 1 << 29);
+        assert_eq!(hb_frame_to_ts(1000) as u64, 1000 << 29);
+    }
+    #[test]
+    fn partial_1() {
+        // Data before the first heartbeat are skipped:
+
+        let (mut f, items, _) = framer();
+        add_all(&mut f, &[le(1), d2(8), te(2)]);
+        assert!(!f.started());
+        f.finish().unwrap();
+        assert!(items.borrow().is_empty());
+        assert_eq!(f.frames(), 0);
+    }
+    #[test]
+    fn frames_1() {
+        let (mut f, items, flushed) = framer();
+        let throttle = MikumariDatum::Other(5);
+        add_all(&mut f, &[le(1), d1(100), d2(16), le(10), throttle, te(20), d1(101), d2(8), le(30)]);
+
+        // Only the first frame is complete:
+
+        assert!(f.started());
+        assert_eq!(items.borrow().len(), 1);
+        f.finish().unwrap();
+        assert!(*flushed.borrow());
+        assert_eq!(f.frames(), 2);
+        assert_eq!(
+            *items.borrow(),
+            vec![
+                (MIKUMARI_FRAME_ITEM_TYPE, 0, 0, vec![100, d1(100).get(), d2(16).get(), le(10).get(), te(20).get()]),
+                (MIKUMARI_FRAME_ITEM_TYPE, 1 << 29, 0, vec![101, d1(101).get(), d2(8).get(), le(30).get()]),
+            ]
+        );
+    }
+    #[test]
+    fn frames_2() {
+        // The absolute frame number doesn't roll over with the delimiter's and
+        // the source id goes in the body header:
+
+        let (mut f, items, _) = framer();
+        f.set_source_id(7);
+        add_all(&mut f, &[d1(0xffffff), d2(0), d1(0), d2(0)]);
+        f.finish().unwrap();
+        let items = items.borrow();
+        assert_eq!(items.iter().map(|i| (i.2, i.3[0])).collect::<Vec<_>>(), vec![(7, 0xffffff), (7, 0x1000000)]);
+    }
+    #[test]
+    fn write_1() {
+        // Other items go out ahead of the frame being built:
+
+        let (mut f, items, _) = framer();
+        add_all(&mut f, &[d1(5), d2(0)]);
+        f.write(&RingItem::new_with_body_header(1, 0, 3, 1)).unwrap();
+        f.finish().unwrap();
+        f.write(&RingItem::new_with_body_header(2, 0, 3, 2)).unwrap();
+        let types : Vec<u32> = items.borrow().iter().map(|i| i.0).collect();
+        assert_eq!(types, vec![1, MIKUMARI_FRAME_ITEM_TYPE, 2]);
+    }
+    #[test]
+    fn read_from_1() {
+        let data = [le(1), d1(3), d2(8), le(2), d1(4), d2(0)];
+        let bytes : Vec<u8> = data.iter().flat_map(|d| d.get().to_ne_bytes()).collect();
+        let mut reader = MikumariReader::new(Box::new(Cursor::new(bytes)));
+
+        let (mut f, items, _) = framer();
+        f.read_from(&mut reader).unwrap();
+        f.finish().unwrap();
+        let frames : Vec<u64> = items.borrow().iter().map(|i| i.3[0]).collect();
+        assert_eq!(frames, vec![3, 4]);
+    }
+    #[test]
+    fn finish_1() {
+        // Frames after a finish carry on from the ones before it:
+
+        let (mut f, items, _) = framer();
+        add_all(&mut f, &[d1(10), d2(0)]);
+        f.finish().unwrap();
+        add_all(&mut f, &[le(1), d1(11), d2(0)]);
+        f.finish().unwrap();
+        let frames : Vec<(u64, u64)> = items.borrow().iter().map(|i| (i.1, i.3[0])).collect();
+        assert_eq!(frames, vec![(0, 10), (1 << 29, 11)]);
+        assert_eq!(items.borrow()[1].3.len(), 3);       // The hit before the heartbeat is gone.
+    }
+    #[test]
+    fn fail_1() {
+        let mut f = Framer::new(Box::new(FailingSink {}));
+        f.add(&d1(1)).unwrap();                          // Nothing written yet.
+        assert_eq!(f.add(&d1(2)), Err(String::from("sink failed")));
+        assert_eq!(f.frames(), 0);
+
+        let mut f = Framer::new(Box::new(FailingSink {}));
+        f.add(&d1(1)).unwrap();
+        assert_eq!(f.finish(), Err(String::from("sink failed")));
+    }
+}
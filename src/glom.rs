@@ -4,31 +4,550 @@
 //! 
 //! 
 use frib_datasource::DataSink;
-use rust_ringitem_format::{RingItem, PHYSICS_EVENT};
+use rust_ringitem_format::{RingItem, PHYSICS_EVENT, END_RUN};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+// Bits in the flags word of a hit:
+
+/// The flags word of a frame boundary.
+pub const FRAME_BOUNDARY : u16 = 0xffff;
+/// Set in the flags word of a trailing edge hit.
+pub const TRAILING_EDGE  : u16 = 0x8000;
+/// Set in the flags word of a leading/trailing edge pair.
+pub const PAIR_RECORD    : u16 = 0x4000;
+/// Set in a pair's flags word if there was no trailing edge.
+pub const NO_TRAILING    : u16 = 0x2000;
+/// Set in a pair's flags word if there was no leading edge.
+pub const NO_LEADING     : u16 = 0x1000;
+
+/// Source id that, in a channel specification, matches any source.
+pub const ANY_SOURCE : u32 = 0xffffffff;
+
+/// The full identification of a channel: the source id of the board it's on
+/// and its channel number on that board.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ChannelId {
+    pub source  : u32,
+    pub channel : u16,
+}
+impl ChannelId {
+    /// The channel id written in frame boundaries.
+    pub const FRAME : ChannelId = ChannelId { source: 0xffffffff, channel: 0xffff };
+
+    pub fn new(source : u32, channel : u16) -> ChannelId {
+        ChannelId { source, channel }
+    }
+    /// True if this channel specification matches a channel.  The specification
+    /// matches if the channels are the same and either the sources are the same
+    /// or the specification's source is ANY_SOURCE.
+    pub fn matches(&self, id : &ChannelId) -> bool {
+        self.channel == id.channel && (self.source == ANY_SOURCE || self.source == id.source)
+    }
+}
+impl fmt::Display for ChannelId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.source == ANY_SOURCE {
+            write!(f, "{}", self.channel)
+        } else {
+            write!(f, "{}:{}", self.source, self.channel)
+        }
+    }
+}
+/// Parse a channel specification: either ```source:channel``` or just ```channel```
+/// which gives a source of ANY_SOURCE.
+impl std::str::FromStr for ChannelId {
+    type Err = String;
+    fn from_str(s : &str) -> Result<ChannelId, String> {
+        let bad = |_| format!("Invalid channel specification '{}' must be channel or source:channel", s);
+        match s.split_once(':') {
+            Some((source, channel)) => Ok(ChannelId::new(
+                source.trim().parse::<u32>().map_err(bad)?,
+                channel.trim().parse::<u16>().map_err(bad)?
+            )),
+            None => Ok(ChannelId::new(ANY_SOURCE, s.trim().parse::<u16>().map_err(bad)?)),
+        }
+    }
+}
+
+/// Errors that can be returned by Glom operations.
+#[derive(Debug, Clone, PartialEq)]
+pub enum GlomError {
+    /// The data sink refused a ring item. The string is the sink's message.
+    SinkWrite(String),
+    /// A hit was earlier than the start of the event being built
+    /// (or the last event written) and the policy is OutOfOrderPolicy::Error.
+    OutOfOrder { time : u64, t0 : u64 },
+    /// A hit time can't be written relative to the event timestamp in
+    /// HitLayout::Compact.  The event is not written.
+    RelativeTime { time : u64, t0 : u64 },
+}
+impl fmt::Display for GlomError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GlomError::SinkWrite(msg) => write!(f, "Unable to write a ring item: {}", msg),
+            GlomError::OutOfOrder { time, t0 } => write!(
+                f, "Hit at time {} is earlier than the event start time {}", time, t0
+            ),
+            GlomError::RelativeTime { time, t0 } => write!(
+                f, "Hit at time {} is too far from the event start time {} for the compact layout", time, t0
+            ),
+        }
+    }
+}
+impl std::error::Error for GlomError {}
+
+/// Result type returned by the Glom methods.
+pub type GlomResult<T> = Result<T, GlomError>;
+
+/// What to do with hits that are earlier than the event being built.
+/// The default, used by Glom::new and defenestrator, is Drop.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutOfOrderPolicy {
+    /// Return GlomError::OutOfOrder from add_hit.
+    Error,
+    /// Throw the hit away, counting it in the statistics.
+    #[default]
+    Drop,
+    /// Put the hit in the event being built (or a new one if there is none).
+    Include,
+}
+
+/// How hits are grouped into events.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CoincidenceMode {
+    /// The first hit opens a window of dt ticks.  Hits in that window are
+    /// in the event (the default).
+    Fixed,
+    /// Each hit within dt ticks of the previous hit in the event extends
+    /// the event.
+    Sliding,
+    /// Only hits on one of the channels open an event.  Hits from pre ticks
+    /// before the trigger hit through post ticks after it are in the event.
+    /// Hits that don't fall in any trigger window are discarded.
+    /// Trigger channels are channel specifications (see ChannelId::matches).
+    Trigger { channels : Vec<ChannelId>, pre : u64, post : u64 },
+}
+
+/// How hits are encoded in the body of the events that are written.
+/// The body of each event starts with an event header that has the
+/// layout id (see HitLayout::id) so decoders can tell which was used.
+///
+/// Every layout starts a hit with the flags word (u16), channel (u16) and
+/// source id (u32).  What follows depends on the layout and on whether the
+/// hit is a pair (PAIR_RECORD):
+///
+/// | Layout  | Hit                      | Pair                                        |
+/// |---------|--------------------------|---------------------------------------------|
+/// | Legacy  | time u64, tot u32        | leading u64, trailing u64, width u32        |
+/// | Compact | time i32, tot u32        | leading i32, trailing i32, width u32        |
+/// | Aligned | time u64, tot u32, 0 u32 | leading u64, trailing u64, width u32, 0 u32 |
+///
+/// Compact times are relative to the event timestamp.  The frame number of a
+/// frame boundary does not fit in 32 bits, so in the compact layout its low 32 bits
+/// are in the time field and its high 32 bits are in the tot field.
+/// Aligned records are padded to a multiple of 8 bytes so that every field
+/// is on its natural boundary relative to the start of the event body.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HitLayout {
+    /// 64 bit absolute times (the default).
+    #[default]
+    Legacy,
+    /// 32 bit times relative to the event timestamp.
+    Compact,
+    /// 64 bit absolute times with 8 byte aligned records.
+    Aligned,
+}
+impl HitLayout {
+    /// The id of the layout written in the event header.
+    pub fn id(&self) -> u16 {
+        match self {
+            HitLayout::Legacy  => 0,
+            HitLayout::Compact => 1,
+            HitLayout::Aligned => 2,
+        }
+    }
+    /// The layout with an id; None if there is none.
+    pub fn from_id(id : u16) -> Option<HitLayout> {
+        match id {
+            0 => Some(HitLayout::Legacy),
+            1 => Some(HitLayout::Compact),
+            2 => Some(HitLayout::Aligned),
+            _ => None,
+        }
+    }
+}
+
+/// When a Glom asks its data sink to flush what it has buffered.
+/// Ring items are always handed to the sink as they are made; flushing
+/// less often lets file sinks write in larger blocks.  Whatever the policy,
+/// the sink is flushed after an end run item is written and when the Glom
+/// is dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FlushPolicy {
+    /// Flush after every ring item (the default).
+    #[default]
+    EveryItem,
+    /// Flush once this many ring items have been written since the last flush.
+    Items(u64),
+    /// Flush once this many bytes of ring items have been written since the last flush.
+    Bytes(u64),
+    /// Flush on the first ring item written at least this long after the last flush
+    /// or, if no more items come, when Glom::flush_if_due is called after that long.
+    Interval(Duration),
+}
+/// The flush policy defenestrator uses when it isn't given one (bytes:1048576).
+/// A ```tcp://``` ring that must get each event as it is built needs EveryItem instead.
+pub const DEFAULT_BATCH_FLUSH : FlushPolicy = FlushPolicy::Bytes(1 << 20);
+
+/// Parses always, items:n, bytes:n or ms:n (Interval in milliseconds).
+impl std::str::FromStr for FlushPolicy {
+    type Err = String;
+    fn from_str(s : &str) -> Result<FlushPolicy, String> {
+        if s == "always" {
+            return Ok(FlushPolicy::EveryItem);
+        }
+        let bad = || format!("Invalid flush policy '{}' must be always, items:n, bytes:n or ms:n", s);
+        let (kind, value) = s.split_once(':').ok_or_else(bad)?;
+        let value = value.parse::<u64>().ok().filter(|v| *v > 0).ok_or_else(bad)?;
+        match kind {
+            "items" => Ok(FlushPolicy::Items(value)),
+            "bytes" => Ok(FlushPolicy::Bytes(value)),
+            "ms"    => Ok(FlushPolicy::Interval(Duration::from_millis(value))),
+            _ => Err(bad()),
+        }
+    }
+}
+
+/// Size in bytes of the header at the start of each event body:
+///
+/// | Offset | Contents    | Type | Notes                                             |
+/// |--------|-------------|------|---------------------------------------------------|
+/// | 0      | layout id   | u16  | HitLayout::id                                     |
+/// | 2      | header size | u16  | EVENT_HEADER_SIZE                                 |
+/// | 4      | flags       | u32  | EVENT_CROSSES_FRAME, EVENT_OUT_OF_ORDER           |
+/// | 8      | hit count   | u32  | Hits and pairs; frame boundaries don't count.     |
+/// | 12     | reserved    | u32  | 0                                                 |
+/// | 16     | first frame | u64  | Absolute frame number of the first hit.           |
+/// | 24     | last frame  | u64  | Absolute frame number of the last hit.            |
+///
+/// The frame of a hit is the last frame boundary before it.  If there was none, or the
+/// event has no hits, the frame numbers are NO_FRAME.
+pub const EVENT_HEADER_SIZE : u16 = 32;
+/// Set in the event header flags if the event's hits are in more than one frame.
+pub const EVENT_CROSSES_FRAME : u32 = 0x1;
+/// Set in the event header flags if the event has out of order hits that were
+/// included by OutOfOrderPolicy::Include.
+pub const EVENT_OUT_OF_ORDER  : u32 = 0x2;
+/// The first/last frame number in an event header when it is not known.
+pub const NO_FRAME : u64 = 0xffffffffffffffff;
+
+/// Event acceptance criteria applied when an event is flushed.
+/// The default accepts every event.
+/// 
+/// Multiplicity is the number of hits (leading and trailing edges each count,
+/// a leading/trailing pair counts once) that are not on an ignored channel.
+/// Frame boundaries never count.  Hits on ignored channels are written with
+/// accepted events but they can't satisfy the multiplicity or required channel
+/// criteria.  Channels are channel specifications (see ChannelId::matches).
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct EventFilter {
+    pub min_multiplicity  : usize,              // Fewest hits in an accepted event.
+    pub max_multiplicity  : Option<usize>,      // Most hits in an accepted event.
+    pub required_channels : Vec<ChannelId>,     // Each of these must have a hit.
+    pub ignored_channels  : Vec<ChannelId>,     // These don't count.
+}
+impl EventFilter {
+    /// Create a filter that accepts all events.
+    pub fn new() -> EventFilter {
+        EventFilter::default()
+    }
+    /// Decide if an event's hits pass the filter.
+    /// 
+    /// ### Parameters:
+    /// * hits - the flags, channel, time, tot of each hit.  Frame boundaries
+    ///   have flags of FRAME_BOUNDARY.
+    pub fn accepts(&self, hits : &[(u16, ChannelId, u64, u32)]) -> bool {
+        let channels : Vec<ChannelId> = hits.iter()
+            .filter(|h| h.0 != FRAME_BOUNDARY)
+            .map(|h| h.1)
+            .filter(|c| !self.ignored_channels.iter().any(|i| i.matches(c)))
+            .collect();
+        let multiplicity = channels.len();
+        if multiplicity < self.min_multiplicity {
+            return false;
+        }
+        if let Some(max) = self.max_multiplicity && multiplicity > max {
+            return false;
+        }
+        self.required_channels.iter().all(|r| channels.iter().any(|c| r.matches(c)))
+    }
+}
+
+/// The header of an event body as decoded by decode_event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EventHeader {
+    pub layout      : HitLayout,
+    pub flags       : u32,      // EVENT_CROSSES_FRAME, EVENT_OUT_OF_ORDER.
+    pub hit_count   : u32,
+    pub first_frame : u64,
+    pub last_frame  : u64,
+}
+
+/// A hit, pair or frame boundary from an event body as decoded by decode_event.
+/// Times are absolute whatever the layout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventRecord {
+    Hit { trailing : bool, channel : ChannelId, time : u64, tot : u32 },
+    /// A missing edge is None.
+    Pair { channel : ChannelId, leading : Option<u64>, trailing : Option<u64>, width : u32 },
+    FrameBoundary { frame : u64, timestamp : u64, flags : u32 },
+}
+
+// Takes the fields of an event body in order.
+
+struct BodyCursor<'a> {
+    body   : &'a [u8],
+    offset : usize,
+}
+impl BodyCursor<'_> {
+    fn take<const N : usize>(&mut self) -> Result<[u8; N], String> {
+        let bytes = self.body.get(self.offset..self.offset + N).ok_or_else(|| format!(
+            "The event body ends at byte {} in a field that needs {} bytes at byte {}",
+            self.body.len(), N, self.offset
+        ))?;
+        self.offset += N;
+        Ok(bytes.try_into().unwrap())
+    }
+    fn u16(&mut self) -> Result<u16, String> { Ok(u16::from_ne_bytes(self.take()?)) }
+    fn u32(&mut self) -> Result<u32, String> { Ok(u32::from_ne_bytes(self.take()?)) }
+    fn i32(&mut self) -> Result<i32, String> { Ok(i32::from_ne_bytes(self.take()?)) }
+    fn u64(&mut self) -> Result<u64, String> { Ok(u64::from_ne_bytes(self.take()?)) }
+}
+
+/// Decode the body of an event written by a Glom.
+///
+/// ### Parameters:
+/// *  body  - the ring item payload after the body header.
+/// *  stamp - the timestamp in the body header; compact layout times are relative to it.
+///
+/// ### Returns:
+/// The event header and the records in the order they were written or a description of
+/// what's wrong with the body (an unknown layout or a record cut off by the end of the body).
+pub fn decode_event(body : &[u8], stamp : u64) -> Result<(EventHeader, Vec<EventRecord>), String> {
+    let mut cursor = BodyCursor { body, offset : 0 };
+    let id = cursor.u16()?;
+    let layout = HitLayout::from_id(id).ok_or_else(|| format!("Unknown hit layout id {}", id))?;
+    let header_size = cursor.u16()? as usize;
+    let flags = cursor.u32()?;
+    let hit_count = cursor.u32()?;
+    cursor.u32()?;                                  // Reserved.
+    let first_frame = cursor.u64()?;
+    let last_frame = cursor.u64()?;
+    if header_size < cursor.offset {
+        return Err(format!("The event header size {} is too small", header_size));
+    }
+    cursor.offset = header_size;
+    let header = EventHeader { layout, flags, hit_count, first_frame, last_frame };
+
+    let absolute = |relative : i32| stamp.wrapping_add_signed(relative as i64);
+    let mut records = Vec::new();
+    while cursor.offset < body.len() {
+        let record_flags = cursor.u16()?;
+        let channel = ChannelId { channel : cursor.u16()?, source : cursor.u32()? };
+        let record = if record_flags == FRAME_BOUNDARY {
+            let (frame, timestamp) = match layout {
+                HitLayout::Compact => {
+                    let low = cursor.u32()? as u64;
+                    let frame = low | ((cursor.u32()? as u64) << 32);
+                    (frame, absolute(cursor.i32()?))
+                },
+                _ => (cursor.u64()?, cursor.u64()?),
+            };
+            EventRecord::FrameBoundary { frame, timestamp, flags : cursor.u32()? }
+        } else if record_flags & PAIR_RECORD != 0 {
+            let (leading, trailing) = match layout {
+                HitLayout::Compact => (absolute(cursor.i32()?), absolute(cursor.i32()?)),
+                _ => (cursor.u64()?, cursor.u64()?),
+            };
+            EventRecord::Pair {
+                channel,
+                leading  : if record_flags & NO_LEADING != 0 { None } else { Some(leading) },
+                trailing : if record_flags & NO_TRAILING != 0 { None } else { Some(trailing) },
+                width    : cursor.u32()?,
+            }
+        } else {
+            let time = match layout {
+                HitLayout::Compact => absolute(cursor.i32()?),
+                _ => cursor.u64()?,
+            };
+            EventRecord::Hit { trailing : record_flags & TRAILING_EDGE != 0, channel, time, tot : cursor.u32()? }
+        };
+        if layout == HitLayout::Aligned {
+            cursor.u32()?;                          // Padding.
+        }
+        records.push(record);
+    }
+    Ok((header, records))
+}
+
+/// Counters kept by a Glom.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct GlomStatistics {
+    pub events_written    : u64,   // Events that passed the filter and were written.
+    pub events_rejected   : u64,   // Events that failed the filter.
+    pub dropped_hits      : u64,   // Out of order hits dropped by OutOfOrderPolicy::Drop.
+    pub untriggered_hits  : u64,   // Trigger mode hits outside of any trigger window.
+    pub crossing_events   : u64,   // Events written that span more than one frame.
+    pub multiplicity      : Vec<u64>,  // [n] - events written with n hits.
+}
+/// The difference of two sets of counters e.g. the counts in a run are
+/// the counters at the end of the run - those at the beginning.
+impl std::ops::Sub for GlomStatistics {
+    type Output = GlomStatistics;
+    fn sub(self, start : GlomStatistics) -> GlomStatistics {
+        GlomStatistics {
+            events_written   : self.events_written - start.events_written,
+            events_rejected  : self.events_rejected - start.events_rejected,
+            dropped_hits     : self.dropped_hits - start.dropped_hits,
+            untriggered_hits : self.untriggered_hits - start.untriggered_hits,
+            crossing_events  : self.crossing_events - start.crossing_events,
+            multiplicity     : self.multiplicity.iter().enumerate()
+                .map(|(n, count)| count - start.multiplicity.get(n).copied().unwrap_or(0))
+                .collect(),
+        }
+    }
+}
+
 /// The Glom struct and its implementation are what 
 /// do the work.
 ///  Note that we can add hits and frame boundaries to the
 ///  event being accumulated.
 ///  Events are timestamped with the timestamp of the first hit.
 /// 
-///   hits are stored, internally, as a 16 bit channel/edge number
-///  and edge bit and a 64 bit time relative to the start of run.
+///   hits are stored, internally, as a 16 bit flags word (edge bit etc.),
+///  the channel id and a 64 bit time relative to the start of run.
 ///  The frame boundary marker is stored as an absolute  frame number with
-///  all bits set in the channel/edge word.
+///  all bits set in the flags word.
 ///  If the first hit is a frame boundary, it does not start a coincidence interval.
+/// 
+///  In CoincidenceMode::Trigger, hits and frame boundaries that arrive when no event
+///  is open are held in a pending list until they are either older than the pre trigger
+///  window or pulled into an event by a trigger hit.
 pub struct Glom {
     sink : Box<dyn DataSink>,       // Anything writable.
     sid  : u32,                     // Source id.
     dt   : u64,                     // coincidence interval.
     t0   : Option<u64>,             // when some, the start time of the glom.
-    hits : Vec<(u16, u64, u32)>,    // Hits accumulated so far. Issue #11 add TOT.
+    last : Option<u64>,             // Time of the last hit in the event.
+    last_t0 : Option<u64>,          // Start time of the last event flushed.
+    hits : Vec<(u16, ChannelId, u64, u32)>,    // Hits accumulated so far. Issue #11 add TOT.
+    pending : Vec<(u16, ChannelId, u64, u32)>, // Trigger mode hits waiting for a trigger.
+    mode    : CoincidenceMode,      // How hits are grouped into events.
+    policy  : OutOfOrderPolicy,     // What to do with out of order hits.
+    filter  : EventFilter,          // Which events get written.
+    stats   : GlomStatistics,       // Counters.
+    layout  : HitLayout,            // How hits are written.
+    frame   : Option<u64>,          // Last frame boundary added.
+    start_frame   : Option<u64>,    // Frame boundary in effect before hits[0].
+    pending_frame : Option<u64>,    // Frame boundary in effect before pending[0].
+    out_of_order  : bool,           // Event has included out of order hits.
+    frame_info    : BTreeMap<u64, (u64, u32)>,  // Frame number -> timestamp, flags.
+    crossing_frames_only : bool,    // Only write frame boundaries in events that cross frames.
+    flush_policy    : FlushPolicy,  // When the sink is flushed.
+    unflushed_items : u64,          // Items written since the last sink flush.
+    unflushed_bytes : u64,          // Bytes written since the last sink flush.
+    last_sink_flush : Instant,      // When the sink was last flushed.
 }
 
 impl Glom {
+    // Add to the hits/pending lists, remembering the frame the list starts in:
+    fn push_hit(&mut self, hit : (u16, ChannelId, u64, u32)) {
+        if self.hits.is_empty() {
+            self.start_frame = self.frame;
+        }
+        self.hits.push(hit);
+    }
+    fn push_pending(&mut self, hit : (u16, ChannelId, u64, u32)) {
+        if self.pending.is_empty() {
+            self.pending_frame = self.frame;
+        }
+        self.pending.push(hit);
+    }
     // Start a new hit:
-    fn new_event(&mut self, chan : u16, time: u64, tot: u32) {
+    fn new_event(&mut self, flags : u16, id : ChannelId, time: u64, tot: u32) {
         self.t0 = Some(time);
-        self.hits.push((chan, time, tot));
+        self.last = Some(time);
+        self.push_hit((flags, id, time, tot));
+    }
+    // True if a hit at time belongs in the event being built:
+    fn in_window(&self, time : u64) -> bool {
+        match (&self.mode, self.t0, self.last) {
+            (CoincidenceMode::Fixed, Some(t0), _) => time.saturating_sub(t0) <= self.dt,
+            (CoincidenceMode::Sliding, _, Some(last)) => time.saturating_sub(last) <= self.dt,
+            (CoincidenceMode::Trigger { post, .. }, Some(t0), _) => time.saturating_sub(t0) <= *post,
+            _ => false,
+        }
+    }
+    // Fixed and sliding windows -- the event is opened by any hit:
+    fn add_windowed(&mut self, flags : u16, id : ChannelId, time : u64, tot : u32) -> GlomResult<()> {
+        if self.t0.is_none() {
+            self.new_event(flags, id, time, tot);
+        } else if self.in_window(time) {
+            self.push_hit((flags, id, time, tot));
+            self.last = Some(time);
+        } else {
+            let status = self.flush();
+            self.new_event(flags, id, time, tot);
+            return status;
+        }
+        Ok(())
+    }
+    // Trigger mode -- only trigger channels open events:
+    fn add_triggered(&mut self, flags : u16, id : ChannelId, time : u64, tot : u32) -> GlomResult<()> {
+        let mut status = Ok(());
+        if self.t0.is_some() {
+            if self.in_window(time) {
+                self.push_hit((flags, id, time, tot));
+                self.last = Some(time);
+                return Ok(());
+            }
+            status = self.flush();
+        }
+        let (cutoff, trigger) = match &self.mode {
+            CoincidenceMode::Trigger { channels, pre, .. } =>
+                (time.saturating_sub(*pre), channels.iter().any(|c| c.matches(&id))),
+            _ => (time, false),
+        };
+        self.prune_pending(cutoff);
+        if trigger {
+            if !self.pending.is_empty() {
+                self.start_frame = self.pending_frame;
+                self.hits.append(&mut self.pending);
+            }
+            self.new_event(flags, id, time, tot);
+        } else {
+            self.push_pending((flags, id, time, tot));
+        }
+        status
+    }
+    // Remove pending hits earlier than cutoff along with any frame boundaries
+    // that precede them:
+    fn prune_pending(&mut self, cutoff : u64) {
+        let keep_from = self.pending.iter()
+            .rposition(|h| h.0 != FRAME_BOUNDARY && h.2 < cutoff)
+            .map_or(0, |i| i + 1);
+        self.stats.untriggered_hits +=
+            self.pending[..keep_from].iter().filter(|h| h.0 != FRAME_BOUNDARY).count() as u64;
+        for h in self.pending.drain(..keep_from) {
+            if h.0 == FRAME_BOUNDARY {
+                self.pending_frame = Some(h.2);
+            }
+        }
+        if keep_from > 0 {
+            self.forget_frames();
+        }
     }
     /// Flush the frame as a ring item. 
     /// Note that this is a no-op if t0 is None (e.g. maybe at end of run?).
@@ -36,24 +555,174 @@ impl Glom {
     /// Note that if hits are only frame boundaries, this can lead, at the end run,
     /// dropping them on the floor...why do this? Because we're not sure how to timestamp
     /// frame boundaries.
-    pub fn flush(&mut self) {
+    /// 
+    /// Events that don't pass the event filter are counted as rejected rather than
+    /// being written.
+    /// 
+    /// ### Returns:
+    /// GlomError::SinkWrite if the sink could not write the item.  In that case
+    /// the event is discarded so that the next event can be built.
+    pub fn flush(&mut self) -> GlomResult<()> {
         if let Some(stamp) = self.t0 {
+            if !self.filter.accepts(&self.hits) {
+                self.hits.clear();
+                self.t0 = None;
+                self.last = None;
+                self.out_of_order = false;
+                self.last_t0 = Some(stamp);
+                self.forget_frames();
+                self.stats.events_rejected += 1;
+                return Ok(());
+            }
             let mut item = RingItem::new_with_body_header(
                 PHYSICS_EVENT,
                 stamp, self.sid, 0
             );
+            let (flags, count, first, last) = self.event_summary();
+            item.add(self.layout.id());
+            item.add(EVENT_HEADER_SIZE);
+            item.add(flags);
+            item.add(count);
+            item.add(0u32);
+            item.add(first);
+            item.add(last);
+
             // Fill the body with hits:
-            for (ch, t, tot) in &self.hits {
-                item.add(*ch);
-                item.add(*t);
-                item.add(*tot);              // Issue #11
-            }
-            self.sink.write(&item).expect("Unable to write a ring item");
-            self.sink.flush();
+            let frames = !self.crossing_frames_only || flags & EVENT_CROSSES_FRAME != 0;
+            let encoded = self.hits.iter()
+                .filter(|hit| frames || hit.0 != FRAME_BOUNDARY)
+                .try_for_each(|hit| self.encode_hit(&mut item, stamp, hit));
             self.hits.clear();
             self.t0 = None;
+            self.last = None;
+            self.last_t0 = Some(stamp);
+            self.out_of_order = false;
+            self.forget_frames();
+            encoded?;
+            self.write_to_sink(&item)?;
+            self.stats.events_written += 1;
+            if flags & EVENT_CROSSES_FRAME != 0 {
+                self.stats.crossing_events += 1;
+            }
+            let n = count as usize;
+            if self.stats.multiplicity.len() <= n {
+                self.stats.multiplicity.resize(n + 1, 0);
+            }
+            self.stats.multiplicity[n] += 1;
         }
-        
+        Ok(())
+    }
+
+    // Write an item to the sink and flush the sink if the flush policy says it's time:
+
+    fn write_to_sink(&mut self, item : &RingItem) -> GlomResult<()> {
+        self.sink.write(item).map_err(GlomError::SinkWrite)?;
+        self.unflushed_items += 1;
+        self.unflushed_bytes += item.size() as u64;
+        let due = match self.flush_policy {
+            FlushPolicy::EveryItem       => true,
+            FlushPolicy::Items(n)        => self.unflushed_items >= n,
+            FlushPolicy::Bytes(n)        => self.unflushed_bytes >= n,
+            FlushPolicy::Interval(every) => self.last_sink_flush.elapsed() >= every,
+        };
+        if due {
+            self.flush_sink();
+        }
+        Ok(())
+    }
+
+    // Summarize the hits for the event header:
+    // the event flags, the hit count and the first and last frames.
+
+    fn event_summary(&self) -> (u32, u32, u64, u64) {
+        let mut frame = self.start_frame;
+        let mut count = 0u32;
+        let mut first : Option<Option<u64>> = None;
+        let mut last  : Option<u64> = None;
+        for (flags, _, t, _) in &self.hits {
+            if *flags == FRAME_BOUNDARY {
+                frame = Some(*t);
+            } else {
+                count += 1;
+                first.get_or_insert(frame);
+                last = frame;
+            }
+        }
+        let mut flags = 0u32;
+        if first.is_some_and(|f| f != last) {
+            flags |= EVENT_CROSSES_FRAME;
+        }
+        if self.out_of_order {
+            flags |= EVENT_OUT_OF_ORDER;
+        }
+        (flags, count, first.flatten().unwrap_or(NO_FRAME), last.unwrap_or(NO_FRAME))
+    }
+    // Forget the timestamps and flags of frames that are no longer in the
+    // hits or pending lists:
+
+    fn forget_frames(&mut self) {
+        let oldest = self.pending.iter().chain(self.hits.iter())
+            .filter(|h| h.0 == FRAME_BOUNDARY)
+            .map(|h| h.2)
+            .min();
+        match oldest {
+            Some(fno) => self.frame_info = self.frame_info.split_off(&fno),
+            None => self.frame_info.clear(),
+        }
+    }
+    // Add a hit to an event body in the layout:
+
+    fn encode_hit(&self, item : &mut RingItem, stamp : u64, hit : &(u16, ChannelId, u64, u32)) -> GlomResult<()> {
+        let (flags, id, t, tot) = *hit;
+        item.add(flags);
+        item.add(id.channel);
+        item.add(id.source);
+        let relative = |time : u64| {
+            i32::try_from(time as i128 - stamp as i128)
+                .map_err(|_| GlomError::RelativeTime { time, t0: stamp })
+        };
+        if flags == FRAME_BOUNDARY {
+            let (timestamp, frame_flags) = self.frame_info.get(&t).copied().unwrap_or((0, 0));
+            match self.layout {
+                HitLayout::Compact => {
+                    item.add(t as u32);
+                    item.add((t >> 32) as u32);
+                    item.add(relative(timestamp)?);
+                },
+                _ => {
+                    item.add(t);
+                    item.add(timestamp);
+                },
+            }
+            item.add(frame_flags);
+        } else if flags & PAIR_RECORD != 0 {
+            // Pairs: tot is the width, missing edges are written as 0.
+            let leading  = if flags & NO_LEADING != 0 { None } else { Some(t) };
+            let trailing = if flags & NO_TRAILING != 0 { None }
+                else if flags & NO_LEADING != 0 { Some(t) }
+                else { Some(t + tot as u64) };
+            match self.layout {
+                HitLayout::Compact => {
+                    item.add(leading.map_or(Ok(0), relative)?);
+                    item.add(trailing.map_or(Ok(0), relative)?);
+                },
+                _ => {
+                    item.add(leading.unwrap_or(0));
+                    item.add(trailing.unwrap_or(0));
+                },
+            }
+            item.add(tot);
+        } else {
+            match self.layout {
+                HitLayout::Compact => item.add(relative(t)?),
+                _ => item.add(t),
+            };
+            item.add(tot);              // Issue #11
+        }
+        if self.layout == HitLayout::Aligned {
+            item.add(0u32);
+        }
+        Ok(())
     }
 
     /// Create a glommer, the 
@@ -66,87 +735,278 @@ impl Glom {
     /// ### Returns:
     /// a Glom struct.
     /// 
+    /// ### Notes:
+    /// * The out of order policy is initially OutOfOrderPolicy::default().
+    /// * The coincidence mode is initially CoincidenceMode::Fixed.
+    /// * The event filter initially accepts all events.
+    /// * The hit layout is initially HitLayout::Legacy.
+    /// * The flush policy is initially FlushPolicy::EveryItem.
+    /// 
     pub fn new(sink : Box<dyn DataSink>, sid : u32, dt : u64) -> Glom {
         Glom {
-            sink : sink,
-            sid  : sid,
-            dt   : dt,
+            sink,
+            sid,
+            dt,
             t0   : None,            // Not making one.
-            hits : Vec::new()
+            last : None,
+            last_t0 : None,
+            hits : Vec::new(),
+            pending : Vec::new(),
+            mode    : CoincidenceMode::Fixed,
+            policy  : OutOfOrderPolicy::default(),
+            filter  : EventFilter::new(),
+            stats   : GlomStatistics::default(),
+            layout  : HitLayout::Legacy,
+            frame   : None,
+            start_frame   : None,
+            pending_frame : None,
+            out_of_order  : false,
+            frame_info    : BTreeMap::new(),
+            crossing_frames_only : false,
+            flush_policy    : FlushPolicy::EveryItem,
+            unflushed_items : 0,
+            unflushed_bytes : 0,
+            last_sink_flush : Instant::now(),
         }
     }
+    /// Set when the data sink is flushed.
+    pub fn set_flush_policy(&mut self, policy : FlushPolicy) {
+        self.flush_policy = policy;
+    }
+    /// The flush policy in effect.
+    pub fn flush_policy(&self) -> FlushPolicy {
+        self.flush_policy
+    }
+    /// Flush the data sink if the flush policy is FlushPolicy::Interval, items were
+    /// written since the last flush and the interval is up.  Call this while waiting
+    /// for input so that what was written does not wait for the next item.
+    pub fn flush_if_due(&mut self) {
+        if let FlushPolicy::Interval(every) = self.flush_policy
+            && self.unflushed_items > 0
+            && self.last_sink_flush.elapsed() >= every
+        {
+            self.flush_sink();
+        }
+    }
+    /// Flush the data sink now regardless of the flush policy.  Note this
+    /// does not write the event being built; that's what flush does.
+    pub fn flush_sink(&mut self) {
+        self.sink.flush();
+        self.unflushed_items = 0;
+        self.unflushed_bytes = 0;
+        self.last_sink_flush = Instant::now();
+    }
+    /// When only is true, frame boundaries are only written in events
+    /// whose hits are in more than one frame (EVENT_CROSSES_FRAME).  The event
+    /// header still has the first and last frame numbers.
+    pub fn set_crossing_frames_only(&mut self, only : bool) {
+        self.crossing_frames_only = only;
+    }
+    /// Set how hits are encoded in the events that are written.
+    pub fn set_layout(&mut self, layout : HitLayout) {
+        self.layout = layout;
+    }
     /// Alter the sid...
     pub fn set_sid(&mut self, sid:  u32) {
         self.sid = sid;
     }
+    /// Select what add_hit does with hits that are earlier than the
+    /// event being built.
+    pub fn set_out_of_order_policy(&mut self, policy : OutOfOrderPolicy) {
+        self.policy = policy;
+    }
+    /// Set the criteria events must meet to be written.
+    pub fn set_filter(&mut self, filter : EventFilter) {
+        self.filter = filter;
+    }
+    /// The number of hits held: in the event being built and, in trigger mode,
+    /// waiting for a trigger.  Frame boundaries are not counted.
+    pub fn held_hits(&self) -> usize {
+        self.hits.iter().chain(self.pending.iter())
+            .filter(|h| h.0 != FRAME_BOUNDARY)
+            .count()
+    }
+    /// Get a copy of the counters.
+    pub fn statistics(&self) -> GlomStatistics {
+        self.stats.clone()
+    }
+    /// Forget the event being built, untriggered hits, the last frame and the time of the
+    /// last event so that a new run (whose times start over) can be built.  The counters,
+    /// settings and sink are not changed.
+    ///
+    /// ### Notes:
+    /// Anything not yet written is lost; flush first to write the event being built.
+    pub fn reset(&mut self) {
+        self.t0 = None;
+        self.last = None;
+        self.last_t0 = None;
+        self.hits.clear();
+        self.pending.clear();
+        self.frame = None;
+        self.start_frame = None;
+        self.pending_frame = None;
+        self.out_of_order = false;
+        self.frame_info.clear();
+    }
+    /// Select how hits are grouped into events.  This should be done
+    /// before any hits are added.
+    pub fn set_mode(&mut self, mode : CoincidenceMode) {
+        self.mode = mode;
+    }
     /// Sometimes we need to just output a ring item.
     /// Since we own the data sink, this allows that:
     ///
     /// ### Parameters:
     /// * item - references a ring itemt to write unaltered.
     /// 
+    /// ### Returns:
+    /// GlomError::SinkWrite if the sink could not write the item.
+    /// 
     /// ### Notes:
-    /// *  Panics if unable to write.
     /// *  This has no effect on the t0, hits.  At the end of the run, presumably
     ///    one does a flush to write what's there first and then passes the end run item.
+    /// *  The sink is always flushed after an END_RUN item.
     /// 
-    pub fn write_item(&mut self, item: &RingItem) {
-        self.sink.write(&item).expect("Failed to pass through a ring item");
-        self.sink.flush();
+    pub fn write_item(&mut self, item: &RingItem) -> GlomResult<()> {
+        self.write_to_sink(item)?;
+        if item.type_id() == END_RUN {
+            self.flush_sink();
+        }
+        Ok(())
     }
     ///
     /// Add a frame boundary to the hits.  This does not
-    /// have any effect on the t0 value.
+    /// have any effect on the t0 value.  In trigger mode, if no event is
+    /// open, the boundary waits in the pending list with the untriggered hits.
     /// 
     /// ### Parameters:
     /// * fno - absolute frame number.
+    /// * timestamp - timestamp of the start of the frame.
+    /// * flags - frame flags.  For mikumari frames these are the heartbeat
+    ///   delimiter 1 flags in the low 16 bits and the delimiter 2 user flags
+    ///   in the high 16 bits.
     /// 
-    pub fn add_frame_boundary(&mut self,fno : u64) {
-        self.hits.push((0xffff, fno, 0xffffffff));   // issue #11
+    pub fn add_frame_boundary(&mut self, fno : u64, timestamp : u64, flags : u32) {
+        self.frame_info.insert(fno, (timestamp, flags));
+        let boundary = (FRAME_BOUNDARY, ChannelId::FRAME, fno, 0xffffffff);   // issue #11
+        if self.t0.is_none() && matches!(self.mode, CoincidenceMode::Trigger { .. }) {
+            self.push_pending(boundary);
+        } else {
+            self.push_hit(boundary);
+        }
+        self.frame = Some(fno);
     }
     ///
-    /// Add a hit.  We construct the channel number word from the channel number
-    /// and leading flag.  There are two cases to handle 
+    /// Add a hit.  We construct the flags word from the leading flag.
+    /// There are two cases to handle 
     /// 1.  t0 is None. In that case, we are a first hit and set t0 to Some(time). 
-    /// and add the channel/time to the hits vector.
-    /// 2. t0 is Some, in which case, if we are in the glom interval we just add our hit,
-    /// otherwise, flush and start a new event.
+    ///     and add the channel/time to the hits vector.
+    /// 2.  t0 is Some, in which case, if we are in the glom interval we just add our hit,
+    ///     otherwise, flush and start a new event.
+    /// 
+    /// The glom interval depends on the coincidence mode.  In CoincidenceMode::Trigger
+    /// only a hit on a trigger channel starts an event; the event also gets the pending
+    /// hits that are within the pre trigger window.
+    /// 
+    /// A hit earlier than t0 (or, if there's no event being built, earlier than the
+    /// t0 of the last event written) is out of order and is handled according to the
+    /// out of order policy.
     /// 
     /// ###  Parameters
     /// * leading - true if this is a leading edge hit.
-    /// * channel - The channel number.
+    /// * channel - The channel id: source id and channel number.
     /// * time    - The absolute time of the hit.
     /// * tot     - Time over threshold.
     /// 
-    pub fn add_hit(&mut self, leading : bool, channel : u8, time : u64, tot : u32) {
-        // Construct the u16 channel/edge tag.
+    /// ### Returns:
+    /// * GlomError::OutOfOrder if the hit is out of order and the policy is
+    ///   OutOfOrderPolicy::Error.  The hit is not added.
+    /// * GlomError::SinkWrite if the hit closed an event that could not be written.
+    /// 
+    pub fn add_hit(&mut self, leading : bool, channel : ChannelId, time : u64, tot : u32) -> GlomResult<()> {
+        // Construct the u16 flags word.
 
-        let chanword : u16 = if leading {
-            channel as u16
+        let flags : u16 = if leading {
+            0
         } else {
-            channel as u16 | 0x8000
+            TRAILING_EDGE
         };
-        match self.t0 {
-            None => self.new_event(chanword, time, tot),
-            Some(t0) => {
-                if time - t0 <= self.dt {
-                    self.hits.push((chanword, time, tot));
-                } else {
-                    self.flush();
-                    self.new_event(chanword, time, tot);
+        self.add_entry(flags, channel, time, tot)
+    }
+    ///
+    /// Add the output of a Pairer.  Pairs and unpaired edges are
+    /// added like hits using the leading edge time (the trailing edge time if there's
+    /// no leading edge).  Frame boundaries are added with add_frame_boundary.
+    /// 
+    /// In the event, a pair is written as a flags word with PAIR_RECORD set,
+    /// the channel id, the leading edge time, the trailing edge time and the width
+    /// (trailing - leading).  NO_TRAILING or NO_LEADING are set in the flags word
+    /// of unpaired edges, the missing time and the width are 0.
+    /// 
+    /// ### Parameters
+    /// * hit - the paired hit.
+    /// 
+    /// ### Returns:
+    /// As for add_hit.
+    /// 
+    pub fn add_paired(&mut self, hit : PairedHit) -> GlomResult<()> {
+        match hit {
+            PairedHit::Pair { channel, leading, trailing } =>
+                self.add_entry(PAIR_RECORD, channel, leading, (trailing - leading) as u32),
+            PairedHit::UnpairedLeading { channel, time } =>
+                self.add_entry(PAIR_RECORD | NO_TRAILING, channel, time, 0),
+            PairedHit::UnpairedTrailing { channel, time } =>
+                self.add_entry(PAIR_RECORD | NO_LEADING, channel, time, 0),
+            PairedHit::FrameBoundary { frame, timestamp, flags } => {
+                self.add_frame_boundary(frame, timestamp, flags);
+                Ok(())
+            }
+        }
+    }
+    // Add a hit given its flags word, channel, time and tot/width.
+    // Handles out of order hits and then the coincidence modes.
+    fn add_entry(&mut self, flags : u16, id : ChannelId, time : u64, tot : u32) -> GlomResult<()> {
+        if let Some(t0) = self.t0.or(self.last_t0) && time < t0 {
+            match self.policy {
+                OutOfOrderPolicy::Error => return Err(GlomError::OutOfOrder { time, t0 }),
+                OutOfOrderPolicy::Drop  => {
+                    self.stats.dropped_hits += 1;
+                    return Ok(());
+                },
+                OutOfOrderPolicy::Include => {
+                    if self.t0.is_some() {
+                        self.push_hit((flags, id, time, tot));
+                        self.out_of_order = true;
+                        return Ok(());
+                    }
                 }
             }
         }
+        match self.mode {
+            CoincidenceMode::Trigger { .. } => self.add_triggered(flags, id, time, tot),
+            _ => self.add_windowed(flags, id, time, tot),
+        }
+    }
+}
+/// Whatever the flush policy held back is flushed on shutdown.
+impl Drop for Glom {
+    fn drop(&mut self) {
+        self.flush_sink();
     }
 }
 #[cfg(test)]
+#[allow(clippy::needless_range_loop, clippy::explicit_counter_loop)]
 mod glom_tests {
     use super::*;
+    use std::cell::{Cell, RefCell};
+    use std::rc::Rc;
 
-    // Here's a struct that implements a data sink for our tests. It just copies the ring item.
+    // Here's a struct that implements a data sink for our tests. It just copies the ring item
+    // to where the test can see it.
 
+    type Written = Rc<RefCell<Option<RingItem>>>;
     struct TestSink {
-        item : Option<RingItem>
+        item : Written
     }
     impl DataSink for TestSink {
         fn open(&mut self, _uri: &str) -> Result<(), String> {Ok(())}
@@ -166,7 +1026,7 @@ mod glom_tests {
             for i in body_offset..p.len() {
                 new_item.add(p[i]);
             }
-            self.item  = Some(new_item);
+            *self.item.borrow_mut() = Some(new_item);
 
             Ok(())
         }
@@ -176,7 +1036,7 @@ mod glom_tests {
 
     #[test]
     fn new_1() {
-        let sink = TestSink {item: None};
+        let sink = TestSink {item: Written::default()};
         let glom = Glom::new(Box::new(sink), 1, 100);
         assert_eq!(glom.sid, 1);
         assert_eq!(glom.dt, 100);
@@ -187,7 +1047,7 @@ mod glom_tests {
     fn set_sid_1() {
         // Can change the source id:
 
-        let sink = TestSink {item: None};
+        let sink = TestSink {item: Written::default()};
         let mut glom = Glom::new(Box::new(sink), 1, 100);
         glom.set_sid(2);
         assert_eq!(glom.sid, 2);
@@ -196,12 +1056,7 @@ mod glom_tests {
     fn write_item_1() {
         // Can do pass through on an item.
 
-        let sink = Box::new(TestSink {item: None});
-        let p    = Box::into_raw(sink);
-        let rsink = unsafe {&*p};
-        let x = unsafe { Box::from_raw(p)};
-        
-        let mut glom = Glom::new(x, 1, 100);
+        let (mut glom, written) = test_glom();
         let mut item = RingItem::new_with_body_header(
             PHYSICS_EVENT,
             100, 2, 0
@@ -210,10 +1065,10 @@ mod glom_tests {
             let b : u8 = i;
             item.add(b);
         }
-        glom.write_item(&item);
+        glom.write_item(&item).unwrap();
         
-        assert!(rsink.item.is_some());
-        let item = rsink.item.as_ref().unwrap();
+        assert!(written.borrow().is_some());
+        let item = written.take().unwrap();
         assert_eq!(item.type_id(), PHYSICS_EVENT);
         assert!(item.has_body_header());
         let bh = item.get_bodyheader().unwrap();
@@ -233,87 +1088,62 @@ mod glom_tests {
     fn add_frame_1() {
         // Add a frame boundary.
 
-        let sink = Box::new(TestSink {item: None});
-        let p    = Box::into_raw(sink);
-        let rsink = unsafe {&*p};
-        let x = unsafe { Box::from_raw(p)};
-        
-        let mut glom = Glom::new(x, 1, 100);
+        let (mut glom, written) = test_glom();
 
-        glom.add_frame_boundary(123);
+        glom.add_frame_boundary(123, 0, 0);
 
         // Should add an pseudo hit but not write:
 
         assert_eq!(glom.hits.len(), 1);
-        assert_eq!(glom.hits[0], (0xffffu16, 123u64, 0xffffffffu32));
-        assert!(rsink.item.is_none());
+        assert_eq!(glom.hits[0], (FRAME_BOUNDARY, ChannelId::FRAME, 123u64, 0xffffffffu32));
+        assert!(written.borrow().is_none());
     }   
     #[test]
     fn add_hit_1() {
-        let sink = Box::new(TestSink {item: None});
-        let p    = Box::into_raw(sink);
-        let rsink = unsafe {&*p};
-        let x = unsafe { Box::from_raw(p)};
-        
-        let mut glom = Glom::new(x, 1, 100);
-        glom.add_hit(true, 1, 0, 666);    // The hit.
+        let (mut glom, written) = test_glom();
+        glom.add_hit(true, ChannelId::new(0, 1), 0, 666).unwrap();    // The hit.
         assert_eq!(glom.hits.len(), 1);
-        assert_eq!(glom.hits[0], (1u16, 0u64, 666u32));
-        assert!(rsink.item.is_none());
+        assert_eq!(glom.hits[0], (0u16, ChannelId::new(0, 1), 0u64, 666u32));
+        assert!(written.borrow().is_none());
 
     } 
     #[test]
     fn add_hit_2() {
-        let sink = Box::new(TestSink {item: None});
-        let p    = Box::into_raw(sink);
-        let rsink = unsafe {&*p};
-        let x = unsafe { Box::from_raw(p)};
-        
-        let mut glom = Glom::new(x, 1, 100);
-        glom.add_hit(true, 1, 0, 666);    // The hit.
-        glom.add_frame_boundary(123);
+        let (mut glom, written) = test_glom();
+        glom.add_hit(true, ChannelId::new(0, 1), 0, 666).unwrap();    // The hit.
+        glom.add_frame_boundary(123, 0, 0);
         assert_eq!(glom.hits.len(), 2);
-        assert_eq!(glom.hits[0], (1u16, 0u64, 666u32));
-        assert_eq!(glom.hits[1], (0xffffu16, 123u64, 0xffffffffu32));
-        assert!(rsink.item.is_none());
+        assert_eq!(glom.hits[0], (0u16, ChannelId::new(0, 1), 0u64, 666u32));
+        assert_eq!(glom.hits[1], (FRAME_BOUNDARY, ChannelId::FRAME, 123u64, 0xffffffffu32));
+        assert!(written.borrow().is_none());
     }
     #[test]
     fn add_hit_3() {
         // Two hits inside dt don't write
 
-        let sink = Box::new(TestSink {item: None});
-        let p    = Box::into_raw(sink);
-        let rsink = unsafe {&*p};
-        let x = unsafe { Box::from_raw(p)};
-        
-        let mut glom = Glom::new(x, 1, 100);
-        glom.add_hit(true, 1, 0, 666);    // The first hit.
-        glom.add_hit(true, 0, 50, 666);   // dt is 100.
+        let (mut glom, written) = test_glom();
+        glom.add_hit(true, ChannelId::new(0, 1), 0, 666).unwrap();    // The first hit.
+        glom.add_hit(true, ChannelId::new(0, 0), 50, 666).unwrap();   // dt is 100.
 
         assert_eq!(glom.hits.len(), 2);
-        assert_eq!(glom.hits[0], (1u16, 0u64, 666u32));
-        assert_eq!(glom.hits[1], (0u16, 50u64, 666u32));
-        assert!(rsink.item.is_none());
+        assert_eq!(glom.hits[0], (0u16, ChannelId::new(0, 1), 0u64, 666u32));
+        assert_eq!(glom.hits[1], (0u16, ChannelId::new(0, 0), 50u64, 666u32));
+        assert!(written.borrow().is_none());
     }
     #[test]
     fn add_hit_4() {
         // two hits outside dt writes the first.
-        let sink = Box::new(TestSink {item: None});
-        let p    = Box::into_raw(sink);
-        let rsink = unsafe {&*p};
-        let x = unsafe { Box::from_raw(p)};
-        
-        let mut glom = Glom::new(x, 1, 100);
-        glom.add_hit(true, 1, 50, 666);    // The first hit.
-        glom.add_hit(true, 0, 151, 666);   // dt is 100.
+        let (mut glom, written) = test_glom();
+        glom.add_hit(true, ChannelId::new(0, 1), 50, 666).unwrap();    // The first hit.
+        glom.add_hit(true, ChannelId::new(0, 0), 151, 666).unwrap();   // dt is 100.
 
         assert_eq!(glom.hits.len(), 1);    // Second hit still retained.
-        assert_eq!(glom.hits[0], (0u16, 151u64, 666u32));   // this is hit 0.
+        assert_eq!(glom.hits[0], (0u16, ChannelId::new(0, 0), 151u64, 666u32));   // this is hit 0.
 
         // Should have written:
 
-        assert!(rsink.item.is_some());
-        let item = rsink.item.as_ref().unwrap();
+        assert!(written.borrow().is_some());
+        let item = written.take().unwrap();
         assert_eq!(item.type_id(), PHYSICS_EVENT);
         assert!(item.has_body_header());        // THere is a body header and...
         let bh = item.get_bodyheader().unwrap();
@@ -321,43 +1151,44 @@ mod glom_tests {
         assert_eq!(bh.source_id, 1);
         assert_eq!(bh.barrier_type, 0); 
 
-        let body_offset = size_of::<u64>() + 2*size_of::<u32>();
+        let body_offset = size_of::<u64>() + 2*size_of::<u32>() + EVENT_HEADER_SIZE as usize;
         let payload = &item.payload()[body_offset..];
 
         // Body has one hit:
 
-        assert_eq!(payload.len(), size_of::<u16>() + size_of::<u64>() + size_of::<u32>());
+        assert_eq!(payload.len(), 2*size_of::<u16>() + size_of::<u32>() + size_of::<u64>() + size_of::<u32>());
+
+        let flags = u16::from_le_bytes(payload[0..2].try_into().unwrap());
+        assert_eq!(flags, 0);
 
-        let chan  = u16::from_le_bytes(payload[0..2].try_into().unwrap());
+        let chan  = u16::from_le_bytes(payload[2..4].try_into().unwrap());
         assert_eq!(chan, 1);
 
-        let ts = u64::from_le_bytes(payload[2..10].try_into().unwrap());  
+        let source = u32::from_le_bytes(payload[4..8].try_into().unwrap());
+        assert_eq!(source, 0);
+
+        let ts = u64::from_le_bytes(payload[8..16].try_into().unwrap());  
         assert_eq!(ts, 50);
 
-        let tot = u32::from_le_bytes(payload[10..14].try_into().unwrap());
+        let tot = u32::from_le_bytes(payload[16..20].try_into().unwrap());
         assert_eq!(tot, 666);
     }
     #[test]
     fn add_hit_5() {
         // a hit, frame then a hit outside dt writes
         // the hit and frame boundary.
-        let sink = Box::new(TestSink {item: None});
-        let p    = Box::into_raw(sink);
-        let rsink = unsafe {&*p};
-        let x = unsafe { Box::from_raw(p)};
-        
-        let mut glom = Glom::new(x, 1, 100);
-        glom.add_hit(true, 1, 50, 666);    // The first hit.
-        glom.add_frame_boundary(10);
-        glom.add_hit(true, 0, 151, 666);   // dt is 100.
+        let (mut glom, written) = test_glom();
+        glom.add_hit(true, ChannelId::new(0, 1), 50, 666).unwrap();    // The first hit.
+        glom.add_frame_boundary(10, 60, 0x12345678);
+        glom.add_hit(true, ChannelId::new(0, 0), 151, 666).unwrap();   // dt is 100.
 
         assert_eq!(glom.hits.len(), 1);    // Second hit still retained.
-        assert_eq!(glom.hits[0], (0u16, 151u64, 666u32));   // this is hit 0.
+        assert_eq!(glom.hits[0], (0u16, ChannelId::new(0, 0), 151u64, 666u32));   // this is hit 0.
 
         // Should have written:
 
-        assert!(rsink.item.is_some());
-        let item = rsink.item.as_ref().unwrap();
+        assert!(written.borrow().is_some());
+        let item = written.take().unwrap();
         assert_eq!(item.type_id(), PHYSICS_EVENT);
         assert!(item.has_body_header());        // THere is a body header and...
         let bh = item.get_bodyheader().unwrap();
@@ -365,54 +1196,59 @@ mod glom_tests {
         assert_eq!(bh.source_id, 1);
         assert_eq!(bh.barrier_type, 0); 
 
-        let body_offset = size_of::<u64>() + 2*size_of::<u32>();
+        let body_offset = size_of::<u64>() + 2*size_of::<u32>() + EVENT_HEADER_SIZE as usize;
         let payload = &item.payload()[body_offset..];
 
-        // size of the payload is 2 hits:
+        // size of the payload is a hit and a frame boundary:
 
-        let hit_size = size_of::<u16>() + size_of::<u64>() + size_of::<u32>();
-        assert_eq!(payload.len(), hit_size*2);
+        let hit_size = 2*size_of::<u16>() + size_of::<u32>() + size_of::<u64>() + size_of::<u32>();
+        let frame_size = hit_size + size_of::<u64>();
+        assert_eq!(payload.len(), hit_size + frame_size);
 
         
-        let chan  = u16::from_le_bytes(payload[0..2].try_into().unwrap());
+        let chan  = u16::from_le_bytes(payload[2..4].try_into().unwrap());
         assert_eq!(chan, 1);
 
-        let ts = u64::from_le_bytes(payload[2..10].try_into().unwrap());  
+        let ts = u64::from_le_bytes(payload[8..16].try_into().unwrap());  
         assert_eq!(ts, 50);
 
-        let tot = u32::from_le_bytes(payload[10..14].try_into().unwrap());
+        let tot = u32::from_le_bytes(payload[16..20].try_into().unwrap());
         assert_eq!(tot, 666);
 
-        let chan  = u16::from_le_bytes(payload[hit_size..hit_size+2].try_into().unwrap());
+        let flags  = u16::from_le_bytes(payload[hit_size..hit_size+2].try_into().unwrap());
+        assert_eq!(flags, 0xffff);
+
+        let chan  = u16::from_le_bytes(payload[hit_size+2..hit_size+4].try_into().unwrap());
         assert_eq!(chan, 0xffff);
 
-        let ts = u64::from_le_bytes(payload[hit_size+2..hit_size+10].try_into().unwrap());  
-        assert_eq!(ts, 10);
+        let source = u32::from_le_bytes(payload[hit_size+4..hit_size+8].try_into().unwrap());
+        assert_eq!(source, 0xffffffff);
+
+        let fno = u64::from_le_bytes(payload[hit_size+8..hit_size+16].try_into().unwrap());  
+        assert_eq!(fno, 10);
 
-        let tot = u32::from_le_bytes(payload[hit_size+10..hit_size+14].try_into().unwrap());
-        assert_eq!(tot, 0xffffffff);
+        let ts = u64::from_le_bytes(payload[hit_size+16..hit_size+24].try_into().unwrap());  
+        assert_eq!(ts, 60);
+
+        let flags = u32::from_le_bytes(payload[hit_size+24..hit_size+28].try_into().unwrap());
+        assert_eq!(flags, 0x12345678);
     }
     #[test]
     fn add_hit_6() {
         // two hits inside dt followed by one out writes the first two.
         
-        let sink = Box::new(TestSink {item: None});
-        let p    = Box::into_raw(sink);
-        let rsink = unsafe {&*p};
-        let x = unsafe { Box::from_raw(p)};
-        
-        let mut glom = Glom::new(x, 1, 100);
-        glom.add_hit(true, 1, 50, 666);    // The first hit.
-        glom.add_hit(true, 0, 75, 666);    // second hit in time window.
-        glom.add_hit(true, 1, 151, 666);   // outside of window.
+        let (mut glom, written) = test_glom();
+        glom.add_hit(true, ChannelId::new(0, 1), 50, 666).unwrap();    // The first hit.
+        glom.add_hit(true, ChannelId::new(0, 0), 75, 666).unwrap();    // second hit in time window.
+        glom.add_hit(true, ChannelId::new(0, 1), 151, 666).unwrap();   // outside of window.
 
         assert_eq!(glom.hits.len(), 1);    // Second hit still retained.
-        assert_eq!(glom.hits[0], (1u16, 151u64, 666u32));   // this is hit 0.
+        assert_eq!(glom.hits[0], (0u16, ChannelId::new(0, 1), 151u64, 666u32));   // this is hit 0.
 
         // Should have written:
 
-        assert!(rsink.item.is_some());
-        let item = rsink.item.as_ref().unwrap();
+        assert!(written.borrow().is_some());
+        let item = written.take().unwrap();
         assert_eq!(item.type_id(), PHYSICS_EVENT);
         assert!(item.has_body_header());        // THere is a body header and...
         let bh = item.get_bodyheader().unwrap();
@@ -420,33 +1256,871 @@ mod glom_tests {
         assert_eq!(bh.source_id, 1);
         assert_eq!(bh.barrier_type, 0); 
 
-        let body_offset = size_of::<u64>() + 2*size_of::<u32>();
+        let body_offset = size_of::<u64>() + 2*size_of::<u32>() + EVENT_HEADER_SIZE as usize;
         let payload = &item.payload()[body_offset..];
 
         // size of the payload is 2 hits:
 
-        let hit_size = size_of::<u16>() + size_of::<u64>() + size_of::<u32>();
+        let hit_size = 2*size_of::<u16>() + size_of::<u32>() + size_of::<u64>() + size_of::<u32>();
         assert_eq!(payload.len(), hit_size*2);
 
-        let chan  = u16::from_le_bytes(payload[0..2].try_into().unwrap());
+        let chan  = u16::from_le_bytes(payload[2..4].try_into().unwrap());
         assert_eq!(chan, 1);
 
-        let ts = u64::from_le_bytes(payload[2..10].try_into().unwrap());  
+        let ts = u64::from_le_bytes(payload[8..16].try_into().unwrap());  
         assert_eq!(ts, 50);
 
-        let tot = u32::from_le_bytes(payload[10..14].try_into().unwrap());
+        let tot = u32::from_le_bytes(payload[16..20].try_into().unwrap());
         assert_eq!(tot, 666);
 
-        let chan  = u16::from_le_bytes(payload[hit_size..hit_size+2].try_into().unwrap());
+        let chan  = u16::from_le_bytes(payload[hit_size+2..hit_size+4].try_into().unwrap());
         assert_eq!(chan, 0);
 
-        let ts = u64::from_le_bytes(payload[hit_size+2..hit_size+10].try_into().unwrap());  
+        let ts = u64::from_le_bytes(payload[hit_size+8..hit_size+16].try_into().unwrap());  
         assert_eq!(ts, 75);
 
-        let tot = u32::from_le_bytes(payload[hit_size+10..hit_size+14].try_into().unwrap());
+        let tot = u32::from_le_bytes(payload[hit_size+16..hit_size+20].try_into().unwrap());
         assert_eq!(tot, 666);
 
     }
+    #[test]
+    fn out_of_order_1() {
+        // With the Error policy an out of order hit is an error and is not added.
+
+        let sink = TestSink {item: Written::default()};
+        let mut glom = Glom::new(Box::new(sink), 1, 100);
+        assert_eq!(glom.policy, OutOfOrderPolicy::Drop);              // The default.
+        glom.set_out_of_order_policy(OutOfOrderPolicy::Error);
+        glom.add_hit(true, ChannelId::new(0, 1), 50, 666).unwrap();
+        assert_eq!(
+            glom.add_hit(true, ChannelId::new(0, 0), 10, 666),
+            Err(GlomError::OutOfOrder { time: 10, t0: 50 })
+        );
+        assert_eq!(glom.hits.len(), 1);
+    }
+    #[test]
+    fn out_of_order_2() {
+        // Drop policy discards and counts the hit:
+
+        let sink = TestSink {item: Written::default()};
+        let mut glom = Glom::new(Box::new(sink), 1, 100);
+        glom.set_out_of_order_policy(OutOfOrderPolicy::Drop);
+        glom.add_hit(true, ChannelId::new(0, 1), 50, 666).unwrap();
+        glom.add_hit(true, ChannelId::new(0, 0), 10, 666).unwrap();
+        assert_eq!(glom.hits.len(), 1);
+        assert_eq!(glom.statistics().dropped_hits, 1);
+    }
+    #[test]
+    fn out_of_order_3() {
+        // Include policy puts the hit in the current event:
+
+        let sink = TestSink {item: Written::default()};
+        let mut glom = Glom::new(Box::new(sink), 1, 100);
+        glom.set_out_of_order_policy(OutOfOrderPolicy::Include);
+        glom.add_hit(true, ChannelId::new(0, 1), 50, 666).unwrap();
+        glom.add_hit(true, ChannelId::new(0, 0), 10, 666).unwrap();
+        assert_eq!(glom.hits.len(), 2);
+        assert_eq!(glom.hits[1], (0u16, ChannelId::new(0, 0), 10u64, 666u32));
+        assert_eq!(glom.t0, Some(50));
+        assert_eq!(glom.statistics().dropped_hits, 0);
+    }
+    #[test]
+    fn out_of_order_4() {
+        // After a flush, hits earlier than the last event are still out of order.
+
+        let sink = TestSink {item: Written::default()};
+        let mut glom = Glom::new(Box::new(sink), 1, 100);
+        glom.set_out_of_order_policy(OutOfOrderPolicy::Error);
+        glom.add_hit(true, ChannelId::new(0, 1), 50, 666).unwrap();
+        glom.flush().unwrap();
+        assert_eq!(
+            glom.add_hit(true, ChannelId::new(0, 0), 10, 666),
+            Err(GlomError::OutOfOrder { time: 10, t0: 50 })
+        );
+        assert!(glom.hits.is_empty());
+    }
+
+    #[test]
+    fn sliding_1() {
+        // Each hit extends the window in sliding mode:
+
+        let sink = TestSink {item: Written::default()};
+        let mut glom = Glom::new(Box::new(sink), 1, 100);
+        glom.set_mode(CoincidenceMode::Sliding);
+        glom.add_hit(true, ChannelId::new(0, 1), 0, 666).unwrap();
+        glom.add_hit(true, ChannelId::new(0, 0), 80, 666).unwrap();
+        glom.add_hit(true, ChannelId::new(0, 1), 160, 666).unwrap();   // Would be out of a fixed window.
+
+        assert_eq!(glom.hits.len(), 3);
+        assert_eq!(glom.t0, Some(0));
+    }
+    #[test]
+    fn sliding_2() {
+        // A gap longer than dt closes the event:
+
+        let (mut glom, written) = test_glom();
+        glom.set_mode(CoincidenceMode::Sliding);
+        glom.add_hit(true, ChannelId::new(0, 1), 0, 666).unwrap();
+        glom.add_hit(true, ChannelId::new(0, 0), 80, 666).unwrap();
+        glom.add_hit(true, ChannelId::new(0, 1), 181, 666).unwrap();
+
+        assert_eq!(glom.hits, vec![(0u16, ChannelId::new(0, 1), 181u64, 666u32)]);
+        let item = written.take().unwrap();
+        assert_eq!(item.get_bodyheader().unwrap().timestamp, 0);
+        let hit_size = 2*size_of::<u16>() + size_of::<u32>() + size_of::<u64>() + size_of::<u32>();
+        let body_offset = size_of::<u64>() + 2*size_of::<u32>() + EVENT_HEADER_SIZE as usize;
+        assert_eq!(item.payload().len() - body_offset, 2*hit_size);
+    }
+    #[test]
+    fn trigger_1() {
+        // Non trigger hits don't open an event:
+
+        let sink = TestSink {item: Written::default()};
+        let mut glom = Glom::new(Box::new(sink), 1, 100);
+        glom.set_mode(CoincidenceMode::Trigger { channels: vec![ChannelId::new(0, 5)], pre: 20, post: 50 });
+        glom.add_hit(true, ChannelId::new(0, 1), 0, 666).unwrap();
+        glom.add_hit(true, ChannelId::new(0, 2), 10, 666).unwrap();
+
+        assert!(glom.t0.is_none());
+        assert!(glom.hits.is_empty());
+        assert_eq!(glom.pending.len(), 2);
+    }
+    #[test]
+    fn trigger_2() {
+        // A trigger hit gets the pending hits in the pre window
+        // the others are counted as untriggered.
+
+        let sink = TestSink {item: Written::default()};
+        let mut glom = Glom::new(Box::new(sink), 1, 100);
+        glom.set_mode(CoincidenceMode::Trigger { channels: vec![ChannelId::new(0, 5)], pre: 20, post: 50 });
+        glom.add_hit(true, ChannelId::new(0, 1), 0, 666).unwrap();
+        glom.add_hit(true, ChannelId::new(0, 2), 90, 666).unwrap();
+        glom.add_hit(true, ChannelId::new(0, 5), 100, 666).unwrap();
+
+        assert_eq!(glom.t0, Some(100));
+        assert_eq!(glom.hits, vec![(0u16, ChannelId::new(0, 2), 90u64, 666u32), (0u16, ChannelId::new(0, 5), 100u64, 666u32)]);
+        assert!(glom.pending.is_empty());
+        assert_eq!(glom.statistics().untriggered_hits, 1);
+    }
+    #[test]
+    fn trigger_3() {
+        // Hits in the post window are in the event, a later one closes it:
+
+        let (mut glom, written) = test_glom();
+        glom.set_mode(CoincidenceMode::Trigger { channels: vec![ChannelId::new(0, 5)], pre: 20, post: 50 });
+        glom.add_hit(true, ChannelId::new(0, 5), 100, 666).unwrap();
+        glom.add_hit(true, ChannelId::new(0, 1), 150, 666).unwrap();
+        assert!(written.borrow().is_none());
+        glom.add_hit(true, ChannelId::new(0, 1), 151, 666).unwrap();     // Closes the event, goes pending.
+
+        assert!(glom.t0.is_none());
+        assert!(glom.hits.is_empty());
+        assert_eq!(glom.pending, vec![(0u16, ChannelId::new(0, 1), 151u64, 666u32)]);
+        let item = written.take().unwrap();
+        assert_eq!(item.get_bodyheader().unwrap().timestamp, 100);
+    }
+    #[test]
+    fn trigger_4() {
+        // Frame boundaries wait with pending hits and come along with them:
+
+        let sink = TestSink {item: Written::default()};
+        let mut glom = Glom::new(Box::new(sink), 1, 100);
+        glom.set_mode(CoincidenceMode::Trigger { channels: vec![ChannelId::new(0, 5)], pre: 20, post: 50 });
+        glom.add_hit(true, ChannelId::new(0, 1), 0, 666).unwrap();
+        glom.add_frame_boundary(12, 0, 0);
+        glom.add_hit(true, ChannelId::new(0, 2), 90, 666).unwrap();
+        glom.add_hit(true, ChannelId::new(0, 5), 100, 666).unwrap();
+
+        assert_eq!(glom.hits, vec![
+            (FRAME_BOUNDARY, ChannelId::FRAME, 12u64, 0xffffffffu32), (0u16, ChannelId::new(0, 2), 90u64, 666u32), (0u16, ChannelId::new(0, 5), 100u64, 666u32)
+        ]);
+    }
+    #[test]
+    fn filter_1() {
+        // The default filter accepts everything, even empty events.
+
+        let f = EventFilter::new();
+        assert!(f.accepts(&[]));
+        assert!(f.accepts(&[(0, ChannelId::new(0, 1), 0, 666)]));
+    }
+    #[test]
+    fn filter_2() {
+        // Multiplicity limits -- frame boundaries don't count:
+
+        let f = EventFilter { min_multiplicity: 2, max_multiplicity: Some(3), ..EventFilter::new() };
+        assert!(!f.accepts(&[(0, ChannelId::new(0, 1), 0, 666), (FRAME_BOUNDARY, ChannelId::FRAME, 10, 0xffffffff)]));
+        assert!(f.accepts(&[(0, ChannelId::new(0, 1), 0, 666), (TRAILING_EDGE, ChannelId::new(0, 1), 5, 666)]));
+        assert!(f.accepts(&[
+            (0, ChannelId::new(0, 1), 0, 666), (0, ChannelId::new(0, 2), 1, 666), (0, ChannelId::new(0, 3), 2, 666)
+        ]));
+        assert!(!f.accepts(&[
+            (0, ChannelId::new(0, 1), 0, 666), (0, ChannelId::new(0, 2), 1, 666),
+            (0, ChannelId::new(0, 3), 2, 666), (0, ChannelId::new(0, 4), 3, 666)
+        ]));
+    }
+    #[test]
+    fn filter_3() {
+        // Required channels, either edge satisfies:
+
+        let f = EventFilter {
+            required_channels: vec![ChannelId::new(ANY_SOURCE, 0), ChannelId::new(ANY_SOURCE, 1)],
+            ..EventFilter::new()
+        };
+        assert!(!f.accepts(&[(0, ChannelId::new(0, 1), 0, 666)]));
+        assert!(f.accepts(&[(0, ChannelId::new(0, 1), 0, 666), (TRAILING_EDGE, ChannelId::new(0, 0), 5, 666)]));
+    }
+    #[test]
+    fn filter_4() {
+        // Ignored channels don't count for multiplicity or requirements:
+
+        let three = ChannelId::new(ANY_SOURCE, 3);
+        let f = EventFilter {
+            min_multiplicity: 1, required_channels: vec![three], ignored_channels: vec![three],
+            ..EventFilter::new()
+        };
+        assert!(!f.accepts(&[(0, ChannelId::new(0, 3), 0, 666)]));
+        let f = EventFilter { min_multiplicity: 2, ignored_channels: vec![three], ..EventFilter::new() };
+        assert!(!f.accepts(&[(0, ChannelId::new(0, 3), 0, 666), (0, ChannelId::new(0, 1), 1, 666)]));
+        assert!(f.accepts(&[
+            (0, ChannelId::new(0, 3), 0, 666), (0, ChannelId::new(0, 1), 1, 666), (0, ChannelId::new(0, 2), 2, 666)
+        ]));
+    }
+    #[test]
+    fn filter_6() {
+        // A source qualified channel only matches hits from that source:
+
+        let f = EventFilter { required_channels: vec![ChannelId::new(2, 7)], ..EventFilter::new() };
+        assert!(!f.accepts(&[(0, ChannelId::new(1, 7), 0, 666)]));
+        assert!(f.accepts(&[(0, ChannelId::new(2, 7), 0, 666)]));
+    }
+    #[test]
+    fn filter_5() {
+        // Rejected events are counted, not written:
+
+        let (mut glom, written) = test_glom();
+        glom.set_filter(EventFilter { min_multiplicity: 2, ..EventFilter::new() });
+        glom.add_hit(true, ChannelId::new(0, 1), 0, 666).unwrap();
+        glom.add_hit(true, ChannelId::new(0, 1), 500, 666).unwrap();     // closes a 1 hit event.
+
+        assert!(written.borrow().is_none());
+        assert_eq!(glom.hits.len(), 1);
+        glom.add_hit(true, ChannelId::new(0, 2), 550, 666).unwrap();
+        glom.flush().unwrap();
+        assert!(written.borrow().is_some());
+
+        let stats = glom.statistics();
+        assert_eq!(stats.events_rejected, 1);
+        assert_eq!(stats.events_written, 1);
+    }
+    #[test]
+    fn paired_1() {
+        // Pairs and unpaired edges are written as pair records:
+
+        let (mut glom, written) = test_glom();
+        glom.add_paired(PairedHit::Pair { channel: ChannelId::new(0, 2), leading: 10, trailing: 30 }).unwrap();
+        glom.add_paired(PairedHit::UnpairedLeading { channel: ChannelId::new(0, 3), time: 15 }).unwrap();
+        glom.add_paired(PairedHit::UnpairedTrailing { channel: ChannelId::new(1, 4), time: 20 }).unwrap();
+        assert_eq!(glom.hits, vec![
+            (PAIR_RECORD, ChannelId::new(0, 2), 10u64, 20u32),
+            (PAIR_RECORD | NO_TRAILING, ChannelId::new(0, 3), 15, 0),
+            (PAIR_RECORD | NO_LEADING, ChannelId::new(1, 4), 20, 0),
+        ]);
+        glom.flush().unwrap();
+
+        let item = written.take().unwrap();
+        let body_offset = size_of::<u64>() + 2*size_of::<u32>() + EVENT_HEADER_SIZE as usize;
+        let payload = &item.payload()[body_offset..];
+        let pair_size = 2*size_of::<u16>() + size_of::<u32>() + 2*size_of::<u64>() + size_of::<u32>();
+        assert_eq!(payload.len(), 3*pair_size);
+
+        let expected = [(PAIR_RECORD, 2u16, 0u32, 10u64, 30u64, 20u32),
+            (PAIR_RECORD | NO_TRAILING, 3, 0, 15, 0, 0),
+            (PAIR_RECORD | NO_LEADING, 4, 1, 0, 20, 0)];
+        for (i, e) in expected.iter().enumerate() {
+            let r = &payload[i*pair_size..];
+            assert_eq!(u16::from_le_bytes(r[0..2].try_into().unwrap()), e.0);
+            assert_eq!(u16::from_le_bytes(r[2..4].try_into().unwrap()), e.1);
+            assert_eq!(u32::from_le_bytes(r[4..8].try_into().unwrap()), e.2);
+            assert_eq!(u64::from_le_bytes(r[8..16].try_into().unwrap()), e.3);
+            assert_eq!(u64::from_le_bytes(r[16..24].try_into().unwrap()), e.4);
+            assert_eq!(u32::from_le_bytes(r[24..28].try_into().unwrap()), e.5);
+        }
+    }
+    #[test]
+    fn paired_2() {
+        // Pairs count once and by channel in the filter:
+
+        let f = EventFilter {
+            min_multiplicity: 2, required_channels: vec![ChannelId::new(0, 2)], ..EventFilter::new()
+        };
+        assert!(!f.accepts(&[(PAIR_RECORD, ChannelId::new(0, 2), 10, 20)]));
+        assert!(f.accepts(&[
+            (PAIR_RECORD, ChannelId::new(0, 2), 10, 20), (PAIR_RECORD | NO_LEADING, ChannelId::new(0, 3), 20, 0)
+        ]));
+    }
+    #[test]
+    fn wide_channel_1() {
+        // Channels above 255 and the hit's source are written intact:
+
+        let (mut glom, written) = test_glom();
+        glom.add_hit(false, ChannelId::new(7, 300), 50, 666).unwrap();
+        glom.flush().unwrap();
+
+        let item = written.take().unwrap();
+        let body_offset = size_of::<u64>() + 2*size_of::<u32>() + EVENT_HEADER_SIZE as usize;
+        let payload = &item.payload()[body_offset..];
+        assert_eq!(u16::from_le_bytes(payload[0..2].try_into().unwrap()), TRAILING_EDGE);
+        assert_eq!(u16::from_le_bytes(payload[2..4].try_into().unwrap()), 300);
+        assert_eq!(u32::from_le_bytes(payload[4..8].try_into().unwrap()), 7);
+    }
+    // Write an event with the hits in a layout and return its body (after the body header).
+
+    fn layout_event(
+        layout : HitLayout, hits : &[(u16, ChannelId, u64, u32)], frames : &[(u64, u64, u32)]
+    ) -> Vec<u8> {
+        let (mut glom, written) = test_glom();
+        glom.set_layout(layout);
+        glom.t0 = Some(100);
+        glom.hits = hits.to_vec();
+        for (fno, timestamp, flags) in frames {
+            glom.frame_info.insert(*fno, (*timestamp, *flags));
+        }
+        glom.flush().unwrap();
+        let item = written.take().unwrap();
+        item.payload()[size_of::<u64>() + 2*size_of::<u32>()..].to_vec()
+    }
+    #[test]
+    fn layout_1() {
+        // The event header has the layout id and header size:
+
+        for layout in [HitLayout::Legacy, HitLayout::Compact, HitLayout::Aligned] {
+            let body = layout_event(layout, &[], &[]);
+            assert_eq!(body.len(), EVENT_HEADER_SIZE as usize);
+            assert_eq!(u16::from_le_bytes(body[0..2].try_into().unwrap()), layout.id());
+            assert_eq!(u16::from_le_bytes(body[2..4].try_into().unwrap()), EVENT_HEADER_SIZE);
+            assert_eq!(event_header(&body), (0, 0, NO_FRAME, NO_FRAME));
+        }
+        assert_eq!(HitLayout::default(), HitLayout::Legacy);
+    }
+    #[test]
+    fn layout_2() {
+        // Compact times are signed and relative to the event timestamp,
+        // frame numbers are split into two 32 bit fields:
+
+        let body = layout_event(HitLayout::Compact, &[
+            (0, ChannelId::new(2, 1), 90, 666),
+            (FRAME_BOUNDARY, ChannelId::FRAME, 0x1_0000_0002, 0xffffffff),
+            (PAIR_RECORD, ChannelId::new(2, 3), 110, 20),
+            (PAIR_RECORD | NO_TRAILING, ChannelId::new(2, 4), 120, 0),
+        ], &[(0x1_0000_0002, 95, 7)]);
+        let hits = &body[EVENT_HEADER_SIZE as usize..];
+        assert_eq!(hits.len(), 16 + 24 + 20 + 20);
+
+        assert_eq!(u16::from_le_bytes(hits[2..4].try_into().unwrap()), 1);
+        assert_eq!(u32::from_le_bytes(hits[4..8].try_into().unwrap()), 2);
+        assert_eq!(i32::from_le_bytes(hits[8..12].try_into().unwrap()), -10);
+        assert_eq!(u32::from_le_bytes(hits[12..16].try_into().unwrap()), 666);
+
+        let frame = &hits[16..];
+        assert_eq!(u16::from_le_bytes(frame[0..2].try_into().unwrap()), FRAME_BOUNDARY);
+        assert_eq!(u32::from_le_bytes(frame[8..12].try_into().unwrap()), 2);
+        assert_eq!(u32::from_le_bytes(frame[12..16].try_into().unwrap()), 1);
+        assert_eq!(i32::from_le_bytes(frame[16..20].try_into().unwrap()), -5);
+        assert_eq!(u32::from_le_bytes(frame[20..24].try_into().unwrap()), 7);
+
+        let pair = &hits[40..];
+        assert_eq!(i32::from_le_bytes(pair[8..12].try_into().unwrap()), 10);
+        assert_eq!(i32::from_le_bytes(pair[12..16].try_into().unwrap()), 30);
+        assert_eq!(u32::from_le_bytes(pair[16..20].try_into().unwrap()), 20);
+
+        let unpaired = &hits[60..];
+        assert_eq!(i32::from_le_bytes(unpaired[8..12].try_into().unwrap()), 20);
+        assert_eq!(i32::from_le_bytes(unpaired[12..16].try_into().unwrap()), 0);
+    }
+    #[test]
+    fn layout_3() {
+        // Aligned records are padded to multiples of 8 bytes:
+
+        let body = layout_event(HitLayout::Aligned, &[
+            (0, ChannelId::new(2, 1), 150, 666),
+            (PAIR_RECORD, ChannelId::new(2, 3), 110, 20),
+            (FRAME_BOUNDARY, ChannelId::FRAME, 12, 0xffffffff),
+        ], &[(12, 140, 3)]);
+        let hits = &body[EVENT_HEADER_SIZE as usize..];
+        assert_eq!(hits.len(), 24 + 32 + 32);
+
+        assert_eq!(u64::from_le_bytes(hits[8..16].try_into().unwrap()), 150);
+        assert_eq!(u32::from_le_bytes(hits[16..20].try_into().unwrap()), 666);
+        assert_eq!(u32::from_le_bytes(hits[20..24].try_into().unwrap()), 0);
+
+        let pair = &hits[24..];
+        assert_eq!(u64::from_le_bytes(pair[8..16].try_into().unwrap()), 110);
+        assert_eq!(u64::from_le_bytes(pair[16..24].try_into().unwrap()), 130);
+        assert_eq!(u32::from_le_bytes(pair[24..28].try_into().unwrap()), 20);
+
+        let frame = &hits[56..];
+        assert_eq!(u64::from_le_bytes(frame[8..16].try_into().unwrap()), 12);
+        assert_eq!(u64::from_le_bytes(frame[16..24].try_into().unwrap()), 140);
+        assert_eq!(u32::from_le_bytes(frame[24..28].try_into().unwrap()), 3);
+        assert_eq!(u32::from_le_bytes(frame[28..32].try_into().unwrap()), 0);
+    }
+    #[test]
+    fn layout_4() {
+        // Compact times that don't fit are an error and the event is dropped:
+
+        let written = Written::default();
+        let mut glom = Glom::new(Box::new(TestSink {item: written.clone()}), 1, 1 << 40);
+        glom.set_layout(HitLayout::Compact);
+        glom.add_hit(true, ChannelId::new(0, 1), 0, 666).unwrap();
+        glom.add_hit(true, ChannelId::new(0, 1), 1 << 31, 666).unwrap();
+        assert_eq!(glom.flush(), Err(GlomError::RelativeTime { time: 1 << 31, t0: 0 }));
+        assert!(written.borrow().is_none());
+        assert!(glom.hits.is_empty());
+        assert_eq!(glom.statistics().events_written, 0);
+    }
+    #[test]
+    fn decode_1() {
+        // Events decode to what was put in them in every layout:
+
+        let hits = [
+            (0, ChannelId::new(2, 1), 90, 666),
+            (FRAME_BOUNDARY, ChannelId::FRAME, 0x1_0000_0002, 0xffffffff),
+            (TRAILING_EDGE, ChannelId::new(2, 2), 105, 5),
+            (PAIR_RECORD, ChannelId::new(2, 3), 110, 20),
+            (PAIR_RECORD | NO_TRAILING, ChannelId::new(2, 4), 120, 0),
+            (PAIR_RECORD | NO_LEADING, ChannelId::new(2, 5), 125, 0),
+        ];
+        let expected = vec![
+            EventRecord::Hit { trailing : false, channel : ChannelId::new(2, 1), time : 90, tot : 666 },
+            EventRecord::FrameBoundary { frame : 0x1_0000_0002, timestamp : 95, flags : 7 },
+            EventRecord::Hit { trailing : true, channel : ChannelId::new(2, 2), time : 105, tot : 5 },
+            EventRecord::Pair { channel : ChannelId::new(2, 3), leading : Some(110), trailing : Some(130), width : 20 },
+            EventRecord::Pair { channel : ChannelId::new(2, 4), leading : Some(120), trailing : None, width : 0 },
+            EventRecord::Pair { channel : ChannelId::new(2, 5), leading : None, trailing : Some(125), width : 0 },
+        ];
+        for layout in [HitLayout::Legacy, HitLayout::Compact, HitLayout::Aligned] {
+            let body = layout_event(layout, &hits, &[(0x1_0000_0002, 95, 7)]);
+            let (header, records) = decode_event(&body, 100).unwrap();
+            assert_eq!(header, EventHeader {
+                layout, flags : EVENT_CROSSES_FRAME, hit_count : 5, first_frame : NO_FRAME, last_frame : 0x1_0000_0002
+            });
+            assert_eq!(records, expected);
+        }
+    }
+    #[test]
+    fn decode_2() {
+        // Malformed bodies are errors:
+
+        let body = layout_event(HitLayout::Legacy, &[(0, ChannelId::new(2, 1), 90, 666)], &[]);
+        assert!(decode_event(&body[..10], 100).is_err());
+        assert!(decode_event(&body[..body.len() - 1], 100).unwrap_err().contains("ends at byte 51"));
+        let mut unknown = body.clone();
+        unknown[0] = 9;
+        assert_eq!(decode_event(&unknown, 100).unwrap_err(), "Unknown hit layout id 9");
+        assert_eq!(HitLayout::from_id(HitLayout::Aligned.id()), Some(HitLayout::Aligned));
+    }
+    #[test]
+    fn decode_3() {
+        // Times are relative to the body header timestamp, which comes from the input
+        // and can be anything:
+
+        let hits = [(0, ChannelId::new(2, 1), 90, 666), (TRAILING_EDGE, ChannelId::new(2, 2), 105, 5)];
+        let body = layout_event(HitLayout::Compact, &hits, &[]);
+        let stamp = i64::MAX as u64;
+        let (_, records) = decode_event(&body, stamp).unwrap();
+        assert_eq!(records, vec![
+            EventRecord::Hit { trailing : false, channel : ChannelId::new(2, 1), time : stamp - 10, tot : 666 },
+            EventRecord::Hit { trailing : true, channel : ChannelId::new(2, 2), time : stamp + 5, tot : 5 },
+        ]);
+        let (_, records) = decode_event(&body, u64::MAX).unwrap();
+        assert!(matches!(records[1], EventRecord::Hit { time : 4, .. }));       // Wraps.
+    }
+    // Decode the flags, hit count, first and last frame of an event header:
+
+    fn event_header(body : &[u8]) -> (u32, u32, u64, u64) {
+        (
+            u32::from_le_bytes(body[4..8].try_into().unwrap()),
+            u32::from_le_bytes(body[8..12].try_into().unwrap()),
+            u64::from_le_bytes(body[16..24].try_into().unwrap()),
+            u64::from_le_bytes(body[24..32].try_into().unwrap()),
+        )
+    }
+    // Make a glom with a test sink, returning the glom and the sink:
+
+    fn test_glom() -> (Glom, Written) {
+        let written = Written::default();
+        let sink = TestSink {item: written.clone()};
+        (Glom::new(Box::new(sink), 1, 100), written)
+    }
+    fn written_body(written : &Written) -> Vec<u8> {
+        written.borrow().as_ref().unwrap().payload()[size_of::<u64>() + 2*size_of::<u32>()..].to_vec()
+    }
+    #[test]
+    fn header_1() {
+        // Hits in one frame - frame boundaries don't count as hits:
+
+        let (mut glom, written) = test_glom();
+        glom.add_frame_boundary(7, 0, 0);
+        glom.add_hit(true, ChannelId::new(0, 1), 10, 666).unwrap();
+        glom.add_hit(false, ChannelId::new(0, 1), 20, 666).unwrap();
+        glom.flush().unwrap();
+        assert_eq!(event_header(&written_body(&written)), (0, 2, 7, 7));
+    }
+    #[test]
+    fn header_2() {
+        // An event that crosses a frame boundary:
+
+        let (mut glom, written) = test_glom();
+        glom.add_frame_boundary(7, 0, 0);
+        glom.add_hit(true, ChannelId::new(0, 1), 10, 666).unwrap();
+        glom.add_frame_boundary(8, 0, 0);
+        glom.add_hit(true, ChannelId::new(0, 2), 20, 666).unwrap();
+        glom.flush().unwrap();
+        assert_eq!(event_header(&written_body(&written)), (EVENT_CROSSES_FRAME, 2, 7, 8));
+
+        // The next event starts in frame 8:
+
+        glom.add_hit(true, ChannelId::new(0, 2), 200, 666).unwrap();
+        glom.add_frame_boundary(9, 0, 0);          // after the last hit - doesn't cross.
+        glom.flush().unwrap();
+        assert_eq!(event_header(&written_body(&written)), (0, 1, 8, 8));
+    }
+    #[test]
+    fn header_3() {
+        // Hits before any frame boundary have unknown frames:
+
+        let (mut glom, written) = test_glom();
+        glom.add_hit(true, ChannelId::new(0, 1), 10, 666).unwrap();
+        glom.add_frame_boundary(1, 0, 0);
+        glom.add_hit(true, ChannelId::new(0, 1), 20, 666).unwrap();
+        glom.flush().unwrap();
+        assert_eq!(event_header(&written_body(&written)), (EVENT_CROSSES_FRAME, 2, NO_FRAME, 1));
+    }
+    #[test]
+    fn header_4() {
+        // Included out of order hits are flagged:
+
+        let (mut glom, written) = test_glom();
+        glom.set_out_of_order_policy(OutOfOrderPolicy::Include);
+        glom.add_hit(true, ChannelId::new(0, 1), 50, 666).unwrap();
+        glom.add_hit(true, ChannelId::new(0, 1), 40, 666).unwrap();
+        glom.flush().unwrap();
+        assert_eq!(event_header(&written_body(&written)).0, EVENT_OUT_OF_ORDER);
+
+        glom.add_hit(true, ChannelId::new(0, 1), 60, 666).unwrap();
+        glom.flush().unwrap();
+        assert_eq!(event_header(&written_body(&written)).0, 0);
+    }
+    #[test]
+    fn header_5() {
+        // Trigger mode: the frame of pruned pending hits is remembered:
+
+        let (mut glom, written) = test_glom();
+        glom.set_mode(CoincidenceMode::Trigger { channels: vec![ChannelId::new(0, 5)], pre: 20, post: 50 });
+        glom.add_frame_boundary(3, 0, 0);
+        glom.add_hit(true, ChannelId::new(0, 1), 0, 666).unwrap();
+        glom.add_frame_boundary(4, 0, 0);
+        glom.add_hit(true, ChannelId::new(0, 1), 10, 666).unwrap();
+        glom.add_hit(true, ChannelId::new(0, 2), 90, 666).unwrap();
+        glom.add_hit(true, ChannelId::new(0, 5), 100, 666).unwrap();
+        glom.flush().unwrap();
+        assert_eq!(event_header(&written_body(&written)), (0, 2, 4, 4));
+    }
+    #[test]
+    fn frame_markers_1() {
+        // With crossing frames only, events in one frame have no frame boundaries:
+
+        let (mut glom, written) = test_glom();
+        glom.set_crossing_frames_only(true);
+        glom.add_frame_boundary(7, 0, 0);
+        glom.add_hit(true, ChannelId::new(0, 1), 10, 666).unwrap();
+        glom.add_hit(true, ChannelId::new(0, 2), 20, 666).unwrap();
+        glom.add_frame_boundary(8, 30, 0);       // After the hits.
+        glom.flush().unwrap();
+        let body = written_body(&written);
+        assert_eq!(event_header(&body), (0, 2, 7, 7));
+        assert_eq!(body.len(), EVENT_HEADER_SIZE as usize + 2*20);
+
+        // Crossing events keep them:
+
+        glom.add_hit(true, ChannelId::new(0, 1), 40, 666).unwrap();
+        glom.add_frame_boundary(9, 50, 0);
+        glom.add_hit(true, ChannelId::new(0, 2), 60, 666).unwrap();
+        glom.flush().unwrap();
+        let body = written_body(&written);
+        assert_eq!(event_header(&body), (EVENT_CROSSES_FRAME, 2, 8, 9));
+        assert_eq!(body.len(), EVENT_HEADER_SIZE as usize + 2*20 + 28);
+    }
+    #[test]
+    fn frame_markers_2() {
+        // Frame timestamps and flags are only kept while their boundaries are:
+
+        let (mut glom, _written) = test_glom();
+        for fno in 0..10 {
+            glom.add_frame_boundary(fno, fno*1000, fno as u32);
+            glom.add_hit(true, ChannelId::new(0, 1), fno*1000 + 10, 666).unwrap();
+        }
+        assert!(glom.frame_info.is_empty());         // Frame 9 went out with the last event.
+        glom.add_frame_boundary(10, 10000, 10);
+        assert_eq!(glom.frame_info.len(), 1);
+        assert_eq!(glom.frame_info.get(&10), Some(&(10000, 10)));
+        glom.flush().unwrap();
+        assert!(glom.frame_info.is_empty());
+    }
+    #[test]
+    fn frame_markers_3() {
+        // Paired frame boundaries carry their timestamp and flags:
+
+        let (mut glom, written) = test_glom();
+        glom.add_paired(PairedHit::FrameBoundary { frame: 3, timestamp: 5, flags: 0x20001 }).unwrap();
+        glom.add_hit(true, ChannelId::new(0, 1), 10, 666).unwrap();
+        glom.flush().unwrap();
+        let body = written_body(&written);
+        let frame = &body[EVENT_HEADER_SIZE as usize..];
+        assert_eq!(u64::from_le_bytes(frame[8..16].try_into().unwrap()), 3);
+        assert_eq!(u64::from_le_bytes(frame[16..24].try_into().unwrap()), 5);
+        assert_eq!(u32::from_le_bytes(frame[24..28].try_into().unwrap()), 0x20001);
+    }
+    // A sink that refuses to write:
+
+    struct FailingSink {}
+    impl DataSink for FailingSink {
+        fn open(&mut self, _uri: &str) -> Result<(), String> {Ok(())}
+        fn write(&mut self, _item : &RingItem) ->Result<(), String> {
+            Err(String::from("sink failed"))
+        }
+        fn close(&mut self) {}
+    }
+
+    #[test]
+    fn sink_error_1() {
+        // Failed writes are reported and the event is discarded:
+
+        let mut glom = Glom::new(Box::new(FailingSink {}), 1, 100);
+        glom.add_hit(true, ChannelId::new(0, 1), 50, 666).unwrap();
+        assert_eq!(glom.flush(), Err(GlomError::SinkWrite(String::from("sink failed"))));
+        assert!(glom.t0.is_none());
+        assert!(glom.hits.is_empty());
+    }
+    #[test]
+    fn sink_error_2() {
+        // A hit that closes an event reports the failed write:
+
+        let mut glom = Glom::new(Box::new(FailingSink {}), 1, 100);
+        glom.add_hit(true, ChannelId::new(0, 1), 50, 666).unwrap();
+        assert!(glom.add_hit(true, ChannelId::new(0, 1), 500, 666).is_err());
+        assert_eq!(glom.hits, vec![(0u16, ChannelId::new(0, 1), 500u64, 666u32)]);
+    }
+    #[test]
+    fn sink_error_3() {
+        // Pass through reports failed writes:
+
+        let mut glom = Glom::new(Box::new(FailingSink {}), 1, 100);
+        let item = RingItem::new(PHYSICS_EVENT);
+        assert!(glom.write_item(&item).is_err());
+    }
+
+    // A sink that counts the items written and flushes.  The counts are shared
+    // so they can be seen after the Glom is dropped.
+
+    struct CountingSink {
+        items   : Rc<Cell<usize>>,
+        flushes : Rc<Cell<usize>>,
+    }
+    impl DataSink for CountingSink {
+        fn open(&mut self, _uri: &str) -> Result<(), String> {Ok(())}
+        fn write(&mut self, _item : &RingItem) ->Result<(), String> {
+            self.items.set(self.items.get() + 1);
+            Ok(())
+        }
+        fn close(&mut self) {}
+        fn flush(&mut self) {
+            self.flushes.set(self.flushes.get() + 1);
+        }
+    }
+    fn counting_glom() -> (Glom, Rc<Cell<usize>>, Rc<Cell<usize>>) {
+        let items   = Rc::new(Cell::new(0));
+        let flushes = Rc::new(Cell::new(0));
+        let sink = CountingSink { items: items.clone(), flushes: flushes.clone() };
+        (Glom::new(Box::new(sink), 1, 100), items, flushes)
+    }
+
+    #[test]
+    fn flush_policy_1() {
+        // The default flushes every item:
+
+        let (mut glom, items, flushes) = counting_glom();
+        for _ in 0..3 {
+            glom.write_item(&RingItem::new(PHYSICS_EVENT)).unwrap();
+        }
+        assert_eq!(items.get(), 3);
+        assert_eq!(flushes.get(), 3);
+    }
+    #[test]
+    fn flush_policy_2() {
+        // Item count batches, events count too:
+
+        let (mut glom, items, flushes) = counting_glom();
+        glom.set_flush_policy(FlushPolicy::Items(3));
+        glom.write_item(&RingItem::new(PHYSICS_EVENT)).unwrap();
+        glom.add_hit(true, ChannelId::new(0, 1), 50, 666).unwrap();
+        glom.flush().unwrap();
+        assert_eq!((items.get(), flushes.get()), (2, 0));
+        glom.write_item(&RingItem::new(PHYSICS_EVENT)).unwrap();
+        assert_eq!((items.get(), flushes.get()), (3, 1));
+        glom.write_item(&RingItem::new(PHYSICS_EVENT)).unwrap();
+        assert_eq!((items.get(), flushes.get()), (4, 1));
+    }
+    #[test]
+    fn flush_policy_3() {
+        // Byte count batches:
+
+        let (mut glom, _items, flushes) = counting_glom();
+        let size = RingItem::new(PHYSICS_EVENT).size() as u64;
+        glom.set_flush_policy(FlushPolicy::Bytes(2 * size + 1));
+        glom.write_item(&RingItem::new(PHYSICS_EVENT)).unwrap();
+        glom.write_item(&RingItem::new(PHYSICS_EVENT)).unwrap();
+        assert_eq!(flushes.get(), 0);
+        glom.write_item(&RingItem::new(PHYSICS_EVENT)).unwrap();
+        assert_eq!(flushes.get(), 1);
+    }
+    #[test]
+    fn flush_policy_4() {
+        // Interval; the first item after the interval flushes:
+
+        let (mut glom, _items, flushes) = counting_glom();
+        glom.set_flush_policy(FlushPolicy::Interval(Duration::from_secs(3600)));
+        glom.write_item(&RingItem::new(PHYSICS_EVENT)).unwrap();
+        assert_eq!(flushes.get(), 0);
+        glom.set_flush_policy(FlushPolicy::Interval(Duration::ZERO));
+        glom.write_item(&RingItem::new(PHYSICS_EVENT)).unwrap();
+        assert_eq!(flushes.get(), 1);
+    }
+    #[test]
+    fn flush_policy_5() {
+        // End run and dropping the glom always flush:
+
+        let (mut glom, _items, flushes) = counting_glom();
+        glom.set_flush_policy(FlushPolicy::Items(1000));
+        glom.write_item(&RingItem::new(PHYSICS_EVENT)).unwrap();
+        glom.write_item(&RingItem::new(END_RUN)).unwrap();
+        assert_eq!(flushes.get(), 1);
+        glom.write_item(&RingItem::new(PHYSICS_EVENT)).unwrap();
+        drop(glom);
+        assert_eq!(flushes.get(), 2);
+    }
+    #[test]
+    fn reset_1() {
+        // After a reset, earlier times are not out of order:
+
+        let (mut glom, items, _flushes) = counting_glom();
+        glom.set_out_of_order_policy(OutOfOrderPolicy::Error);
+        glom.add_frame_boundary(7, 0, 0);
+        glom.add_hit(true, ChannelId::new(0, 1), 5000, 1).unwrap();
+        glom.flush().unwrap();
+        glom.add_hit(true, ChannelId::new(0, 1), 6000, 1).unwrap();
+        assert!(glom.add_hit(true, ChannelId::new(0, 1), 10, 1).is_err());
+        glom.reset();
+        assert!(glom.t0.is_none());
+        assert!(glom.hits.is_empty());
+        assert!(glom.frame.is_none());
+        assert!(glom.frame_info.is_empty());
+        glom.add_hit(true, ChannelId::new(0, 1), 10, 1).unwrap();
+        glom.flush().unwrap();
+        assert_eq!(items.get(), 2);
+        assert_eq!(glom.statistics().events_written, 2);
+    }
+    #[test]
+    fn held_1() {
+        // Hits in the event being built are held; frame boundaries don't count:
+
+        let (mut glom, _items, _flushes) = counting_glom();
+        assert_eq!(glom.held_hits(), 0);
+        glom.add_frame_boundary(7, 0, 0);
+        glom.add_hit(true, ChannelId::new(0, 1), 5000, 1).unwrap();
+        glom.add_hit(true, ChannelId::new(0, 2), 5001, 1).unwrap();
+        assert_eq!(glom.held_hits(), 2);
+        glom.flush().unwrap();
+        assert_eq!(glom.held_hits(), 0);
+
+        // As are hits waiting for a trigger:
+
+        glom.set_mode(CoincidenceMode::Trigger { channels : vec![ChannelId::new(0, 9)], pre : 100, post : 100 });
+        glom.add_hit(true, ChannelId::new(0, 1), 6000, 1).unwrap();
+        assert_eq!(glom.held_hits(), 1);
+    }
+    #[test]
+    fn statistics_1() {
+        let end = GlomStatistics {
+            events_written : 10, events_rejected : 5, dropped_hits : 3, untriggered_hits : 1,
+            crossing_events : 2, multiplicity : vec![0, 7, 3],
+        };
+        let start = GlomStatistics {
+            events_written : 4, events_rejected : 5, dropped_hits : 1, untriggered_hits : 0,
+            crossing_events : 1, multiplicity : vec![0, 4],
+        };
+        assert_eq!(end - start, GlomStatistics {
+            events_written : 6, events_rejected : 0, dropped_hits : 2, untriggered_hits : 1,
+            crossing_events : 1, multiplicity : vec![0, 3, 3],
+        });
+    }
+    #[test]
+    fn statistics_2() {
+        // Crossing events and the multiplicity histogram (events written only):
+
+        let (mut glom, _written) = test_glom();
+        glom.add_frame_boundary(7, 0, 0);
+        glom.add_hit(true, ChannelId::new(0, 1), 10, 666).unwrap();
+        glom.add_frame_boundary(8, 0, 0);
+        glom.add_hit(true, ChannelId::new(0, 2), 20, 666).unwrap();
+        glom.flush().unwrap();
+        glom.add_hit(true, ChannelId::new(0, 2), 200, 666).unwrap();
+        glom.flush().unwrap();
+        let stats = glom.statistics();
+        assert_eq!(stats.crossing_events, 1);
+        assert_eq!(stats.multiplicity, vec![0, 1, 1]);
+
+        let mut filter = EventFilter::new();
+        filter.min_multiplicity = 2;
+        glom.set_filter(filter);
+        glom.add_hit(true, ChannelId::new(0, 2), 400, 666).unwrap();
+        glom.flush().unwrap();
+        assert_eq!(glom.statistics().multiplicity, vec![0, 1, 1]);
+        assert_eq!(glom.statistics().events_rejected, 1);
+    }
+    #[test]
+    fn flush_policy_6() {
+        assert_eq!("always".parse::<FlushPolicy>(), Ok(FlushPolicy::EveryItem));
+        assert_eq!("items:100".parse::<FlushPolicy>(), Ok(FlushPolicy::Items(100)));
+        assert_eq!("bytes:4096".parse::<FlushPolicy>(), Ok(FlushPolicy::Bytes(4096)));
+        assert_eq!("ms:250".parse::<FlushPolicy>(), Ok(FlushPolicy::Interval(Duration::from_millis(250))));
+        assert!("items".parse::<FlushPolicy>().is_err());
+        assert!("items:0".parse::<FlushPolicy>().is_err());
+        assert!("lines:10".parse::<FlushPolicy>().is_err());
+        assert_eq!("bytes:1048576".parse::<FlushPolicy>(), Ok(DEFAULT_BATCH_FLUSH));
+    }
+    #[test]
+    fn flush_policy_7() {
+        // Idle flushes only happen with an interval policy once it's up and there's
+        // something to flush:
+
+        let (mut glom, _items, flushes) = counting_glom();
+        glom.set_flush_policy(FlushPolicy::Items(1000));
+        glom.write_item(&RingItem::new(PHYSICS_EVENT)).unwrap();
+        glom.flush_if_due();
+        assert_eq!(flushes.get(), 0);
+
+        glom.set_flush_policy(FlushPolicy::Interval(Duration::from_secs(3600)));
+        glom.flush_if_due();
+        assert_eq!(flushes.get(), 0);
+
+        glom.set_flush_policy(FlushPolicy::Interval(Duration::ZERO));
+        assert_eq!(glom.flush_policy(), FlushPolicy::Interval(Duration::ZERO));
+        glom.flush_if_due();
+        assert_eq!(flushes.get(), 1);
+        glom.flush_if_due();                       // Nothing new written.
+        assert_eq!(flushes.get(), 1);
+    }
 }
 /// Merges hits into a fully time ordered stream.
 /// The output of this can be inserted into a Glom
@@ -456,6 +2130,7 @@ mod glom_tests {
 pub struct Orderer {
     hits : Vec<(bool, u16, u64, u32)>,  // Soup of hits.
 }
+#[allow(clippy::new_without_default)]
 impl Orderer {
     /// Create a new orderer.
     pub fn new() -> Orderer {
@@ -488,7 +2163,238 @@ impl Orderer {
         result
     }
 }
+
+/// What a Pairer produces.  Times are absolute hit times.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PairedHit {
+    /// A leading edge and the next trailing edge in the same channel.
+    Pair { channel : ChannelId, leading : u64, trailing : u64 },
+    /// A leading edge with no trailing edge within the maximum width.
+    UnpairedLeading { channel : ChannelId, time : u64 },
+    /// A trailing edge with no leading edge.
+    UnpairedTrailing { channel : ChannelId, time : u64 },
+    /// A frame boundary - the absolute frame number, the frame timestamp
+    /// and the frame flags (see Glom::add_frame_boundary).
+    FrameBoundary { frame : u64, timestamp : u64, flags : u32 },
+}
+
+/// Pairs leading and trailing edges.  Goes between an Orderer and a Glom.
+/// Ordered hits (and frame boundaries) are fed in and each channel's leading edge
+/// is matched with the next trailing edge in that channel if it is no more than
+/// max_width ticks later.
+/// 
+/// Since a pair is only complete when its trailing edge arrives, pairs are held
+/// until no open leading edge can precede them.  What pairs() returns is therefore
+/// ordered by leading edge time (trailing edge time for unpaired trailing edges).
+pub struct Pairer {
+    max_width : u32,
+    open      : BTreeMap<ChannelId, u64>, // Leading edge time waiting for a trailing edge by channel.
+    ready     : Vec<(u64, PairedHit)>,    // Sort key and completed output.
+}
+impl Pairer {
+    // Leading edges that can no longer be paired because it is now later than
+    // max_width after them become unpaired:
+    fn expire(&mut self, now : u64) {
+        let max_width = self.max_width as u64;
+        let expired : Vec<ChannelId> = self.open.iter()
+            .filter(|(_, l)| now.saturating_sub(**l) > max_width)
+            .map(|(c, _)| *c)
+            .collect();
+        for channel in expired {
+            let time = self.open.remove(&channel).expect("expired channel is open");
+            self.ready.push((time, PairedHit::UnpairedLeading { channel, time }));
+        }
+    }
+    /// Create a pairer.
+    /// 
+    /// ### Parameters:
+    /// * max_width - the largest trailing - leading edge time that makes a pair.
+    pub fn new(max_width : u32) -> Pairer {
+        Pairer {
+            max_width,
+            open  : BTreeMap::new(),
+            ready : Vec::new(),
+        }
+    }
+    /// Add a frame boundary.
+    /// 
+    /// ### Parameters:
+    /// * fno  - absolute frame number.
+    /// * time - timestamp of the start of the frame.  This orders the boundary
+    ///   with respect to the hits.
+    /// * flags - the frame flags (see Glom::add_frame_boundary).
+    pub fn add_frame_boundary(&mut self, fno : u64, time : u64, flags : u32) {
+        self.expire(time);
+        self.ready.push((time, PairedHit::FrameBoundary { frame: fno, timestamp: time, flags }));
+    }
+    /// Add a hit.  Hits must be added in time order (e.g. from Orderer::order).
+    /// 
+    /// ### Parameters
+    /// *  rising - true if this hit is a rising edge.
+    /// *  chan   - channel id of the hit.
+    /// *  time   - Time at which the hit happened.
+    /// *  tot    - Time over threshold (not used; the width is computed from the edges).
+    pub fn add_hit(&mut self, rising : bool, chan : ChannelId, time : u64, _tot : u32) {
+        self.expire(time);
+        if rising {
+            if let Some(prior) = self.open.insert(chan, time) {
+                self.ready.push((prior, PairedHit::UnpairedLeading { channel: chan, time: prior }));
+            }
+        } else if let Some(leading) = self.open.remove(&chan) {
+            self.ready.push((leading, PairedHit::Pair { channel: chan, leading, trailing: time }));
+        } else {
+            self.ready.push((time, PairedHit::UnpairedTrailing { channel: chan, time }));
+        }
+    }
+    /// Return the output that can't be preceded by anything still waiting for a
+    /// trailing edge.
+    pub fn pairs(&mut self) -> Vec<PairedHit> {
+        let limit = self.open.values().min().copied();
+        self.ready.sort_by_key(|r| r.0);        // Stable so frame boundaries stay ahead of hits.
+        let count = match limit {
+            Some(l) => self.ready.partition_point(|r| r.0 < l),
+            None => self.ready.len(),
+        };
+        self.ready.drain(..count).map(|r| r.1).collect()
+    }
+    /// Return everything, leading edges still waiting for trailing edges are returned
+    /// as unpaired.  Use this at the end of a run.
+    pub fn flush(&mut self) -> Vec<PairedHit> {
+        let open = std::mem::take(&mut self.open);
+        for (channel, time) in open {
+            self.ready.push((time, PairedHit::UnpairedLeading { channel, time }));
+        }
+        self.pairs()
+    }
+    /// The number of edges held: leading edges waiting for trailing edges and
+    /// output waiting for them.
+    pub fn held(&self) -> usize {
+        self.open.len() + self.ready.iter().filter(|r| !matches!(r.1, PairedHit::FrameBoundary { .. })).count()
+    }
+}
+#[cfg(test)]
+mod channel_id_tests {
+    use super::*;
+    #[test]
+    fn parse_1() {
+        // source:channel and bare channel specifications:
+
+        assert_eq!("3:17".parse::<ChannelId>(), Ok(ChannelId::new(3, 17)));
+        assert_eq!("300".parse::<ChannelId>(), Ok(ChannelId::new(ANY_SOURCE, 300)));
+        assert!("x:1".parse::<ChannelId>().is_err());
+        assert!("1:70000".parse::<ChannelId>().is_err());
+    }
+    #[test]
+    fn display_1() {
+        // Display is the inverse of the parse:
+
+        assert_eq!(ChannelId::new(3, 17).to_string(), "3:17");
+        assert_eq!(ChannelId::new(ANY_SOURCE, 300).to_string(), "300");
+    }
+    #[test]
+    fn matches_1() {
+        let spec = ChannelId::new(2, 5);
+        assert!(spec.matches(&ChannelId::new(2, 5)));
+        assert!(!spec.matches(&ChannelId::new(1, 5)));
+        assert!(!spec.matches(&ChannelId::new(2, 6)));
+
+        let any = ChannelId::new(ANY_SOURCE, 5);
+        assert!(any.matches(&ChannelId::new(1, 5)));
+        assert!(any.matches(&ChannelId::new(2, 5)));
+    }
+}
+#[cfg(test)]
+mod pairer_tests {
+    use super::*;
+    #[test]
+    fn new_1() {
+        let p = Pairer::new(100);
+        assert_eq!(p.max_width, 100);
+        assert!(p.open.is_empty());
+        assert!(p.ready.is_empty());
+    }
+    #[test]
+    fn pair_1() {
+        // A leading then trailing edge in a channel pair:
+
+        let mut p = Pairer::new(100);
+        p.add_hit(true, ChannelId::new(0, 1), 10, 0);
+        assert!(p.pairs().is_empty());       // waiting for trailing edge.
+        p.add_hit(false, ChannelId::new(0, 1), 60, 0);
+        assert_eq!(p.pairs(), vec![PairedHit::Pair { channel: ChannelId::new(0, 1), leading: 10, trailing: 60 }]);
+    }
+    #[test]
+    fn held_1() {
+        let mut p = Pairer::new(100);
+        p.add_frame_boundary(1, 0, 0);
+        p.add_hit(true, ChannelId::new(0, 1), 10, 0);
+        p.add_hit(true, ChannelId::new(0, 2), 20, 0);
+        p.add_hit(false, ChannelId::new(0, 2), 30, 0);
+        assert_eq!(p.held(), 2);         // 0:1 open and the 0:2 pair waiting behind it.
+        p.flush();
+        assert_eq!(p.held(), 0);
+    }
+    #[test]
+    fn pair_2() {
+        // Too wide a pulse is two unpaired edges:
+
+        let mut p = Pairer::new(100);
+        p.add_hit(true, ChannelId::new(0, 1), 10, 0);
+        p.add_hit(false, ChannelId::new(0, 1), 111, 0);
+        assert_eq!(p.pairs(), vec![
+            PairedHit::UnpairedLeading { channel: ChannelId::new(0, 1), time: 10 },
+            PairedHit::UnpairedTrailing { channel: ChannelId::new(0, 1), time: 111 },
+        ]);
+    }
+    #[test]
+    fn pair_3() {
+        // Two leading edges - the first is unpaired.
+
+        let mut p = Pairer::new(100);
+        p.add_hit(true, ChannelId::new(0, 1), 10, 0);
+        p.add_hit(true, ChannelId::new(0, 1), 20, 0);
+        p.add_hit(false, ChannelId::new(0, 1), 30, 0);
+        assert_eq!(p.pairs(), vec![
+            PairedHit::UnpairedLeading { channel: ChannelId::new(0, 1), time: 10 },
+            PairedHit::Pair { channel: ChannelId::new(0, 1), leading: 20, trailing: 30 },
+        ]);
+    }
+    #[test]
+    fn pair_4() {
+        // Output is held so it's ordered by leading edge:
+
+        let mut p = Pairer::new(100);
+        p.add_hit(true, ChannelId::new(0, 1), 10, 0);
+        p.add_hit(true, ChannelId::new(0, 2), 20, 0);
+        p.add_hit(false, ChannelId::new(0, 2), 30, 0);
+        assert!(p.pairs().is_empty());        // Channel 1 pair must come first.
+        p.add_hit(false, ChannelId::new(0, 1), 40, 0);
+        assert_eq!(p.pairs(), vec![
+            PairedHit::Pair { channel: ChannelId::new(0, 1), leading: 10, trailing: 40 },
+            PairedHit::Pair { channel: ChannelId::new(0, 2), leading: 20, trailing: 30 },
+        ]);
+    }
+    #[test]
+    fn pair_5() {
+        // Frame boundaries are ordered by their time and flush makes
+        // open leading edges unpaired:
+
+        let mut p = Pairer::new(100);
+        p.add_hit(true, ChannelId::new(0, 1), 10, 0);
+        p.add_frame_boundary(7, 50, 0x10001);
+        p.add_hit(true, ChannelId::new(0, 2), 60, 0);
+        assert!(p.pairs().is_empty());
+        assert_eq!(p.flush(), vec![
+            PairedHit::UnpairedLeading { channel: ChannelId::new(0, 1), time: 10 },
+            PairedHit::FrameBoundary { frame: 7, timestamp: 50, flags: 0x10001 },
+            PairedHit::UnpairedLeading { channel: ChannelId::new(0, 2), time: 60 },
+        ]);
+        assert!(p.open.is_empty());
+        assert!(p.ready.is_empty());
+    }
+}
 #[cfg(test)]
+#[allow(clippy::needless_range_loop, clippy::explicit_counter_loop)]
 mod orderer_tests {
     use super::*;
     use rand::{RngExt};
@@ -1,5 +1,11 @@
 
 // Re publishing other crates:
 
+pub mod calibration;                         // Per channel hit calibration.
+pub mod check;                               // Raw data integrity checks.
+pub mod dnl;                                 // HR TDC fine time DNL correction.
+pub mod framer;                              // Raw data to frame ring items.
 pub  mod glom;                               // Glom for defenestrator.
 pub mod mikumari_format;
+pub mod pipeline;                            // Defenestrator stages and threads.
+pub mod report;                              // Defenestrator run statistics.
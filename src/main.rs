@@ -3,17 +3,16 @@ use std::process::exit;
 
 use std::io::{stdin, BufReader, Read};
 use std::fs::File;
-use rust_ringitem_format::{RingItem, BodyHeader, ToRaw};
+use mikumarimaker::framer::Framer;
+use rust_ringitem_format::{BodyHeader, ToRaw};
 use rust_ringitem_format::state_change::{StateChange, StateChangeType};  // begin run/end run.
-use frib_datasource::{data_sink_factory, DataSink};
+use frib_datasource::data_sink_factory;
 
 use clap::{value_parser, Arg, ArgAction, Command, ArgMatches};
 use std::time;
 
 
-const HEART_BEAT_MICROSECONDS : f64 = 524.288; // Time between heart beats.
-const TDC_TICK_PS : f64 = 0.9765625;           // LSB value for tdc.
-
+#[allow(clippy::empty_line_after_doc_comments)]
 /// We're going to support the following optional uhm.. options.
 /// --title - a run title.
 /// --run   - a run number.
@@ -64,9 +63,10 @@ fn main() ->std::io::Result<()> {
 
     let mut data_source = mikumari_format::MikumariReader::new(source);
     
-    // Open the output ring item - or ring buffer.
+    // Open the output ring item - or ring buffer and frame the data into it.
 
-    let mut ring_file = data_sink_factory(&ring_name).expect("Unable to open data sink"); 
+    let ring_file = data_sink_factory(&ring_name).expect("Unable to open data sink"); 
+    let mut framer = Framer::new(ring_file);
 
     // Set up to encapsulate the run:
 
@@ -81,16 +81,20 @@ fn main() ->std::io::Result<()> {
         &b,
         run_num, 0, 1, &title, Some(sid)
     );
-    ring_file.write(&begin_run.to_raw()).expect("Failed to write begin run item to sink.");
+    framer.write(&begin_run.to_raw()).expect("Failed to write begin run item to sink.");
 
     // Mikumari data has a partial frame at the front. We _could_
-    // figure out how to timestamp it, but, instead, we'll just skip
+    // figure out how to timestamp it, but, instead, the framer just skips
     // that data as that seems to be standard.
 
-    let hb = skip_partial_frame(&mut data_source);
+    framer.read_from(&mut data_source).expect("Failed to write a ring item to data sink.");
+    if !framer.started() {
+        // We had an error before finding a heartbeat.
 
-    let hb_t0 = hb.frame();        // our t0 frame.
-    dump_data(&mut data_source, hb_t0, &mut ring_file);
+        eprintln!("Did not find the first heartbeat before eof or read error");
+        exit(-1);
+    }
+    framer.finish().expect("Failed to write ring item to data sink.");   // Flush the last frame out.
 
     // The end run item:
 
@@ -102,84 +106,10 @@ fn main() ->std::io::Result<()> {
         run_num, elapsed.as_secs() as u32,
         1, &title, Some(sid)
     );
-    ring_file.write(&end_run.to_raw()).expect("Failed to write end run item to sink");
-    ring_file.flush();     // Probably not needed but what the heck.
+    framer.write(&end_run.to_raw()).expect("Failed to write end run item to sink");
+    framer.finish().expect("Failed to flush the data sink");     // Probably not needed but what the heck.
     Ok(())
 }
-fn skip_partial_frame(src : &mut mikumari_format::MikumariReader) ->
-    mikumari_format::Delimeter1
-{
-    
-    while let Ok(data) = src.read() {
-        if let mikumari_format::MikumariDatum::Heartbeat0(d1) = data {
-            return d1;
-        }
-    }
-    // We had an error before finding a heartbeat.
-
-    eprintln!("Did not find the first heartbeat before eof or read error");
-    exit(-1);
-
-}
-// t0 - the frame # of t0.
-// We're going to try to make the times into absolutes as well.
-// Ring items we make:
-//   These consist of raw hit values.
-//   the timestamp comes from the relative frame_no, but the first
-//   u64 bit item is the absolute frame number.
-//
-fn dump_data(src : &mut mikumari_format::MikumariReader, t0 : u64, rf : &mut Box<dyn DataSink>) {
-    let mut frame_no = 0;                       // THe current frame number.
-    let mut absolute_frame = t0;
-
-    // start a ring item for the first frame:
-
-    let mut ring_item = RingItem::new_with_body_header(
-        mikumari_format::MIKUMARI_FRAME_ITEM_TYPE,
-        hb_frame_to_ts(frame_no) as u64,
-        0, 0
-     );
-     ring_item.add(absolute_frame);
-    while let Ok(data) = src.read() {
-        match data {
-            mikumari_format::MikumariDatum::LeadingEdge(le) => {
-                ring_item.add(le.get());  // ISsue #11 Already includes the TOT field.
-            },
-            mikumari_format::MikumariDatum::TrailingEdge(te) => {
-                ring_item.add(te.get());  // ISsue #11 Already includes the TOT field.
-            }
-            mikumari_format::MikumariDatum::Heartbeat0(_d) => {
-                // Heart beat means we write the item and 
-                // start a new one:
-                rf.write(&ring_item).expect("Failed to write a ring item to data sink.");
-            
-                frame_no += 1;                   // Next frame.
-                absolute_frame += 1;
-                // Start the new ring item:
-
-                ring_item = RingItem::new_with_body_header(
-                    mikumari_format::MIKUMARI_FRAME_ITEM_TYPE,
-                    hb_frame_to_ts(frame_no) as u64,
-                    0,0
-                );
-                ring_item.add(absolute_frame);
-            }
-            mikumari_format::MikumariDatum::Heartbeat1(_d) => (),
-            mikumari_format::MikumariDatum::Other(_d) => (),
-        }
-    }
-    // Flush the last ring item out:
-  
-    rf.write(&ring_item).expect("Failed to write ring item to data sink.");
-    
-}
-
-// Convert a frame number to a mikumari timestamp:
-
-fn hb_frame_to_ts(frame: u64) -> f64 {
-    let frame_t : f64 = frame as f64 * HEART_BEAT_MICROSECONDS; // frame_time in usec.
-    (frame_t * (1.0e6)) / TDC_TICK_PS
-}
 
 fn get_title(parsed : &ArgMatches) -> String {
     parsed.get_one::<String>("title").expect("there should have been a default title").clone()
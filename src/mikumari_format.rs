@@ -1,12 +1,20 @@
 #![allow(unused)]
+#![allow(clippy::suspicious_doc_comments, clippy::empty_line_after_doc_comments)]
 ///! Contains the formatting  stuff for mikumari data
 ///! 
 ///! 
 ///! 
 
+/// Ring item type of a frame item.  The body is the absolute frame number (u64)
+/// followed by the frame's first and second delimiters and its hits.  Items written
+/// before the delimiters were kept have only the hits after the frame number;
+/// readers accept both layouts (see MikumariFrame::from_item).
 pub const MIKUMARI_FRAME_ITEM_TYPE: u32=51;
-use std::io::Read;
+use std::io::{Read, Write};
 use std::io;
+use std::fmt;
+use std::mem::size_of;
+use rust_ringitem_format::{body_header_size, RingItem};
 // Data type values:
 
 pub const TDC_LEADING_DATA : u8 = 0b001011;
@@ -18,13 +26,57 @@ pub const INPUT_THROTTLE_T2_END : u8   = 0b010010;
 pub const DELIMETER1  : u8 = 0b011100;
 pub const DELIMETER2  : u8 = 0b011110;
 
+// Delimiter 1 flag bits that are known (see the example in mikumariformat.txt):
+
+/// The TDC overflowed in the frame.
+pub const FLAG_OVERFLOW : u16 = 1 << 11;
+/// Input throttling type 2 was on in the frame.
+pub const FLAG_INPUT_THROTTLE_T2 : u16 = 1 << 6;
+
+/// TDC ticks (0.9765625 ps) in a heartbeat frame (524.288 us).
+pub const HEARTBEAT_PERIOD_TICKS : u64 = 1 << 29;
+
+// Largest values of the fields that are narrower than their types:
+
+pub const MAX_FRAME_NUMBER : u32 = 0xffffff;     // 24 bits.
+pub const MAX_DATA_SIZE    : u32 = 0xfffff;      // 20 bits.
+pub const MAX_CHANNEL      : u8  = 0x7f;         // 7 bits.
+pub const MAX_TOT          : u32 = 0x3fffff;     // 22 bits.
+pub const MAX_TIME         : u32 = 0x1fffffff;   // 29 bits.
+
+/// A value given to a checked constructor (e.g. HRTDCLeading::try_new) that doesn't fit in its field.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RangeError {
+    pub field : &'static str,
+    pub value : u64,
+    pub max   : u64,
+}
+impl fmt::Display for RangeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "The {} {} is larger than the largest allowed: {}", self.field, self.value, self.max)
+    }
+}
+impl std::error::Error for RangeError {}
+
+// Check that a value fits in its field:
+
+fn check(field : &'static str, value : u64, max : u64) -> Result<(), RangeError> {
+    if value > max {
+        Err(RangeError { field, value, max })
+    } else {
+        Ok(())
+    }
+}
+
 /// A heartbeat delimieter1 and its data:
 /// 
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Delimeter1 {
 
     delimeter : u64
 }
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Delimeter2 {
     delimeter : u64
 }
@@ -33,22 +85,41 @@ pub struct Delimeter2 {
 // low resolution as I don't see separate data types for them.
 // Assumption:  Time over threshold will only be present in the trailing
 // time as the TOT is from leading to trailing edge(?).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct HRTDCLeading {
     leading : u64
 }
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct HRTDCTrailing {
     trailing : u64
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct LRTDCLeading {
     leading : u64
 }
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct LRTDCTrailing {
     trailing : u64
 }
-// TODO: range check the inputs as they're not full sized.
+// Each encoder has three constructors:
+//   try_new      - returns a RangeError if a value doesn't fit in its field.
+//   new          - panics if a value doesn't fit in its field.
+//   new_unchecked - does no checking; values that don't fit spill into the other fields.
 impl Delimeter1 {
+    /// ### Parameters:
+    /// *  time_offset  - LACCP time offset.
+    /// *  frame_number - heartbeat frame number; at most MAX_FRAME_NUMBER.
+    pub fn try_new(time_offset : u16, frame_number: u32) -> Result<Delimeter1, RangeError> {
+        check("frame number", frame_number as u64, MAX_FRAME_NUMBER as u64)?;
+        Ok(Delimeter1::new_unchecked(time_offset, frame_number))
+    }
+    /// ### Notes:
+    /// Panics if the frame number is out of range (see try_new).
     pub fn new(time_offset : u16, frame_number: u32) -> Delimeter1 {
+        Delimeter1::try_new(time_offset, frame_number).unwrap_or_else(|e| panic!("{}", e))
+    }
+    pub fn new_unchecked(time_offset : u16, frame_number: u32) -> Delimeter1 {
         let mut value : u64 = 0;
         value |= (DELIMETER1 as u64) << 58;
         value |= (time_offset as u64) << 24;
@@ -72,9 +143,23 @@ impl Delimeter1 {
     pub fn time_offset(&self) -> u64 {
         (self.delimeter >> 24) & 0xffff
     }
+    pub fn flags(&self) -> u16 {
+        ((self.delimeter >> 40) & 0xffff) as u16
+    }
 }
 impl Delimeter2 {
-    pub fn new(data_size: u32)-> Delimeter2 {
+    /// ### Parameters:
+    /// *  data_size - the generated and transferred data sizes; at most MAX_DATA_SIZE.
+    pub fn try_new(data_size: u32) -> Result<Delimeter2, RangeError> {
+        check("data size", data_size as u64, MAX_DATA_SIZE as u64)?;
+        Ok(Delimeter2::new_unchecked(data_size))
+    }
+    /// ### Notes:
+    /// Panics if the data size is out of range (see try_new).
+    pub fn new(data_size: u32) -> Delimeter2 {
+        Delimeter2::try_new(data_size).unwrap_or_else(|e| panic!("{}", e))
+    }
+    pub fn new_unchecked(data_size: u32)-> Delimeter2 {
         let mut value = 0u64;
         value |= (DELIMETER2 as u64) << 58;
         let s = data_size as u64;
@@ -93,12 +178,49 @@ impl Delimeter2 {
     pub fn get (&self) -> u64 {
         self.delimeter
     }    
+    /// The transferred data size in bytes.
     pub fn datasize(&self) -> u64 {
         self.delimeter & 0xfffff
     }  
+    /// The generated data size in bytes.
+    pub fn generated_size(&self) -> u64 {
+        (self.delimeter >> 20) & 0xfffff
+    }
+    pub fn user_flags(&self) -> u16 {
+        ((self.delimeter >> 40) & 0xffff) as u16
+    }
+    /// ### Parameters:
+    /// *  data_size - the new generated and transferred data sizes; at most MAX_DATA_SIZE.
+    /// ### Returns:
+    /// A copy of this delimiter with its sizes set to data_size.  The other fields
+    /// (e.g. the user flags) are kept.
+    pub fn resized(&self, data_size : u32) -> Result<Delimeter2, RangeError> {
+        let sizes = Delimeter2::try_new(data_size)?.get() & 0xffffffffff;
+        Ok(Delimeter2::fromu64((self.delimeter & !0xffffffffff) | sizes))
+    }
+}
+// Check the fields of a HR TDC word:
+
+fn check_hrtdc(chan : u8, tot : u32, time : u32) -> Result<(), RangeError> {
+    check("channel", chan as u64, MAX_CHANNEL as u64)?;
+    check("TOT", tot as u64, MAX_TOT as u64)?;
+    check("time", time as u64, MAX_TIME as u64)
 }
 impl HRTDCLeading {
+    /// ### Parameters:
+    /// *  chan - channel number; at most MAX_CHANNEL.
+    /// *  tot  - time over threshold; at most MAX_TOT.
+    /// *  time - TDC time; at most MAX_TIME.
+    pub fn try_new(chan : u8, tot : u32, time : u32) -> Result<HRTDCLeading, RangeError> {
+        check_hrtdc(chan, tot, time)?;
+        Ok(HRTDCLeading::new_unchecked(chan, tot, time))
+    }
+    /// ### Notes:
+    /// Panics if a value is out of range (see try_new).
     pub fn new(chan : u8, tot : u32, time : u32) -> HRTDCLeading {
+        HRTDCLeading::try_new(chan, tot, time).unwrap_or_else(|e| panic!("{}", e))
+    }
+    pub fn new_unchecked(chan : u8, tot : u32, time : u32) -> HRTDCLeading {
         let mut value = (TDC_LEADING_DATA as u64) << 58;
         value |= (chan as u64) << 51;
         value |= (tot as u64)     << 29;
@@ -131,8 +253,19 @@ impl HRTDCLeading {
 // this is just like the leading edge so we do do some dirty stuff.
 
 impl HRTDCTrailing {
+    /// ### Parameters:
+    /// As for HRTDCLeading::try_new.
+    pub fn try_new(chan : u8, tot : u32, time : u32) -> Result<HRTDCTrailing, RangeError> {
+        check_hrtdc(chan, tot, time)?;
+        Ok(HRTDCTrailing::new_unchecked(chan, tot, time))
+    }
+    /// ### Notes:
+    /// Panics if a value is out of range (see try_new).
     pub fn new(chan : u8, tot : u32, time : u32) -> HRTDCTrailing {
-        let leading = HRTDCLeading::new(chan, tot, time);
+        HRTDCTrailing::try_new(chan, tot, time).unwrap_or_else(|e| panic!("{}", e))
+    }
+    pub fn new_unchecked(chan : u8, tot : u32, time : u32) -> HRTDCTrailing {
+        let leading = HRTDCLeading::new_unchecked(chan, tot, time);
         // maks off the data type and replace it with 0x34
 
         let mut data = leading.leading;
@@ -168,8 +301,75 @@ impl HRTDCTrailing {
     }
 }
 
+// Display writes a word in the style of the example in mikumariformat.txt:
+// a title with the word in hex, then its fields.  The fields follow the title on one line
+// or, with the alternate flag ({:#}), are each on their own line.
+
+fn write_word(f : &mut fmt::Formatter<'_>, title : &str, word : u64, fields : &[String]) -> fmt::Result {
+    write!(f, "{}: {:016x}", title, word)?;
+    let separator = if f.alternate() { "\n" } else { " " };
+    for field in fields {
+        write!(f, "{}{}", separator, field)?;
+    }
+    Ok(())
+}
+// Delimiter 1 flags with the names of the known flags and the bit numbers (from 0) of the others:
+
+pub(crate) fn flag_names(flags : u16) -> String {
+    let names : Vec<String> = (0..16).rev()
+        .filter(|bit| flags & (1 << bit) != 0)
+        .map(|bit| match 1u16 << bit {
+            FLAG_OVERFLOW => String::from("Overflow"),
+            FLAG_INPUT_THROTTLE_T2 => String::from("Input throttling type2"),
+            _ => format!("bit {}", bit),
+        })
+        .collect();
+    if names.is_empty() {
+        format!("Flags {}", flags)
+    } else {
+        format!("Flags {} ({})", flags, names.join(", "))
+    }
+}
+impl fmt::Display for Delimeter1 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_word(f, "First Delimiter", self.get(), &[
+            flag_names(self.flags()),
+            format!("LACCP fine offset {}", self.time_offset()),
+            format!("Heartbeat frame number {}", self.frame()),
+        ])
+    }
+}
+impl fmt::Display for Delimeter2 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_word(f, "Second Delimiter", self.get(), &[
+            format!("User reg. {}", self.user_flags()),
+            format!("Generated data size [byte] {}", self.generated_size()),
+            format!("Transferred data size [byte] {}", self.datasize()),
+        ])
+    }
+}
+impl fmt::Display for HRTDCLeading {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_word(f, "Leading edge data word", self.get(), &[
+            format!("Channel {}", self.channel()),
+            format!("TOT {}", self.tot()),
+            format!("TDC timing {}", self.time()),
+        ])
+    }
+}
+impl fmt::Display for HRTDCTrailing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_word(f, "Trailing edge data word", self.get(), &[
+            format!("Channel {}", self.channel()),
+            format!("TOT {}", self.tot()),
+            format!("TDC timing {}", self.time()),
+        ])
+    }
+}
+
 // This enum is data that can come from a Mikumari data source:
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum MikumariDatum {
     Heartbeat0(Delimeter1),
     Heartbeat1(Delimeter2),
@@ -193,6 +393,36 @@ impl MikumariDatum {
             MikumariDatum::Other(datum)
         }
     }
+    /// The raw word of the datum.
+    pub fn get(&self) -> u64 {
+        match self {
+            MikumariDatum::Heartbeat0(d1) => d1.get(),
+            MikumariDatum::Heartbeat1(d2) => d2.get(),
+            MikumariDatum::LeadingEdge(le) => le.get(),
+            MikumariDatum::TrailingEdge(te) => te.get(),
+            MikumariDatum::Other(word) => *word,
+        }
+    }
+}
+impl fmt::Display for MikumariDatum {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MikumariDatum::Heartbeat0(d1) => d1.fmt(f),
+            MikumariDatum::Heartbeat1(d2) => d2.fmt(f),
+            MikumariDatum::LeadingEdge(le) => le.fmt(f),
+            MikumariDatum::TrailingEdge(te) => te.fmt(f),
+            MikumariDatum::Other(word) => {
+                let dtype = (word >> (64-6)) as u8;
+                let name = match dtype {
+                    INPUT_THROTTLE_T1_START => String::from("InputThrottlingT1Start"),
+                    INPUT_THROTTLE_T1_END => String::from("InputThrottlingT1End"),
+                    INPUT_THROTTLE_T2_START => String::from("InputThrottlingT2Start or End"),
+                    _ => String::from("Unknown"),
+                };
+                write_word(f, "Data word", *word, &[format!("Data type 0b{:06b} ({})", dtype, name)])
+            },
+        }
+    }
 }
 pub struct MikumariReader {
     source : Box<dyn Read>,
@@ -221,6 +451,193 @@ impl MikumariReader {
     }
 } 
 
+/// A heartbeat frame: its delimiters and the data words that follow them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MikumariFrame {
+    pub delimeter1 : Delimeter1,
+    pub delimeter2 : Delimeter2,     // Its sizes are recomputed when written.
+    pub data       : Vec<MikumariDatum>,
+}
+impl MikumariFrame {
+    /// A frame with no data and a second delimiter with no user flags.
+    pub fn new(delimeter1 : Delimeter1) -> MikumariFrame {
+        MikumariFrame {
+            delimeter1,
+            delimeter2 : Delimeter2::new(0),
+            data       : Vec::new(),
+        }
+    }
+    pub fn add(&mut self, datum : MikumariDatum) {
+        self.data.push(datum);
+    }
+    /// The number of bytes of data words in the frame (the delimiters are not counted).
+    pub fn data_size(&self) -> usize {
+        self.data.len() * size_of::<u64>()
+    }
+    /// Gets a frame back from a MIKUMARI_FRAME_ITEM_TYPE ring item made by mikumarimaker.
+    ///
+    /// ### Parameters:
+    /// *  item - the ring item.
+    /// ### Returns:
+    /// The absolute frame number and the frame; the frame's data are the words following
+    /// its delimiters.  An error describes an item that is not a mikumari frame item:  wrong
+    /// type, too short, a partial word or misplaced delimiters.
+    /// ### Notes:
+    /// Items written before the delimiters were kept have only hits after the frame number.
+    /// Their first delimiter is made from the low 24 bits of the frame number and their
+    /// second delimiter has no user flags and is sized for the hits.
+    pub fn from_item(item : &RingItem) -> Result<(u64, MikumariFrame), String> {
+        if item.type_id() != MIKUMARI_FRAME_ITEM_TYPE {
+            return Err(format!("Item type {} is not a mikumari frame", item.type_id()));
+        }
+        let payload = item.payload();
+        let start = if item.has_body_header() { body_header_size() } else { 0 };
+        MikumariFrame::from_body(payload.get(start..).unwrap_or(&[]))
+    }
+    /// Gets a frame back from the body of a frame item (what follows the body header).
+    /// Both layouts are accepted (see from_item).
+    ///
+    /// ### Parameters:
+    /// *  body - the item body.
+    /// ### Returns:
+    /// As from_item.
+    pub fn from_body(body : &[u8]) -> Result<(u64, MikumariFrame), String> {
+        if !body.len().is_multiple_of(size_of::<u64>()) {
+            return Err(format!("Frame item body has a partial word ({} bytes)", body.len()));
+        }
+        let mut words = body.chunks(size_of::<u64>())
+            .map(|w| u64::from_ne_bytes(w.try_into().unwrap()));
+
+        let frame_number = words.next().ok_or("Frame item is too short to have a frame number")?;
+        let mut words = words.map(MikumariDatum::from_u64).peekable();
+        if !matches!(words.peek(), Some(MikumariDatum::Heartbeat0(_))) {
+            return MikumariFrame::from_old_layout(frame_number, words);
+        }
+        let mut frame = match words.next() {
+            Some(MikumariDatum::Heartbeat0(d1)) => MikumariFrame::new(d1),
+            _ => unreachable!("Peeked a first delimiter"),
+        };
+        let mut second = false;                  // Seen the second delimiter.
+        for datum in words {
+            match datum {
+                MikumariDatum::Heartbeat0(_) => {
+                    return Err(format!("Frame {} has more than one first delimiter", frame_number));
+                },
+                MikumariDatum::Heartbeat1(_) if second => {
+                    return Err(format!("Frame {} has more than one second delimiter", frame_number));
+                },
+                MikumariDatum::Heartbeat1(d2) => {
+                    frame.delimeter2 = d2;
+                    second = true;
+                },
+                _ => frame.add(datum),
+            }
+        }
+        Ok((frame_number, frame))
+    }
+    // A frame item body with no delimiters; words are what follows the frame number.
+
+    fn from_old_layout(
+        frame_number : u64, words : impl Iterator<Item = MikumariDatum>
+    ) -> Result<(u64, MikumariFrame), String> {
+        let mut frame = MikumariFrame::new(Delimeter1::new(0, (frame_number & MAX_FRAME_NUMBER as u64) as u32));
+        for datum in words {
+            match datum {
+                MikumariDatum::Heartbeat0(_) | MikumariDatum::Heartbeat1(_) => {
+                    return Err(format!("Frame {} has a delimiter but does not start with a first delimiter", frame_number));
+                },
+                _ => frame.add(datum),
+            }
+        }
+        let size = u32::try_from(frame.data_size()).unwrap_or(u32::MAX);
+        frame.delimeter2 = Delimeter2::try_new(size).map_err(|e| format!("Frame {}: {}", frame_number, e))?;
+        Ok((frame_number, frame))
+    }
+}
+
+/// Writes mikumari data as a raw AMANEQ byte stream (as read by MikumariReader).
+///
+/// Data written with write are held a frame at a time so that the sizes in each
+/// frame's second delimiter match the data actually written.  That makes it possible
+/// to filter raw data (e.g. drop channels) and still have consistent delimiters.
+/// Data before the first heartbeat (the partial frame) are written as they are.
+/// Buffered data are written by flush and when the writer is dropped.
+pub struct MikumariWriter {
+    sink  : Box<dyn Write>,
+    frame : Option<MikumariFrame>,     // Frame being accumulated.
+}
+impl MikumariWriter {
+    // Write a u64 to the sink (in the byte order readu64 expects):
+    fn writeu64(&mut self, datum : u64) -> io::Result<()> {
+        self.sink.write_all(&datum.to_ne_bytes())
+    }
+    // Write the frame being accumulated (if there is one):
+    fn write_pending(&mut self) -> io::Result<()> {
+        if let Some(frame) = self.frame.take() {
+            self.write_frame(&frame)?;
+        }
+        Ok(())
+    }
+
+    pub fn new(sink : Box<dyn Write>) -> MikumariWriter {
+        MikumariWriter {
+            sink,
+            frame : None,
+        }
+    }
+    /// ### Parameters:
+    /// *  datum - the next datum of the stream.
+    /// ### Notes:
+    /// * A Heartbeat0 writes the frame being accumulated and starts a new one.
+    /// * A Heartbeat1 supplies the user flags of the frame's second delimiter; its sizes are ignored.
+    /// * Other data are added to the frame.
+    pub fn write(&mut self, datum : &MikumariDatum) -> io::Result<()> {
+        match (datum, self.frame.as_mut()) {
+            (MikumariDatum::Heartbeat0(d1), _) => {
+                self.write_pending()?;
+                self.frame = Some(MikumariFrame::new(*d1));
+                Ok(())
+            },
+            (MikumariDatum::Heartbeat1(d2), Some(frame)) => {
+                frame.delimeter2 = *d2;
+                Ok(())
+            },
+            (_, Some(frame)) => {
+                frame.add(*datum);
+                Ok(())
+            },
+            (_, None) => self.writeu64(datum.get()),
+        }
+    }
+    /// Writes a complete frame: the first delimiter, the second delimiter with its
+    /// sizes set to the frame's data_size, then the data.  Any frame accumulated by
+    /// write is written first.
+    ///
+    /// ### Returns:
+    /// An InvalidData error if the frame has more data than a delimiter can describe.
+    pub fn write_frame(&mut self, frame : &MikumariFrame) -> io::Result<()> {
+        self.write_pending()?;
+        let d2 = frame.delimeter2.resized(u32::try_from(frame.data_size()).unwrap_or(u32::MAX))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        self.writeu64(frame.delimeter1.get())?;
+        self.writeu64(d2.get())?;
+        for datum in &frame.data {
+            self.writeu64(datum.get())?;
+        }
+        Ok(())
+    }
+    /// Writes the frame being accumulated and flushes the sink.  Call this at the end
+    /// of the data; data written after it up to the next Heartbeat0 are written as they are.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.write_pending()?;
+        self.sink.flush()
+    }
+}
+impl Drop for MikumariWriter {
+    fn drop(&mut self) {
+        let _ = self.flush();          // Errors must be caught with flush.
+    }
+}
 #[cfg(test)]
 mod delim1test {
     use super::*;
@@ -240,6 +657,41 @@ mod delim1test {
         let d = Delimeter1::new(65535, 0);
         assert_eq!((d.get() >> 24) & 0xffff, 65535);
     }
+    #[test]
+    fn flags_1() {
+        // From the example in mikumariformat.txt:
+        let d = Delimeter1::fromu64(0x70084000000f865f);
+        assert_eq!(d.flags(), 0x0840);
+        assert_eq!(d.frame(), 1017439);
+        assert_eq!(Delimeter1::new(0xffff, 0xffffff).flags(), 0);
+    }
+    #[test]
+    fn display_1() {
+        let d = Delimeter1::fromu64(0x70084000000f865f);
+        assert_eq!(
+            d.to_string(),
+            "First Delimiter: 70084000000f865f Flags 2112 (Overflow, Input throttling type2) \
+             LACCP fine offset 0 Heartbeat frame number 1017439"
+        );
+        assert_eq!(
+            format!("{:#}", d),
+            "First Delimiter: 70084000000f865f\nFlags 2112 (Overflow, Input throttling type2)\n\
+             LACCP fine offset 0\nHeartbeat frame number 1017439"
+        );
+    }
+    #[test]
+    fn display_2() {
+        // Flags without names are given by bit number; no flags, no names:
+
+        let d = Delimeter1::fromu64(Delimeter1::new(3, 7).get() | (0x8001 << 40));
+        assert!(d.to_string().contains("Flags 32769 (bit 15, bit 0) LACCP fine offset 3"));
+        assert!(Delimeter1::new(0, 7).to_string().contains("Flags 0 LACCP"));
+    }
+    #[test]
+    fn eq_1() {
+        assert_eq!(Delimeter1::new(1, 2), Delimeter1::fromu64(Delimeter1::new(1, 2).get()));
+        assert_ne!(Delimeter1::new(1, 2), Delimeter1::new(1, 3));
+    }
 }
 #[cfg(test)]
 mod delim2test {
@@ -261,6 +713,27 @@ mod delim2test {
         let d = Delimeter2::new(12345); 
         assert_eq!((d.get() >> 20) & 0xfffff, 12345u64);
     }
+    #[test]
+    fn user_flags_1() {
+        let d = Delimeter2::fromu64(Delimeter2::new(0xfffff).get() | (0xbeef << 40));
+        assert_eq!(d.user_flags(), 0xbeef);
+        assert_eq!(Delimeter2::new(0xfffff).user_flags(), 0);
+    }
+    #[test]
+    fn generated_size_1() {
+        // From the example in mikumariformat.txt:
+        let d = Delimeter2::fromu64(0x7800000004000020);
+        assert_eq!(d.generated_size(), 64);
+        assert_eq!(d.datasize(), 32);
+    }
+    #[test]
+    fn display_1() {
+        assert_eq!(
+            Delimeter2::fromu64(0x7800000004000020).to_string(),
+            "Second Delimiter: 7800000004000020 User reg. 0 Generated data size [byte] 64 \
+             Transferred data size [byte] 32"
+        );
+    }
 }
 #[cfg(test)] 
 mod hrtdc {
@@ -320,4 +793,340 @@ mod hrtdc {
         let trailing = HRTDCTrailing::new(10, 100, 12345);
         assert_eq!(trailing.time(), 12345);
     }
-}
\ No newline at end of file
+    #[test]
+    fn display_1() {
+        // From the example in mikumariformat.txt:
+        assert_eq!(
+            format!("{:#}", HRTDCLeading::fromu64(0x2c1831c96c2f265e)),
+            "Leading edge data word: 2c1831c96c2f265e\nChannel 3\nTOT 101963\nTDC timing 204416606"
+        );
+        assert_eq!(
+            HRTDCTrailing::new(10, 100, 12345).to_string(),
+            format!("Trailing edge data word: {:016x} Channel 10 TOT 100 TDC timing 12345", HRTDCTrailing::new(10, 100, 12345).get())
+        );
+    }
+    #[test]
+    fn eq_1() {
+        let copy = HRTDCLeading::new(10, 100, 12345);
+        assert_eq!(copy, HRTDCLeading::new(10, 100, 12345));
+        assert_ne!(HRTDCTrailing::new(10, 100, 12345), HRTDCTrailing::new(11, 100, 12345));
+    }
+}
+#[cfg(test)]
+mod datum_tests {
+    use super::*;
+
+    #[test]
+    fn from_u64_1() {
+        assert_eq!(MikumariDatum::from_u64(0x70084000000f865f), MikumariDatum::Heartbeat0(Delimeter1::fromu64(0x70084000000f865f)));
+        assert_eq!(MikumariDatum::from_u64(0x7800000004000020), MikumariDatum::Heartbeat1(Delimeter2::fromu64(0x7800000004000020)));
+        assert_eq!(MikumariDatum::from_u64(HRTDCLeading::new(1, 2, 3).get()), MikumariDatum::LeadingEdge(HRTDCLeading::new(1, 2, 3)));
+        assert_eq!(MikumariDatum::from_u64(HRTDCTrailing::new(1, 2, 3).get()), MikumariDatum::TrailingEdge(HRTDCTrailing::new(1, 2, 3)));
+        assert_eq!(MikumariDatum::from_u64(0), MikumariDatum::Other(0));
+    }
+    #[test]
+    fn display_1() {
+        // Datums display as what they hold:
+
+        let d1 = Delimeter1::fromu64(0x70084000000f865f);
+        assert_eq!(MikumariDatum::Heartbeat0(d1).to_string(), d1.to_string());
+        assert_eq!(format!("{:#}", MikumariDatum::Heartbeat0(d1)), format!("{:#}", d1));
+
+        let throttle = (INPUT_THROTTLE_T1_END as u64) << 58;
+        assert_eq!(
+            MikumariDatum::from_u64(throttle).to_string(),
+            "Data word: 4400000000000000 Data type 0b010001 (InputThrottlingT1End)"
+        );
+        assert_eq!(MikumariDatum::Other(5).to_string(), "Data word: 0000000000000005 Data type 0b000000 (Unknown)");
+    }
+    #[test]
+    fn get_1() {
+        for word in [0x70084000000f865f, 0x7800000004000020, HRTDCLeading::new(1, 2, 3).get(),
+                     HRTDCTrailing::new(1, 2, 3).get(), 5] {
+            assert_eq!(MikumariDatum::from_u64(word).get(), word);
+        }
+    }
+}
+#[cfg(test)]
+mod range_tests {
+    use super::*;
+    use rand::RngExt;
+
+    const TRIALS : usize = 10000;
+
+    #[test]
+    fn round_trip_1() {
+        // Every field of every encoder comes back as it went in:
+
+        let mut r = rand::rng();
+        for _ in 0..TRIALS {
+            let offset : u16 = r.random();
+            let frame = r.random_range(0..=MAX_FRAME_NUMBER);
+            let d1 = Delimeter1::try_new(offset, frame).unwrap();
+            let d1 = Delimeter1::fromu64(d1.get());
+            assert_eq!((d1.time_offset(), d1.frame(), d1.flags()), (offset as u64, frame as u64, 0));
+            assert_eq!(MikumariDatum::from_u64(d1.get()), MikumariDatum::Heartbeat0(d1));
+
+            let size = r.random_range(0..=MAX_DATA_SIZE);
+            let d2 = Delimeter2::fromu64(Delimeter2::try_new(size).unwrap().get());
+            assert_eq!((d2.datasize(), d2.generated_size(), d2.user_flags()), (size as u64, size as u64, 0));
+            assert_eq!(MikumariDatum::from_u64(d2.get()), MikumariDatum::Heartbeat1(d2));
+
+            let chan = r.random_range(0..=MAX_CHANNEL);
+            let tot = r.random_range(0..=MAX_TOT);
+            let time = r.random_range(0..=MAX_TIME);
+            let le = HRTDCLeading::fromu64(HRTDCLeading::try_new(chan, tot, time).unwrap().get());
+            assert_eq!((le.channel(), le.tot(), le.time()), (chan, tot, time));
+            assert_eq!(MikumariDatum::from_u64(le.get()), MikumariDatum::LeadingEdge(le));
+            let te = HRTDCTrailing::fromu64(HRTDCTrailing::try_new(chan, tot, time).unwrap().get());
+            assert_eq!((te.channel(), te.tot(), te.time()), (chan, tot, time));
+            assert_eq!(MikumariDatum::from_u64(te.get()), MikumariDatum::TrailingEdge(te));
+
+            // The checked and unchecked constructors agree on values in range:
+
+            assert_eq!(le, HRTDCLeading::new_unchecked(chan, tot, time));
+            assert_eq!(te, HRTDCTrailing::new(chan, tot, time));
+        }
+    }
+    #[test]
+    fn range_1() {
+        // Values out of range are errors naming the field:
+
+        let mut r = rand::rng();
+        for _ in 0..TRIALS {
+            let frame = r.random_range(MAX_FRAME_NUMBER + 1..=u32::MAX);
+            assert_eq!(
+                Delimeter1::try_new(0, frame),
+                Err(RangeError { field : "frame number", value : frame as u64, max : MAX_FRAME_NUMBER as u64 })
+            );
+            let size = r.random_range(MAX_DATA_SIZE + 1..=u32::MAX);
+            assert_eq!(Delimeter2::try_new(size).unwrap_err().field, "data size");
+
+            let chan = r.random_range(MAX_CHANNEL + 1..=u8::MAX);
+            let tot = r.random_range(MAX_TOT + 1..=u32::MAX);
+            let time = r.random_range(MAX_TIME + 1..=u32::MAX);
+            assert_eq!(HRTDCLeading::try_new(chan, 0, 0).unwrap_err().field, "channel");
+            assert_eq!(HRTDCLeading::try_new(0, tot, 0).unwrap_err().field, "TOT");
+            assert_eq!(HRTDCTrailing::try_new(0, 0, time).unwrap_err().field, "time");
+        }
+    }
+    #[test]
+    fn unchecked_1() {
+        // Unchecked values spill into the neighboring fields:
+
+        let le = HRTDCLeading::new_unchecked(0, 0, MAX_TIME + 1);
+        assert_eq!((le.time(), le.tot()), (0, 1));
+        assert_eq!(Delimeter1::new_unchecked(0, MAX_FRAME_NUMBER + 1).time_offset(), 1);
+    }
+    #[test]
+    fn display_1() {
+        assert_eq!(
+            HRTDCLeading::try_new(200, 0, 0).unwrap_err().to_string(),
+            "The channel 200 is larger than the largest allowed: 127"
+        );
+    }
+    #[test]
+    #[should_panic(expected = "The TOT")]
+    fn new_1() {
+        HRTDCTrailing::new(0, MAX_TOT + 1, 0);
+    }
+}
+#[cfg(test)]
+mod writer_tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::io::Cursor;
+    use std::rc::Rc;
+
+    // A sink whose bytes can be looked at after the writer is done with it:
+
+    #[derive(Clone, Default)]
+    struct SharedSink(Rc<RefCell<Vec<u8>>>);
+    impl Write for SharedSink {
+        fn write(&mut self, buf : &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+    fn writer() -> (MikumariWriter, SharedSink) {
+        let sink = SharedSink::default();
+        (MikumariWriter::new(Box::new(sink.clone())), sink)
+    }
+    // Read back what was written:
+
+    fn read_back(sink : &SharedSink) -> Vec<MikumariDatum> {
+        let mut reader = MikumariReader::new(Box::new(Cursor::new(sink.0.borrow().clone())));
+        let mut result = Vec::new();
+        while let Ok(datum) = reader.read() {
+            result.push(datum);
+        }
+        result
+    }
+    fn hit(chan : u8) -> MikumariDatum {
+        MikumariDatum::LeadingEdge(HRTDCLeading::new(chan, 10, 100))
+    }
+    fn d1(frame : u32) -> MikumariDatum {
+        MikumariDatum::Heartbeat0(Delimeter1::new(0, frame))
+    }
+
+    #[test]
+    fn resized_1() {
+        // Sizes change, user flags stay:
+
+        let d2 = Delimeter2::fromu64(0x7800000004000020 | (0x1234 << 40)).resized(24).unwrap();
+        assert_eq!((d2.user_flags(), d2.generated_size(), d2.datasize()), (0x1234, 24, 24));
+        assert!(Delimeter2::new(0).resized(MAX_DATA_SIZE + 1).is_err());
+    }
+    #[test]
+    fn frame_1() {
+        let mut frame = MikumariFrame::new(Delimeter1::new(0, 1));
+        assert_eq!(frame.data_size(), 0);
+        frame.add(hit(1));
+        frame.add(hit(2));
+        assert_eq!(frame.data_size(), 16);
+        assert_eq!(frame.delimeter2, Delimeter2::new(0));
+    }
+    // A frame item the way mikumarimaker makes them:
+
+    fn frame_item(frame : u64, words : &[u64]) -> RingItem {
+        let mut item = RingItem::new_with_body_header(MIKUMARI_FRAME_ITEM_TYPE, 100, 1, 0);
+        item.add(frame);
+        for w in words {
+            item.add(*w);
+        }
+        item
+    }
+    #[test]
+    fn from_item_1() {
+        let d2 = Delimeter2::fromu64(Delimeter2::new(16).get() | (3 << 40));
+        let item = frame_item(1234, &[Delimeter1::new(0, 1234).get(), d2.get(), hit(1).get(), 7]);
+        let (number, frame) = MikumariFrame::from_item(&item).unwrap();
+        assert_eq!(number, 1234);
+        assert_eq!(frame.delimeter1, Delimeter1::new(0, 1234));
+        assert_eq!(frame.delimeter2, d2);
+        assert_eq!(frame.data, vec![hit(1), MikumariDatum::Other(7)]);
+    }
+    #[test]
+    fn from_item_2() {
+        // No second delimiter, no data:
+
+        let (number, frame) = MikumariFrame::from_item(&frame_item(5, &[Delimeter1::new(0, 5).get()])).unwrap();
+        assert_eq!(number, 5);
+        assert_eq!(frame, MikumariFrame::new(Delimeter1::new(0, 5)));
+    }
+    #[test]
+    fn from_item_3() {
+        // Bad items:
+
+        let d1 = Delimeter1::new(0, 5).get();
+        let d2 = Delimeter2::new(0).get();
+        assert!(MikumariFrame::from_item(&RingItem::new_with_body_header(30, 0, 1, 0)).is_err());
+        assert!(MikumariFrame::from_item(&RingItem::new_with_body_header(MIKUMARI_FRAME_ITEM_TYPE, 0, 1, 0)).is_err());
+        assert!(MikumariFrame::from_item(&frame_item(5, &[d1, d2, d1])).is_err());
+        assert!(MikumariFrame::from_item(&frame_item(5, &[d2, hit(1).get()])).is_err());
+        assert!(MikumariFrame::from_item(&frame_item(5, &[hit(1).get(), d1])).is_err());
+        assert!(MikumariFrame::from_item(&frame_item(5, &[d1, d2, d2])).is_err());
+
+        let mut partial = frame_item(5, &[d1]);
+        partial.add(1u32);
+        let e = MikumariFrame::from_item(&partial).unwrap_err();
+        assert!(e.contains("partial word"), "{}", e);
+    }
+    #[test]
+    fn from_item_4() {
+        // Old layout items (no delimiters) get delimiters made from the frame number and hits:
+
+        let (number, frame) = MikumariFrame::from_item(&frame_item(0x1234567, &[hit(1).get(), hit(2).get()])).unwrap();
+        assert_eq!(number, 0x1234567);
+        assert_eq!(frame.delimeter1, Delimeter1::new(0, 0x234567));
+        assert_eq!(frame.delimeter2, Delimeter2::new(16));
+        assert_eq!(frame.data, vec![hit(1), hit(2)]);
+
+        let (number, frame) = MikumariFrame::from_item(&frame_item(5, &[])).unwrap();
+        assert_eq!(number, 5);
+        assert_eq!(frame, MikumariFrame::new(Delimeter1::new(0, 5)));
+    }
+    #[test]
+    fn write_frame_1() {
+        // Delimiters are sized for the data:
+
+        let (mut w, sink) = writer();
+        let mut frame = MikumariFrame::new(Delimeter1::new(0, 1));
+        frame.delimeter2 = Delimeter2::fromu64(Delimeter2::new(1000).get() | (5 << 40));
+        frame.add(hit(1));
+        frame.add(MikumariDatum::Other(7));
+        w.write_frame(&frame).unwrap();
+        w.flush().unwrap();
+
+        let expected_d2 = Delimeter2::fromu64(Delimeter2::new(16).get() | (5 << 40));
+        assert_eq!(
+            read_back(&sink),
+            vec![d1(1), MikumariDatum::Heartbeat1(expected_d2), hit(1), MikumariDatum::Other(7)]
+        );
+    }
+    #[test]
+    fn write_frame_2() {
+        // Too much data for a delimiter is an error and nothing is written:
+
+        let (mut w, sink) = writer();
+        let mut frame = MikumariFrame::new(Delimeter1::new(0, 1));
+        frame.data = vec![hit(1); (MAX_DATA_SIZE as usize) / 8 + 1];
+        let e = w.write_frame(&frame).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert!(sink.0.borrow().is_empty());
+    }
+    #[test]
+    fn write_1() {
+        // A stream with hits filtered out gets delimiters for what's left:
+
+        let (mut w, sink) = writer();
+        let input = vec![
+            hit(9),                                                  // Partial frame.
+            d1(1), MikumariDatum::Heartbeat1(Delimeter2::new(24)), hit(1), hit(2), hit(3),
+            d1(2), MikumariDatum::Heartbeat1(Delimeter2::new(8)), hit(2),
+        ];
+        for datum in input.iter().filter(|d| **d != hit(2)) {
+            w.write(datum).unwrap();
+        }
+        w.flush().unwrap();
+
+        assert_eq!(
+            read_back(&sink),
+            vec![
+                hit(9),
+                d1(1), MikumariDatum::Heartbeat1(Delimeter2::new(16)), hit(1), hit(3),
+                d1(2), MikumariDatum::Heartbeat1(Delimeter2::new(0)),
+            ]
+        );
+    }
+    #[test]
+    fn write_2() {
+        // Unchanged data come back unchanged and the last frame is written on drop:
+
+        let sink = SharedSink::default();
+        let input = vec![
+            d1(1), MikumariDatum::Heartbeat1(Delimeter2::new(16)), hit(1), hit(2),
+            d1(2), MikumariDatum::Heartbeat1(Delimeter2::new(8)), hit(3),
+        ];
+        {
+            let mut w = MikumariWriter::new(Box::new(sink.clone()));
+            for datum in &input {
+                w.write(datum).unwrap();
+            }
+        }
+        assert_eq!(read_back(&sink), input);
+    }
+    #[test]
+    fn write_3() {
+        // A frame without a second delimiter gets one:
+
+        let (mut w, sink) = writer();
+        w.write(&d1(1)).unwrap();
+        w.write(&hit(1)).unwrap();
+        w.flush().unwrap();
+        assert_eq!(read_back(&sink), vec![d1(1), MikumariDatum::Heartbeat1(Delimeter2::new(8)), hit(1)]);
+    }
+}
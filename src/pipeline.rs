@@ -0,0 +1,1904 @@
+//!
+//! The stages of defenestrating mikumari frame ring items and the threaded
+//! pipeline that runs them.
+//!
+//! Processing is split into:
+//!
+//! 1.  Reading ring items from the data source.
+//! 2.  Decoding: the hits in a frame item are calibrated and time ordered.  Each
+//!     ring item becomes a list of GlomOp's.
+//! 3.  Building: the GlomOp's are applied to a Glom (and Pairer) which builds events.
+//! 4.  Writing the ring items the Glom produces to the data sink.
+//!
+//! With multiple sources (Decoder::set_multi_source) the decoder merges the frames of
+//! all source ids into one time ordered stream so events can have hits from several boards.
+//! Hits are held until every source has sent a frame that starts after them (the watermark).
+//! A source that falls too far behind the others (Decoder::set_max_source_lag) is not waited for.
+//! Sources are known from their begin run items or their first frame.  End run items are held
+//! until every source has ended its run.
+//!
+//! run_single runs all of these on the calling thread.  run_threaded runs each on its
+//! own thread connected by bounded channels.  Each stage handles its input in
+//! order so the output is the same either way.
+//!
+//! Data sources, sinks and Gloms can't be moved between threads so run_threaded
+//! is given functions that make them and calls those on the threads that use them.
+//!
+//! Frame items that can't be decoded (no body header, too short for a frame number,
+//! not a whole number of 64 bit words or misplaced delimiters) become GlomOp::BadItem.
+//! Frame items in both the current and the old (no delimiters) layout are decoded.  The event builder counts
+//! and skips them or, if it is strict, stops with an error giving the item's offset.
+//!
+use crate::{mikumari_format, glom, calibration, dnl, report};
+use frib_datasource::{DataSink, DataSource};
+use rust_ringitem_format::{body_header_size, BodyHeader, RingItem, ToRaw, BEGIN_RUN, END_RUN, PAUSE_RUN, RESUME_RUN};
+use rust_ringitem_format::text_item::{TextItem, TextItemType};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::mem::size_of;
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender};
+use std::thread;
+use std::time;
+
+/// Default number of messages each channel in the threaded pipeline holds.
+pub const DEFAULT_QUEUE_DEPTH : usize = 64;
+/// Most ring items the event builder thread sends the writer thread at once.
+pub const WRITE_BATCH : usize = 256;
+/// How far a source can fall behind the others in multi source mode before it's not waited for:
+/// 2048 frames (about a second).
+pub const DEFAULT_MAX_SOURCE_LAG : u64 = 2048 * mikumari_format::HEARTBEAT_PERIOD_TICKS;
+/// How long the event builder thread waits for input before checking if a status line is due.
+pub const MONITOR_POLL : time::Duration = time::Duration::from_millis(500);
+
+/// A decoded hit: leading, channel, time, tot.
+pub type DecodedHit = (bool, glom::ChannelId, u64, u32);
+
+/// Errors that stop the event builder.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PipelineError {
+    /// The Glom failed.
+    Glom(glom::GlomError),
+    /// A frame item could not be decoded and the event builder is strict
+    /// (see EventBuilder::set_strict).  offset is the item's byte offset in the input.
+    BadItem { offset : u64, error : String },
+}
+impl fmt::Display for PipelineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PipelineError::Glom(e) => e.fmt(f),
+            PipelineError::BadItem { offset, error } => write!(
+                f, "Bad frame item at offset {}: {}", offset, error
+            ),
+        }
+    }
+}
+impl std::error::Error for PipelineError {}
+impl From<glom::GlomError> for PipelineError {
+    fn from(e : glom::GlomError) -> Self {
+        PipelineError::Glom(e)
+    }
+}
+
+/// Result type returned by the event builder.
+pub type PipelineResult<T> = Result<T, PipelineError>;
+
+/// What decoding a ring item asks the event builder to do.
+pub enum GlomOp {
+    /// Set the source id of the events that are built.
+    SetSid(u32),
+    /// Write a ring item unaltered.
+    Write(RingItem),
+    /// A frame starts.
+    FrameBoundary { frame : u64, timestamp : u64, flags : u32 },
+    /// Time ordered hits.
+    Hits(Vec<DecodedHit>),
+    /// A run begins; the begin run item.
+    BeginRun(RingItem),
+    /// The run is paused; the pause item.
+    PauseRun(RingItem),
+    /// The run resumes; the resume item.
+    ResumeRun(RingItem),
+    /// The run ended; the end run items (more than one with multiple sources).
+    EndRun(Vec<RingItem>),
+    /// There is no more input.
+    EndInput,
+    /// A frame item that could not be decoded; its byte offset in the input and why.
+    BadItem { offset : u64, error : String },
+    /// Something the user should know about (e.g. a source that is not keeping up).
+    Warning(String),
+}
+
+/// Turns ring items into GlomOp's.
+///
+/// By default, all MIKUMARI_FRAME_ITEM_TYPE items are converted, the events built get the
+/// source id of the input and all other items are passed through.
+pub struct Decoder {
+    calibration : Option<calibration::Calibration>,
+    dnl         : Option<dnl::DnlCorrection>,
+    merger      : Option<SourceMerger>,      // Some in multi source mode.
+    frame_type  : u32,                       // Item type of frames.
+    sources     : Option<Vec<u32>>,          // Only frames from these are converted.
+    output_sid  : Option<u32>,               // Source id forced on the events.
+    drop_others : bool,                      // Drop items that are not converted.
+    max_lag     : u64,                       // Most a merged source can lag.
+    offset      : u64,                       // Byte offset of the next item in the input.
+}
+impl Default for Decoder {
+    fn default() -> Self {
+        Decoder::new()
+    }
+}
+impl Decoder {
+    /// A decoder that does not change hit times or TOTs.
+    pub fn new() -> Decoder {
+        Decoder {
+            calibration : None,
+            dnl         : None,
+            merger      : None,
+            frame_type  : mikumari_format::MIKUMARI_FRAME_ITEM_TYPE,
+            sources     : None,
+            output_sid  : None,
+            drop_others : false,
+            max_lag     : DEFAULT_MAX_SOURCE_LAG,
+            offset      : 0,
+        }
+    }
+    /// Set the ring item type of the frame items.
+    pub fn set_frame_type(&mut self, frame_type : u32) {
+        self.frame_type = frame_type;
+    }
+    /// Only convert frames from these source ids.  Frames from other sources are treated
+    /// like other items (see set_drop_others).  In multi source mode, only these sources are
+    /// waited for.  None converts frames from all sources.
+    pub fn set_sources(&mut self, sources : Option<Vec<u32>>) {
+        self.sources = sources;
+    }
+    /// Force the source id of the events built rather than taking it from the input.
+    /// None takes it from the input.
+    pub fn set_output_sid(&mut self, sid : Option<u32>) {
+        self.output_sid = sid;
+    }
+    /// When drop is true, items that are not converted are dropped rather than passed through.
+    /// Begin, end, pause and resume run items are always written.
+    pub fn set_drop_others(&mut self, drop : bool) {
+        self.drop_others = drop;
+    }
+    // Is an item a frame that's converted?
+
+    fn converts(&self, item : &RingItem) -> bool {
+        item.type_id() == self.frame_type && match &self.sources {
+            Some(sources) => body_header(item).is_some_and(|bh| sources.contains(&bh.source_id)),
+            None => true,
+        }
+    }
+    // Is a source id one we care about?
+
+    fn selected(&self, sid : u32) -> bool {
+        self.sources.as_ref().is_none_or(|s| s.contains(&sid))
+    }
+    // What's done with items that are not converted:
+
+    fn other(&self, item : RingItem) -> Vec<GlomOp> {
+        if self.drop_others { Vec::new() } else { vec![GlomOp::Write(item)] }
+    }
+    // The source id the events get given the one from the input:
+
+    fn sid(&self, sid : u32) -> u32 {
+        self.output_sid.unwrap_or(sid)
+    }
+    /// Set the calibration applied to the hits.  The calibration is also written
+    /// as a MONITORED_VARIABLES item after each begin run.
+    pub fn set_calibration(&mut self, calibration : calibration::Calibration) {
+        self.calibration = Some(calibration);
+    }
+    /// Set the DNL correction of the hit fine times.  This is applied before the calibration.
+    pub fn set_dnl(&mut self, dnl : dnl::DnlCorrection) {
+        self.dnl = Some(dnl);
+    }
+    /// Select multi source mode: the frames of all source ids are merged into one
+    /// time ordered stream of hits.  The events built have the source id of the
+    /// last begin run.
+    pub fn set_multi_source(&mut self, multi : bool) {
+        self.merger = if multi { Some(SourceMerger::new(self.max_lag)) } else { None };
+    }
+    /// Set how far (in TDC ticks) a source can fall behind the newest frame in multi source
+    /// mode before it is no longer waited for (default DEFAULT_MAX_SOURCE_LAG).  A source that
+    /// has not sent a frame is behind by the time since the first frame of the run.  Sources
+    /// that fall behind are warned about (GlomOp::Warning) and are waited for again once they
+    /// send a frame; hits they send that are earlier than what was released are out of order.
+    pub fn set_max_source_lag(&mut self, ticks : u64) {
+        self.max_lag = ticks;
+        if let Some(m) = &mut self.merger {
+            m.max_lag = ticks;
+        }
+    }
+    /// The input has ended.
+    ///
+    /// ### Returns:
+    /// Vec<GlomOp> - GlomOp::EndInput.  In multi source mode it's preceded by whatever is
+    /// still held and, if some sources ended their runs, it's GlomOp::EndRun with their end run items.
+    pub fn finish(&mut self) -> Vec<GlomOp> {
+        match &mut self.merger {
+            Some(m) => {
+                let mut ops = m.release(None);
+                if m.end_runs.is_empty() {
+                    ops.push(GlomOp::EndInput);
+                } else {
+                    ops.push(GlomOp::EndRun(std::mem::take(&mut m.end_runs)));
+                }
+                m.reset();
+                ops
+            },
+            None => vec![GlomOp::EndInput],
+        }
+    }
+    /// Decode a ring item.
+    ///
+    /// ### Parameters:
+    /// *  item - the ring item.  Items that are not mikumari frames are passed
+    ///    through in a GlomOp::Write.
+    ///
+    /// ### Returns:
+    /// Vec<GlomOp> - what the event builder should do with the item, in order.  A frame
+    /// item that can't be decoded is a GlomOp::BadItem.
+    pub fn decode(&mut self, item : RingItem) -> Vec<GlomOp> {
+        let offset = self.offset;
+        self.offset += item.size() as u64;
+        self.decode_at(item, offset)
+    }
+    // Decode an item at an offset in the input:
+
+    fn decode_at(&mut self, item : RingItem, offset : u64) -> Vec<GlomOp> {
+        if self.merger.is_some() {
+            return self.decode_merged(item, offset);
+        }
+        let item_type = item.type_id();
+        if self.converts(&item) {
+            let (sid, frame, timestamp, flags, hits) = match self.frame_hits(&item) {
+                Ok(frame) => frame,
+                Err(error) => return vec![GlomOp::BadItem { offset, error }],
+            };
+            return vec![
+                GlomOp::SetSid(self.sid(sid)),    // In case there's no BEGIN e.g.
+                GlomOp::FrameBoundary { frame, timestamp, flags },
+                GlomOp::Hits(hits),
+            ];
+        }
+        match item_type {
+            BEGIN_RUN => {
+                // Set the source id from the ring item and record the calibration after it:
+
+                let mut ops = Vec::new();
+                if let Some(bh) = body_header(&item) {
+                    ops.push(GlomOp::SetSid(self.sid(bh.source_id)));
+                }
+                let calibration = self.calibration.as_ref().map(|cal| calibration_item(cal, &item));
+                ops.push(GlomOp::BeginRun(item));
+                if let Some(cal) = calibration {
+                    ops.push(GlomOp::Write(cal));
+                }
+                ops
+            },
+            END_RUN    => vec![GlomOp::EndRun(vec![item])],
+            PAUSE_RUN  => vec![GlomOp::PauseRun(item)],
+            RESUME_RUN => vec![GlomOp::ResumeRun(item)],
+            _ => self.other(item),
+        }
+    }
+
+    // Multi source decode.
+
+    fn decode_merged(&mut self, item : RingItem, offset : u64) -> Vec<GlomOp> {
+        let item_type = item.type_id();
+        let sid = body_header(&item).map(|bh| bh.source_id);
+        if self.converts(&item) {
+            let (sid, frame, timestamp, flags, hits) = match self.frame_hits(&item) {
+                Ok(frame) => frame,
+                Err(error) => return vec![GlomOp::BadItem { offset, error }],
+            };
+            let merger = self.merger.as_mut().unwrap();
+            let mut ops : Vec<GlomOp> = merger.add_frame(sid, frame, timestamp, flags, hits)
+                .into_iter().map(GlomOp::Warning).collect();
+            if let Some(w) = merger.watermark() {
+                ops.extend(merger.release(Some(w)));
+            }
+            return ops;
+        }
+        let selected = sid.is_none_or(|sid| self.selected(sid));
+        if !matches!(item_type, BEGIN_RUN | END_RUN | PAUSE_RUN | RESUME_RUN) {
+            return self.other(item);
+        }
+        let merger = self.merger.as_mut().unwrap();
+        match item_type {
+            END_RUN => {
+                merger.end_runs.push(item);
+                merger.end_source(sid);
+                if !merger.all_ended() {
+                    return match merger.watermark() {
+                        Some(w) => merger.release(Some(w)),
+                        None => Vec::new(),
+                    };
+                }
+                let mut ops = merger.release(None);
+                ops.push(GlomOp::EndRun(std::mem::take(&mut merger.end_runs)));
+                merger.reset();
+                ops
+            },
+            BEGIN_RUN => {
+                // Only the first source's begin run begins the run:
+
+                if let Some(sid) = sid && selected {
+                    merger.add_source(sid);
+                }
+                if merger.in_run {
+                    return vec![GlomOp::Write(item)];
+                }
+                merger.in_run = true;
+                let merger = self.merger.take();
+                let ops = self.decode_at(item, offset);
+                self.merger = merger;
+                ops
+            },
+            PAUSE_RUN if !merger.paused => {
+                merger.paused = true;
+                vec![GlomOp::PauseRun(item)]
+            },
+            RESUME_RUN if merger.paused => {
+                merger.paused = false;
+                vec![GlomOp::ResumeRun(item)]
+            },
+            _ => vec![GlomOp::Write(item)],    // Other sources' pause/resume.
+        }
+    }
+
+    // Calibrate and time order the hits of a frame.
+    //
+    // Returns the source id, absolute frame number, timestamp, frame flags and hits or
+    // why the item is not a frame that can be decoded.
+
+    fn frame_hits(&self, item : &RingItem) -> Result<(u32, u64, u64, u32, Vec<DecodedHit>), String> {
+        let bh = body_header(item).ok_or("Frame item has no body header")?;
+        let t0 = bh.timestamp;
+        let payload = item.payload();    // Vec<u8>
+
+        // There must be an absolute frame number (64 bits) and whole words after it.
+        // Payload includes the body header.  The words are the frame's delimiters and
+        // hits or, in the old layout, just its hits (see MikumariFrame::from_item).
+
+        let cursor = body_header_size();
+        let body = payload.len() - cursor;
+        if body < size_of::<u64>() {
+            return Err(format!("Frame item body of {} bytes has no frame number", body));
+        }
+        if !body.is_multiple_of(size_of::<u64>()) {
+            return Err(format!("Frame item body of {} bytes is not a whole number of 64 bit words", body));
+        }
+        let (frame, frame_data) = mikumari_format::MikumariFrame::from_body(&payload[cursor..])?;
+        let flags = frame_flags(&frame_data);
+
+        // Sort the hits:
+
+        let mut orderer = glom::Orderer::new();
+        let calibrate = |chan : u8, time : u32, tot : u32| {
+            let id = glom::ChannelId::new(bh.source_id, chan as u16);
+            let t = self.dnl.as_ref().map_or(time as u64, |d| d.correct(&id, time as u64)).saturating_add(t0);
+            match &self.calibration {
+                Some(cal) => cal.apply(&id, t, tot),
+                None => (t, tot),
+            }
+        };
+
+        for datum in &frame_data.data {
+            match datum {
+                mikumari_format::MikumariDatum::LeadingEdge(le)  => {
+                    let (t, tot) = calibrate(le.channel(), le.time(), le.tot());
+                    orderer.add_hit(true, le.channel() as u16, t, tot);
+                },
+                mikumari_format::MikumariDatum::TrailingEdge(te) => {
+                    let (t, tot) = calibrate(te.channel(), te.time(), te.tot());
+                    orderer.add_hit(false, te.channel() as u16, t, tot);
+                },
+                _ => {},              // ANything else is not passed through.
+            }
+        }
+        let hits = orderer.order().into_iter()
+            .map(|(rising, chan, time, tot)| (rising, glom::ChannelId::new(bh.source_id, chan), time, tot))
+            .collect();
+        Ok((bh.source_id, frame, t0, flags, hits))
+    }
+}
+
+// Merges the frames of several sources.  The watermark is the earliest of the
+// latest frame timestamps of the sources whose runs have not ended.  No source can
+// send a hit earlier than that so the hits and frame boundaries before it can be released.
+// Frame boundaries are per frame number rather than per source; the flags of a frame
+// are those of all of the sources.
+//
+// A source that falls more than max_lag ticks behind the newest frame (or, if it has sent
+// none, behind the first frame of the run) is idle: it is not waited for until it sends a
+// frame again.  Otherwise a dead board would hold every other board's hits until the run ends.
+
+struct SourceMerger {
+    sources  : BTreeMap<u32, Option<u64>>,      // Active source -> latest frame timestamp.
+    idle     : BTreeSet<u32>,                   // Active sources not waited for.
+    hits     : BTreeMap<u64, Vec<DecodedHit>>,  // Held hits by time.
+    frames   : BTreeMap<u64, (u64, u32)>,       // Frame number -> timestamp, flags.
+    released : Option<u64>,                     // Last frame boundary released.
+    first    : Option<u64>,                     // Earliest frame timestamp of the run.
+    newest   : Option<u64>,                     // Latest frame timestamp of the run.
+    max_lag  : u64,
+    end_runs : Vec<RingItem>,                   // Held until all sources end.
+    in_run   : bool,                            // A source has begun the run.
+    paused   : bool,                            // A source has paused the run.
+}
+impl SourceMerger {
+    fn new(max_lag : u64) -> SourceMerger {
+        SourceMerger {
+            sources  : BTreeMap::new(),
+            idle     : BTreeSet::new(),
+            hits     : BTreeMap::new(),
+            frames   : BTreeMap::new(),
+            released : None,
+            first    : None,
+            newest   : None,
+            max_lag,
+            end_runs : Vec::new(),
+            in_run   : false,
+            paused   : false,
+        }
+    }
+    // Get ready for the next run:
+
+    fn reset(&mut self) {
+        *self = SourceMerger::new(self.max_lag);
+    }
+    fn add_source(&mut self, sid : u32) {
+        self.sources.entry(sid).or_insert(None);
+    }
+    // Returns warnings about sources that became idle or came back.
+
+    fn add_frame(
+        &mut self, sid : u32, frame : u64, timestamp : u64, flags : u32,
+        hits : Vec<DecodedHit>
+    ) -> Vec<String> {
+        let mut warnings = Vec::new();
+        let latest = self.sources.entry(sid).or_insert(None);
+        *latest = Some(latest.map_or(timestamp, |t| t.max(timestamp)));
+        if self.idle.remove(&sid) {
+            warnings.push(format!("Source {} is sending frames again; its hits may be out of order", sid));
+        }
+        self.first = Some(self.first.map_or(timestamp, |t| t.min(timestamp)));
+        self.newest = Some(self.newest.map_or(timestamp, |t| t.max(timestamp)));
+        if self.released.is_none_or(|r| frame > r) {
+            let info = self.frames.entry(frame).or_insert((timestamp, 0));
+            info.0 = info.0.min(timestamp);
+            info.1 |= flags;
+        }
+        for hit in hits {
+            self.hits.entry(hit.2).or_default().push(hit);
+        }
+
+        // Stop waiting for sources that have fallen too far behind:
+
+        let (first, newest) = (self.first.unwrap_or(0), self.newest.unwrap_or(0));
+        for (sid, latest) in &self.sources {
+            let lag = newest.saturating_sub(latest.unwrap_or(first));
+            if lag > self.max_lag && self.idle.insert(*sid) {
+                warnings.push(format!("Source {} is {} ticks behind the other sources; not waiting for it", sid, lag));
+            }
+        }
+        warnings
+    }
+    // A source ended its run; None is a run end without a source id, which ends them all.
+
+    fn end_source(&mut self, sid : Option<u32>) {
+        match sid {
+            Some(sid) => {
+                self.sources.remove(&sid);
+                self.idle.remove(&sid);
+            },
+            None => {
+                self.sources.clear();
+                self.idle.clear();
+            },
+        }
+    }
+    fn all_ended(&self) -> bool {
+        self.sources.is_empty()
+    }
+    // None if some source that is waited for has not sent a frame yet.
+
+    fn watermark(&self) -> Option<u64> {
+        self.sources.iter()
+            .filter(|(sid, _)| !self.idle.contains(sid))
+            .try_fold(u64::MAX, |w, (_, t)| t.map(|t| w.min(t)))
+            .filter(|_| !self.sources.is_empty())
+    }
+    // Release the hits and frame boundaries earlier than the watermark (all of them if None).
+    // Frame boundaries go before the hits at or after their timestamps.
+
+    fn release(&mut self, watermark : Option<u64>) -> Vec<GlomOp> {
+        let before = |t : u64| watermark.is_none_or(|w| t < w);
+        let held = match watermark {
+            Some(w) => self.hits.split_off(&w),
+            None => BTreeMap::new(),
+        };
+        let mut hits : Vec<_> = std::mem::replace(&mut self.hits, held).into_values().flatten().collect();
+
+        let mut frames : Vec<(u64, (u64, u32))> = Vec::new();
+        while let Some(entry) = self.frames.first_entry() {
+            if !before(entry.get().0) {
+                break;
+            }
+            let (frame, info) = entry.remove_entry();
+            frames.push((frame, info));
+        }
+        frames.sort_by_key(|(frame, (timestamp, _))| (*timestamp, *frame));
+
+        let mut ops = Vec::new();
+        for (frame, (timestamp, flags)) in frames {
+            let n = hits.partition_point(|h| h.2 < timestamp);
+            if n > 0 {
+                let rest = hits.split_off(n);
+                ops.push(GlomOp::Hits(hits));
+                hits = rest;
+            }
+            ops.push(GlomOp::FrameBoundary { frame, timestamp, flags });
+            self.released = Some(frame);
+        }
+        if !hits.is_empty() {
+            ops.push(GlomOp::Hits(hits));
+        }
+        ops
+    }
+}
+
+// Make the MONITORED_VARIABLES item that records the calibration.  Each string
+// is a Tcl set command e.g. set calibration(1:2) {-10 1 0}
+// giving the time offset, TOT gain and TOT offset of a channel.
+
+fn calibration_item(cal : &calibration::Calibration, begin : &RingItem) -> RingItem {
+    let strings : Vec<String> = cal.iter()
+        .map(|(id, c)| format!("set calibration({}) {{{} {} {}}}", id, c.time_offset, c.tot_gain, c.tot_offset))
+        .collect();
+    TextItem::new(
+        TextItemType::MonitoredVariables, body_header(begin),
+        0, time::SystemTime::now(), 1, body_header(begin).map(|bh| bh.source_id),
+        &strings
+    ).to_raw()
+}
+
+/// Make the MONITORED_VARIABLES item with the counts of a run.  Each string
+/// is a Tcl set command e.g. set run_statistics(events_written) 1234.
+/// The elements are events_written, events_rejected, dropped_hits and untriggered_hits
+/// (see glom::GlomStatistics).
+///
+/// ### Parameters:
+/// *  stats  - the counts.
+/// *  header - body header of the item (that of the end run item).
+pub fn run_statistics_item(stats : &glom::GlomStatistics, header : Option<BodyHeader>) -> RingItem {
+    let strings : Vec<String> = [
+        ("events_written", stats.events_written),
+        ("events_rejected", stats.events_rejected),
+        ("dropped_hits", stats.dropped_hits),
+        ("untriggered_hits", stats.untriggered_hits),
+    ].iter().map(|(name, value)| format!("set run_statistics({}) {}", name, value)).collect();
+    TextItem::new(
+        TextItemType::MonitoredVariables, header,
+        0, time::SystemTime::now(), 1, header.map(|bh| bh.source_id),
+        &strings
+    ).to_raw()
+}
+
+// Get the frame flags from the heartbeat delimiters of a frame:
+// delimiter 1 flags in the low 16 bits and delimiter 2 user flags in the high 16 bits.
+// Old layout frames (no delimiters in the item) get delimiters with no flags.
+
+fn frame_flags(frame : &mikumari_format::MikumariFrame) -> u32 {
+    frame.delimeter1.flags() as u32 | ((frame.delimeter2.user_flags() as u32) << 16)
+}
+
+/// The body header of an item.
+///
+/// ### Returns:
+/// None if the item has no body header or is too short to hold one
+/// (RingItem::get_bodyheader panics on those).
+pub fn body_header(item : &RingItem) -> Option<BodyHeader> {
+    if item.has_body_header() && item.payload().len() >= body_header_size() {
+        item.get_bodyheader()
+    } else {
+        None
+    }
+}
+
+// The run number of a begin run item; None if the item is too short to have one.
+
+fn run_number(item : &RingItem) -> Option<u32> {
+    let start = if body_header(item).is_some() { body_header_size() } else { 0 };
+    item.payload().get(start..start + size_of::<u32>())
+        .map(|b| u32::from_ne_bytes(b.try_into().unwrap()))
+}
+
+/// Where a run is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunState {
+    /// Between runs (or before the first begin run).
+    Idle,
+    Active,
+    Paused,
+}
+
+/// Applies GlomOp's to a Glom, pairing hits first if there's a Pairer.
+///
+/// It also follows the state of the run:
+///
+/// *  Hits are flushed (unpaired hits and the event being built are written)
+///    when the run is paused, when it ends and when the input ends.
+/// *  At the end of a run, the counts of the run are written in a MONITORED_VARIABLES
+///    item before the end run item (see run_statistics_item) and the Glom is reset
+///    for the next run.
+/// *  The run's report (see report::RunReport) is given to the report handler at the end
+///    of the run.
+/// *  State changes that don't make sense for the state the run is in
+///    (e.g. a pause while paused) are warned about but still done.
+/// *  A begin run in a run and the end of the input in a run end that run the same way as
+///    an end run except that no end run item is made up:  the statistics item is the
+///    last item of the run.
+pub struct EventBuilder {
+    glom      : glom::Glom,
+    pairer    : Option<glom::Pairer>,
+    state     : RunState,
+    counts    : report::RunReport,       // Frames, hits and passthrough items so far.
+    run       : Option<u32>,             // Run number of the run.
+    run_start : report::RunReport,       // Counters when the run began.
+    warn      : Box<dyn FnMut(&str)>,    // Reports out of sequence state changes, bad items...
+    on_report : Box<dyn FnMut(&report::RunReport)>,  // Gets the report of each run.
+    monitor   : Option<report::RateMonitor>,  // Makes status lines when Some.
+    status    : Box<dyn FnMut(&str)>,    // Gets the status lines.
+    strict    : bool,                    // Bad frame items are errors.
+}
+impl EventBuilder {
+    /// ### Parameters:
+    /// *  glom   - builds and writes the events.
+    /// *  pairer - if Some, leading and trailing edges are paired before they are given to the glom.
+    ///
+    /// ### Notes:
+    /// Warnings are written to stderr and run reports are ignored.
+    pub fn new(glom : glom::Glom, pairer : Option<glom::Pairer>) -> EventBuilder {
+        EventBuilder {
+            glom, pairer,
+            state     : RunState::Idle,
+            counts    : report::RunReport::new(),
+            run       : None,
+            run_start : report::RunReport::new(),
+            warn      : Box::new(|w| eprintln!("Warning: {}", w)),
+            on_report : Box::new(|_| {}),
+            monitor   : None,
+            status    : Box::new(|_| {}),
+            strict    : false,
+        }
+    }
+    /// Replace what is done with warnings.
+    pub fn set_warning_handler(&mut self, handler : Box<dyn FnMut(&str)>) {
+        self.warn = handler;
+    }
+    /// Replace what is done with the report of each run.
+    pub fn set_report_handler(&mut self, handler : Box<dyn FnMut(&report::RunReport)>) {
+        self.on_report = handler;
+    }
+    /// Make status lines with the current rates (see report::status_line).
+    ///
+    /// ### Parameters:
+    /// *  interval - about how often the status lines are made.
+    /// *  handler  - is given each status line.
+    ///
+    /// ### Notes:
+    /// Status lines are only made when ops are applied or update_monitor is called.
+    /// run_threaded calls update_monitor while waiting for input; run_single can't, so
+    /// while its input is idle there are no status lines.
+    pub fn set_monitor(&mut self, interval : time::Duration, handler : Box<dyn FnMut(&str)>) {
+        self.monitor = Some(report::RateMonitor::new(interval));
+        self.status = handler;
+    }
+    /// Make a status line if one is due.
+    pub fn update_monitor(&mut self) {
+        if self.monitor.as_ref().is_some_and(|m| m.due()) {
+            let now = self.report();
+            let backlog = self.glom.held_hits() + self.pairer.as_ref().map_or(0, |p| p.held());
+            if let Some(monitor) = &mut self.monitor {
+                (self.status)(&monitor.status(now, backlog));
+            }
+        }
+    }
+    /// Flush the output if the flush policy's interval is up (see glom::Glom::flush_if_due).
+    /// Call this while waiting for input.
+    pub fn flush_if_due(&mut self) {
+        self.glom.flush_if_due();
+    }
+    /// How long to wait for input before calling update_monitor and flush_if_due:
+    /// MONITOR_POLL or a shorter flush interval.
+    pub fn idle_poll(&self) -> time::Duration {
+        match self.glom.flush_policy() {
+            glom::FlushPolicy::Interval(every) => every.clamp(time::Duration::from_millis(1), MONITOR_POLL),
+            _ => MONITOR_POLL,
+        }
+    }
+    /// When strict is true, a frame item that could not be decoded (GlomOp::BadItem) is an
+    /// error (PipelineError::BadItem).  Otherwise (the default) it is warned about, counted
+    /// (see report::RunReport) and skipped.
+    pub fn set_strict(&mut self, strict : bool) {
+        self.strict = strict;
+    }
+    pub fn state(&self) -> RunState {
+        self.state
+    }
+    // Write out everything that's held:
+
+    fn flush(&mut self) -> glom::GlomResult<()> {
+        if let Some(p) = &mut self.pairer {
+            for hit in p.flush() {
+                self.glom.add_paired(hit)?;
+            }
+        }
+        self.glom.flush()
+    }
+    // End the run: flush, write the statistics, the end run items and reset.
+    // A run that ends without an end run item (a begin run during the run or the end
+    // of the input) is ended with no items:  its statistics item is written and
+    // nothing follows it.
+
+    fn end_run(&mut self, items : Vec<RingItem>) -> glom::GlomResult<()> {
+        self.flush()?;
+        let run = self.run_report();
+        let header = items.first().and_then(body_header);
+        self.glom.write_item(&run_statistics_item(&run.glom, header))?;
+        for item in &items {
+            self.glom.write_item(item)?;
+        }
+        (self.on_report)(&run);
+        self.glom.reset();
+        self.start_run(None);
+        self.state = RunState::Idle;
+        Ok(())
+    }
+    // Start counting a run:
+
+    fn start_run(&mut self, run : Option<u32>) {
+        self.run_start = self.report();
+        self.run = run;
+    }
+    // The counts since the run started:
+
+    fn run_report(&self) -> report::RunReport {
+        let mut run = self.report() - self.run_start.clone();
+        run.run = self.run;
+        run
+    }
+    /// Do what a GlomOp asks.
+    ///
+    /// ### Returns:
+    /// Errors from the Glom and, if strict, PipelineError::BadItem.
+    pub fn apply(&mut self, op : GlomOp) -> PipelineResult<()> {
+        match op {
+            GlomOp::SetSid(sid) => self.glom.set_sid(sid),
+            GlomOp::Write(item) => {
+                self.counts.passthrough += 1;
+                self.glom.write_item(&item)?;
+            },
+            GlomOp::FrameBoundary { frame, timestamp, flags } => {
+                self.counts.frames += 1;
+                match &mut self.pairer {
+                    Some(p) => p.add_frame_boundary(frame, timestamp, flags),
+                    None => self.glom.add_frame_boundary(frame, timestamp, flags),
+                }
+            },
+            GlomOp::Hits(hits) => {
+                for (rising, id, _, _) in &hits {
+                    self.counts.add_hit(*rising, *id);
+                }
+                if let Some(p) = &mut self.pairer {
+                    for (rising, id, time, tot) in hits {
+                        p.add_hit(rising, id, time, tot);
+                    }
+                    for hit in p.pairs() {
+                        self.glom.add_paired(hit)?;
+                    }
+                } else {
+                    for (rising, id, time, tot) in hits {
+                        self.glom.add_hit(rising, id, time, tot)?;
+                    }
+                }
+            },
+            GlomOp::BeginRun(item) => {
+                if self.state != RunState::Idle {
+                    (self.warn)("Begin run during a run; ending that run");
+                    self.end_run(Vec::new())?;
+                }
+                self.flush()?;              // Anything from before the run.
+                self.glom.reset();
+                self.start_run(run_number(&item));
+                self.glom.write_item(&item)?;
+                self.state = RunState::Active;
+            },
+            GlomOp::PauseRun(item) => {
+                if self.state != RunState::Active {
+                    (self.warn)("Pause run when the run is not active");
+                }
+                self.flush()?;
+                self.glom.write_item(&item)?;
+                self.glom.flush_sink();
+                self.state = RunState::Paused;
+            },
+            GlomOp::ResumeRun(item) => {
+                if self.state != RunState::Paused {
+                    (self.warn)("Resume run when the run is not paused");
+                }
+                self.glom.write_item(&item)?;
+                self.state = RunState::Active;
+            },
+            GlomOp::EndRun(items) => {
+                if self.state == RunState::Idle {
+                    (self.warn)("End run without a begin run");
+                }
+                self.end_run(items)?;
+            },
+            GlomOp::EndInput => {
+                if self.state != RunState::Idle {
+                    (self.warn)("The input ended without an end run");
+                    self.end_run(Vec::new())?;
+                } else {
+                    self.flush()?;
+                }
+                self.glom.flush_sink();
+            },
+            GlomOp::BadItem { offset, error } => {
+                self.counts.bad_items += 1;
+                if self.strict {
+                    return Err(PipelineError::BadItem { offset, error });
+                }
+                (self.warn)(&format!("Skipping bad frame item at offset {}: {}", offset, error));
+            },
+            GlomOp::Warning(w) => (self.warn)(&w),
+        }
+        self.update_monitor();
+        Ok(())
+    }
+    /// Get the counters of the glom.
+    pub fn statistics(&self) -> glom::GlomStatistics {
+        self.glom.statistics()
+    }
+    /// Get the counts of everything done so far (run is None).
+    pub fn report(&self) -> report::RunReport {
+        let mut report = self.counts.clone();
+        report.glom = self.glom.statistics();
+        report
+    }
+}
+
+/// Run the stages on this thread until the source has no more items.
+///
+/// ### Returns:
+/// The first error from the event builder.
+/// ### Notes:
+/// Nothing runs while the source waits for input, so unlike run_threaded there is
+/// no idle flush (FlushPolicy::Interval flushes on the next item) and no status lines
+/// from the monitor.
+pub fn run_single(
+    source : &mut dyn DataSource, decoder : &mut Decoder, builder : &mut EventBuilder
+) -> PipelineResult<()> {
+    while let Some(item) = source.read() {
+        for op in decoder.decode(item) {
+            builder.apply(op)?;
+        }
+        builder.flush_if_due();
+    }
+    for op in decoder.finish() {
+        builder.apply(op)?;
+    }
+    Ok(())
+}
+
+// What the event builder thread sends the writer thread.
+
+enum SinkOp {
+    Write(Vec<RingItem>),
+    Flush,
+}
+
+// The data sink the Glom gets in the threaded pipeline.  It hands what it's
+// given to the writer thread in batches of up to WRITE_BATCH items.  Full batches
+// are just written; a flush (the Glom's flush policy) sends the partial batch and
+// asks the writer to flush unless nothing was written since the last one.
+
+struct ChannelSink {
+    sender  : SyncSender<SinkOp>,
+    batch   : Vec<RingItem>,
+    scratch : Vec<u8>,          // Serialized item being copied.
+    unflushed : bool,           // Items written since the last SinkOp::Flush.
+}
+impl ChannelSink {
+    fn new(sender : SyncSender<SinkOp>) -> ChannelSink {
+        ChannelSink { sender, batch : Vec::with_capacity(WRITE_BATCH), scratch : Vec::new(), unflushed : false }
+    }
+    // RingItem is not Clone; copy it through its serialized form.
+
+    fn copy_item(&mut self, item : &RingItem) -> RingItem {
+        self.scratch.clear();
+        item.write_item(&mut self.scratch).expect("Writing a ring item to memory can't fail");
+        RingItem::read_item(&mut self.scratch.as_slice()).expect("Reading a ring item from memory can't fail")
+    }
+    fn send_batch(&mut self) -> Result<(), String> {
+        if self.batch.is_empty() {
+            return Ok(());
+        }
+        let batch = std::mem::replace(&mut self.batch, Vec::with_capacity(WRITE_BATCH));
+        self.sender.send(SinkOp::Write(batch))
+            .map_err(|_| String::from("The output writer has stopped"))
+    }
+}
+impl DataSink for ChannelSink {
+    fn open(&mut self, _uri: &str) -> Result<(), String> {Ok(())}
+    fn write(&mut self, item : &RingItem) -> Result<(), String> {
+        let copy = self.copy_item(item);
+        self.batch.push(copy);
+        self.unflushed = true;
+        if self.batch.len() >= WRITE_BATCH {
+            self.send_batch()?;
+        }
+        Ok(())
+    }
+    fn close(&mut self) {}
+    fn flush(&mut self) {
+        if self.send_batch().is_ok() && self.unflushed {
+            self.unflushed = false;
+            let _ = self.sender.send(SinkOp::Flush);
+        }
+    }
+}
+impl Drop for ChannelSink {
+    fn drop(&mut self) {
+        let _ = self.send_batch();
+    }
+}
+
+/// Run the stages on their own threads until the source has no more items.
+///
+/// ### Parameters:
+/// *  open_source   - makes the data source; called on the reader thread.
+/// *  open_sink     - makes the data sink; called on the writer thread.
+/// *  decoder       - decodes the ring items on the decoder thread.
+/// *  make_builder  - makes the event builder given the sink its Glom must write to;
+///    called on the event builder thread.
+/// *  depth         - number of messages each channel between the threads can hold.
+///
+/// ### Returns:
+/// Result<report::RunReport, String> - the counts of everything done (see EventBuilder::report)
+/// or a description of the first thing that failed.
+///
+/// ### Notes:
+/// A stage that fails stops.  The stages before it stop when they can't send to it and
+/// the stages after it stop when their input ends.
+pub fn run_threaded<S, K, B>(
+    open_source : S, open_sink : K, decoder : &mut Decoder, make_builder : B, depth : usize
+) -> Result<report::RunReport, String>
+where
+    S : FnOnce() -> Result<Box<dyn DataSource>, String> + Send,
+    K : FnOnce() -> Result<Box<dyn DataSink>, String> + Send,
+    B : FnOnce(Box<dyn DataSink>) -> EventBuilder + Send,
+{
+    let (item_tx, item_rx) = sync_channel::<RingItem>(depth);
+    let (op_tx, op_rx)     = sync_channel::<Vec<GlomOp>>(depth);
+    let (sink_tx, sink_rx) = sync_channel::<SinkOp>(depth);
+
+    thread::scope(|scope| {
+        let reader  = scope.spawn(move || read_items(open_source, item_tx));
+        let decode  = scope.spawn(move || decode_items(decoder, item_rx, op_tx));
+        let builder = scope.spawn(move || build_events(make_builder, op_rx, sink_tx));
+        let writer  = scope.spawn(move || write_items(open_sink, sink_rx));
+
+        let reader  = reader.join().expect("Reader thread panicked");
+        decode.join().expect("Decoder thread panicked");
+        let builder = builder.join().expect("Event builder thread panicked");
+        let writer  = writer.join().expect("Writer thread panicked");
+
+        // A writer failure is why the builder failed to write so report it first:
+
+        reader?;
+        writer?;
+        builder
+    })
+}
+
+// Reader thread:
+
+fn read_items<S>(open_source : S, items : SyncSender<RingItem>) -> Result<(), String>
+where S : FnOnce() -> Result<Box<dyn DataSource>, String>
+{
+    let mut source = open_source()?;
+    while let Some(item) = source.read() {
+        if items.send(item).is_err() {
+            break;                     // Later stages stopped.
+        }
+    }
+    Ok(())
+}
+// Decoder thread:
+
+fn decode_items(decoder : &mut Decoder, items : Receiver<RingItem>, ops : SyncSender<Vec<GlomOp>>) {
+    for item in items {
+        if ops.send(decoder.decode(item)).is_err() {
+            return;
+        }
+    }
+    let _ = ops.send(decoder.finish());
+}
+// Event builder thread:
+
+fn build_events<B>(make_builder : B, ops : Receiver<Vec<GlomOp>>, sink : SyncSender<SinkOp>)
+    -> Result<report::RunReport, String>
+where B : FnOnce(Box<dyn DataSink>) -> EventBuilder
+{
+    let mut builder = make_builder(Box::new(ChannelSink::new(sink)));
+    let poll = builder.idle_poll();
+    loop {
+        match ops.recv_timeout(poll) {
+            Ok(batch) => {
+                for op in batch {
+                    builder.apply(op).map_err(|e| e.to_string())?;
+                }
+            },
+            Err(RecvTimeoutError::Timeout) => {       // No input for a while.
+                builder.update_monitor();
+                builder.flush_if_due();
+            },
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+    Ok(builder.report())
+}
+// Writer thread:
+
+fn write_items<K>(open_sink : K, ops : Receiver<SinkOp>) -> Result<(), String>
+where K : FnOnce() -> Result<Box<dyn DataSink>, String>
+{
+    let mut sink = open_sink()?;
+    for op in ops {
+        match op {
+            SinkOp::Write(items) => {
+                for item in &items {
+                    sink.write(item)?;
+                }
+            },
+            SinkOp::Flush => sink.flush(),
+        }
+    }
+    sink.flush();
+    Ok(())
+}
+
+#[cfg(test)]
+mod pipeline_tests {
+    use super::*;
+    use rust_ringitem_format::{MONITORED_VARIABLES, PHYSICS_EVENT};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    // A data source that gives out items from a vector.
+
+    struct VecSource {
+        items : std::vec::IntoIter<RingItem>,
+    }
+    impl DataSource for VecSource {
+        fn open(&mut self, _uri: &str) -> Result<(), String> {Ok(())}
+        fn read(&mut self) -> Option<RingItem> {
+            self.items.next()
+        }
+        fn close(&mut self) {}
+    }
+    // A data sink that saves the serialized items it's given.
+
+    type Saved = Arc<Mutex<Vec<Vec<u8>>>>;
+
+    struct VecSink {
+        items : Saved,
+    }
+    impl DataSink for VecSink {
+        fn open(&mut self, _uri: &str) -> Result<(), String> {Ok(())}
+        fn write(&mut self, item : &RingItem) -> Result<(), String> {
+            let mut bytes = Vec::new();
+            item.write_item(&mut bytes).unwrap();
+            self.items.lock().unwrap().push(bytes);
+            Ok(())
+        }
+        fn close(&mut self) {}
+    }
+    struct FailingSink {}
+    impl DataSink for FailingSink {
+        fn open(&mut self, _uri: &str) -> Result<(), String> {Ok(())}
+        fn write(&mut self, _item : &RingItem) -> Result<(), String> {
+            Err(String::from("sink failed"))
+        }
+        fn close(&mut self) {}
+    }
+
+    // Frame items with hits on a few channels, a begin and end run around them:
+
+    fn run_items() -> Vec<RingItem> {
+        let mut items = vec![RingItem::new_with_body_header(BEGIN_RUN, 0, 2, 1)];
+        for frame in 0..20u64 {
+            let mut item = RingItem::new_with_body_header(
+                mikumari_format::MIKUMARI_FRAME_ITEM_TYPE, frame * 1000, 2, 0
+            );
+            item.add(frame + 100);
+            item.add(mikumari_format::Delimeter1::new(0, frame as u32).get());
+            for i in 0..10u32 {
+                let time = (i * 97 + frame as u32 * 13) % 1000;
+                if i % 3 == 0 {
+                    item.add(mikumari_format::HRTDCTrailing::new((i % 4) as u8, 5, time).get());
+                } else {
+                    item.add(mikumari_format::HRTDCLeading::new((i % 4) as u8, 5, time).get());
+                }
+            }
+            items.push(item);
+        }
+        items.push(RingItem::new_with_body_header(END_RUN, 0, 2, 2));
+        items
+    }
+    fn builder(sink : Box<dyn DataSink>, pair : bool) -> EventBuilder {
+        let mut glom = glom::Glom::new(sink, 0, 50);
+        glom.set_out_of_order_policy(glom::OutOfOrderPolicy::Drop);
+        EventBuilder::new(glom, if pair { Some(glom::Pairer::new(30)) } else { None })
+    }
+    fn single(pair : bool) -> Vec<Vec<u8>> {
+        let saved = Arc::new(Mutex::new(Vec::new()));
+        let mut source = VecSource { items : run_items().into_iter() };
+        let mut b = builder(Box::new(VecSink { items : saved.clone() }), pair);
+        run_single(&mut source, &mut Decoder::new(), &mut b).unwrap();
+        drop(b);
+        Arc::try_unwrap(saved).unwrap().into_inner().unwrap()
+    }
+    fn threaded(pair : bool, depth : usize) -> Vec<Vec<u8>> {
+        let saved = Arc::new(Mutex::new(Vec::new()));
+        let sink_items = saved.clone();
+        run_threaded(
+            || Ok(Box::new(VecSource { items : run_items().into_iter() }) as Box<dyn DataSource>),
+            move || Ok(Box::new(VecSink { items : sink_items }) as Box<dyn DataSink>),
+            &mut Decoder::new(),
+            |sink| builder(sink, pair),
+            depth
+        ).unwrap();
+        Arc::try_unwrap(saved).unwrap().into_inner().unwrap()
+    }
+
+    #[test]
+    fn decode_1() {
+        // State changes become run ops, others pass through:
+
+        let mut d = Decoder::new();
+        assert_eq!(describe(&d.decode(RingItem::new_with_body_header(BEGIN_RUN, 0, 7, 1))), vec!["sid 7", "begin 1"]);
+        assert_eq!(describe(&d.decode(RingItem::new(PAUSE_RUN))), vec!["pause 3"]);
+        assert_eq!(describe(&d.decode(RingItem::new(RESUME_RUN))), vec!["resume 4"]);
+        assert_eq!(describe(&d.decode(RingItem::new(END_RUN))), vec!["end run 2"]);
+        assert_eq!(describe(&d.decode(RingItem::new(20))), vec!["write 20"]);
+        assert_eq!(describe(&d.finish()), vec!["end input"]);
+    }
+    #[test]
+    fn decode_2() {
+        // Frame hits are time ordered and get the frame's source id (old layout, no delimiters):
+
+        let mut item = RingItem::new_with_body_header(mikumari_format::MIKUMARI_FRAME_ITEM_TYPE, 1000, 3, 0);
+        item.add(5u64);
+        item.add(mikumari_format::HRTDCLeading::new(1, 10, 200).get());
+        item.add(mikumari_format::HRTDCTrailing::new(2, 20, 100).get());
+        let ops = Decoder::new().decode(item);
+        assert_eq!(ops.len(), 3);
+        assert!(matches!(ops[1], GlomOp::FrameBoundary { frame : 5, timestamp : 1000, flags : 0 }));
+        match &ops[2] {
+            GlomOp::Hits(hits) => assert_eq!(hits, &vec![
+                (false, glom::ChannelId::new(3, 2), 1100, 20),
+                (true, glom::ChannelId::new(3, 1), 1200, 10),
+            ]),
+            _ => panic!("Expected hits"),
+        }
+    }
+    #[test]
+    fn decode_3() {
+        // Selecting sources, the frame type, the output source id and dropping other items:
+
+        let mut d = Decoder::new();
+        d.set_frame_type(60);
+        d.set_sources(Some(vec![2, 3]));
+        d.set_output_sid(Some(9));
+        assert_eq!(describe(&d.decode(frame_item(2, 1, 0, 0, &[(1, 5)]))), vec!["write 51"]);
+        let mut item = RingItem::new_with_body_header(60, 100, 2, 0);
+        item.add(1u64);
+        item.add(mikumari_format::HRTDCLeading::new(1, 10, 5).get());
+        assert_eq!(describe(&d.decode(item)), vec!["sid 9", "frame 1 100 0", "hits 2:1@105"]);
+        let mut item = RingItem::new_with_body_header(60, 100, 4, 0);
+        item.add(1u64);
+        assert_eq!(describe(&d.decode(item)), vec!["write 60"]);
+        assert_eq!(describe(&d.decode(RingItem::new_with_body_header(BEGIN_RUN, 0, 7, 1))), vec!["sid 9", "begin 1"]);
+
+        d.set_drop_others(true);
+        assert!(d.decode(frame_item(2, 1, 0, 0, &[(1, 5)])).is_empty());
+        assert!(d.decode(RingItem::new(20)).is_empty());
+        assert_eq!(describe(&d.decode(RingItem::new(END_RUN))), vec!["end run 2"]);
+    }
+    #[test]
+    fn decode_4() {
+        // The current layout decodes like the old one with the flags of both delimiters:
+
+        let hits = [
+            mikumari_format::HRTDCLeading::new(1, 10, 200).get(), mikumari_format::HRTDCTrailing::new(2, 20, 100).get()
+        ];
+        let mut old = RingItem::new_with_body_header(mikumari_format::MIKUMARI_FRAME_ITEM_TYPE, 1000, 3, 0);
+        old.add(5u64);
+        let mut new = RingItem::new_with_body_header(mikumari_format::MIKUMARI_FRAME_ITEM_TYPE, 1000, 3, 0);
+        new.add(5u64);
+        new.add(mikumari_format::Delimeter1::new(0, 5).get() | (0x40 << 40));
+        new.add(mikumari_format::Delimeter2::new(16).get() | (0x3 << 40));
+        for hit in hits {
+            old.add(hit);
+            new.add(hit);
+        }
+        let mut d = Decoder::new();
+        assert_eq!(describe(&d.decode(old)), vec!["sid 3", "frame 5 1000 0", "hits 3:2@1100,3:1@1200"]);
+        assert_eq!(describe(&d.decode(new)), vec!["sid 3", "frame 5 1000 30040", "hits 3:2@1100,3:1@1200"]);
+
+        // A delimiter that doesn't start the frame is a bad item:
+
+        let mut bad = RingItem::new_with_body_header(mikumari_format::MIKUMARI_FRAME_ITEM_TYPE, 1000, 3, 0);
+        bad.add(5u64);
+        bad.add(hits[0]);
+        bad.add(mikumari_format::Delimeter1::new(0, 5).get());
+        assert!(matches!(d.decode(bad).as_slice(), [GlomOp::BadItem { .. }]));
+    }
+    #[test]
+    fn threaded_1() {
+        // Same output threaded or not:
+
+        let expected = single(false);
+        assert!(expected.len() > 2);
+        assert_eq!(threaded(false, DEFAULT_QUEUE_DEPTH), expected);
+        assert_eq!(threaded(false, 1), expected);
+    }
+    #[test]
+    fn threaded_2() {
+        let expected = single(true);
+        assert_eq!(threaded(true, 2), expected);
+    }
+    #[test]
+    fn threaded_3() {
+        // Sink failures are reported:
+
+        let result = run_threaded(
+            || Ok(Box::new(VecSource { items : run_items().into_iter() }) as Box<dyn DataSource>),
+            || Ok(Box::new(FailingSink {}) as Box<dyn DataSink>),
+            &mut Decoder::new(),
+            |sink| builder(sink, false),
+            1
+        );
+        assert_eq!(result, Err(String::from("sink failed")));
+    }
+    #[test]
+    fn threaded_4() {
+        // As are failures to open the source:
+
+        let result = run_threaded(
+            || Err(String::from("no source")),
+            || Ok(Box::new(FailingSink {}) as Box<dyn DataSink>),
+            &mut Decoder::new(),
+            |sink| builder(sink, false),
+            1
+        );
+        assert_eq!(result, Err(String::from("no source")));
+    }
+    // A source that waits a while before its input ends and a sink that counts
+    // the flushes that happen before then:
+
+    struct IdleSource {
+        items : std::vec::IntoIter<RingItem>,
+        ended : Arc<AtomicBool>,
+    }
+    impl DataSource for IdleSource {
+        fn open(&mut self, _uri: &str) -> Result<(), String> {Ok(())}
+        fn read(&mut self) -> Option<RingItem> {
+            let item = self.items.next();
+            if item.is_none() {
+                thread::sleep(time::Duration::from_millis(300));
+                self.ended.store(true, Ordering::SeqCst);
+            }
+            item
+        }
+        fn close(&mut self) {}
+    }
+    struct IdleSink {
+        ended        : Arc<AtomicBool>,
+        idle_flushes : Arc<AtomicUsize>,
+    }
+    impl DataSink for IdleSink {
+        fn open(&mut self, _uri: &str) -> Result<(), String> {Ok(())}
+        fn write(&mut self, _item : &RingItem) -> Result<(), String> {Ok(())}
+        fn close(&mut self) {}
+        fn flush(&mut self) {
+            if !self.ended.load(Ordering::SeqCst) {
+                self.idle_flushes.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+    }
+    #[test]
+    fn threaded_5() {
+        // An interval flush policy flushes while waiting for input:
+
+        let ended = Arc::new(AtomicBool::new(false));
+        let idle_flushes = Arc::new(AtomicUsize::new(0));
+        let (source_ended, sink_ended, flushes) = (ended.clone(), ended.clone(), idle_flushes.clone());
+        run_threaded(
+            move || Ok(Box::new(IdleSource { items : vec![RingItem::new(PHYSICS_EVENT)].into_iter(), ended : source_ended }) as Box<dyn DataSource>),
+            move || Ok(Box::new(IdleSink { ended : sink_ended, idle_flushes : flushes }) as Box<dyn DataSink>),
+            &mut Decoder::new(),
+            |sink| {
+                let mut glom = glom::Glom::new(sink, 0, 50);
+                glom.set_flush_policy(glom::FlushPolicy::Interval(time::Duration::from_millis(20)));
+                EventBuilder::new(glom, None)
+            },
+            1
+        ).unwrap();
+        assert!(ended.load(Ordering::SeqCst));
+        assert_eq!(idle_flushes.load(Ordering::SeqCst), 1);
+    }
+    #[test]
+    fn channel_sink_1() {
+        // Full batches are written without a flush and flushes with nothing new aren't sent:
+
+        let (sender, receiver) = sync_channel(WRITE_BATCH + 4);
+        let mut sink = ChannelSink::new(sender);
+        for _ in 0..WRITE_BATCH + 1 {
+            sink.write(&RingItem::new(PHYSICS_EVENT)).unwrap();
+        }
+        sink.flush();
+        sink.flush();
+        drop(sink);
+        let ops : Vec<String> = receiver.iter().map(|op| match op {
+            SinkOp::Write(items) => format!("write {}", items.len()),
+            SinkOp::Flush => String::from("flush"),
+        }).collect();
+        assert_eq!(ops, vec![format!("write {}", WRITE_BATCH), String::from("write 1"), String::from("flush")]);
+    }
+    #[test]
+    fn idle_poll_1() {
+        let mut b = builder(Box::new(FailingSink {}), false);
+        assert_eq!(b.idle_poll(), MONITOR_POLL);
+        b.glom.set_flush_policy(glom::FlushPolicy::Interval(time::Duration::from_millis(20)));
+        assert_eq!(b.idle_poll(), time::Duration::from_millis(20));
+        b.glom.set_flush_policy(glom::FlushPolicy::Interval(time::Duration::from_secs(60)));
+        assert_eq!(b.idle_poll(), MONITOR_POLL);
+    }
+
+    // A frame item with leading edges at the given channels/times relative to the frame:
+
+    fn frame_item(sid : u32, frame : u64, timestamp : u64, flags : u16, hits : &[(u8, u32)]) -> RingItem {
+        let mut item = RingItem::new_with_body_header(mikumari_format::MIKUMARI_FRAME_ITEM_TYPE, timestamp, sid, 0);
+        item.add(frame);
+        item.add(mikumari_format::Delimeter1::new(0, frame as u32).get() | ((flags as u64) << 40));
+        for (chan, time) in hits {
+            item.add(mikumari_format::HRTDCLeading::new(*chan, 5, *time).get());
+        }
+        item
+    }
+    // Summarize ops as strings to compare them easily:
+
+    fn describe(ops : &[GlomOp]) -> Vec<String> {
+        ops.iter().map(|op| match op {
+            GlomOp::SetSid(sid) => format!("sid {}", sid),
+            GlomOp::Write(item) => format!("write {}", item.type_id()),
+            GlomOp::FrameBoundary { frame, timestamp, flags } => format!("frame {} {} {:x}", frame, timestamp, flags),
+            GlomOp::Hits(hits) => format!("hits {}", hits.iter()
+                .map(|(_, id, time, _)| format!("{}@{}", id, time))
+                .collect::<Vec<_>>().join(",")),
+            GlomOp::BeginRun(item) => format!("begin {}", item.type_id()),
+            GlomOp::PauseRun(item) => format!("pause {}", item.type_id()),
+            GlomOp::ResumeRun(item) => format!("resume {}", item.type_id()),
+            GlomOp::EndRun(items) => format!("end run {}", items.iter()
+                .map(|i| i.type_id().to_string()).collect::<Vec<_>>().join(",")),
+            GlomOp::EndInput => String::from("end input"),
+            GlomOp::BadItem { offset, .. } => format!("bad item {}", offset),
+            GlomOp::Warning(w) => format!("warning {}", w),
+        }).collect()
+    }
+    fn multi_decoder() -> Decoder {
+        let mut d = Decoder::new();
+        d.set_multi_source(true);
+        d
+    }
+    // A ring item as it might be read from a damaged file:
+
+    fn raw_item(type_id : u32, body_header_size : u32, payload : &[u8]) -> RingItem {
+        let mut bytes = Vec::new();
+        bytes.extend(((3 * size_of::<u32>() + payload.len()) as u32).to_ne_bytes());
+        bytes.extend(type_id.to_ne_bytes());
+        bytes.extend(body_header_size.to_ne_bytes());
+        bytes.extend(payload);
+        RingItem::read_item(&mut bytes.as_slice()).unwrap()
+    }
+
+    #[test]
+    fn bad_item_1() {
+        // Frame items that can't be decoded are reported with their offsets in the input:
+
+        let mut d = Decoder::new();
+        let good = frame_item(1, 1, 0, 0, &[(1, 5)]);
+        let mut offset = good.size() as u64;
+        assert_eq!(describe(&d.decode(good)), vec!["sid 1", "frame 1 0 0", "hits 1:1@5"]);
+
+        let mut no_header = RingItem::new(mikumari_format::MIKUMARI_FRAME_ITEM_TYPE);
+        no_header.add(1u64);
+        let size = no_header.size() as u64;
+        match d.decode(no_header).as_slice() {
+            [GlomOp::BadItem { offset : o, error }] => {
+                assert_eq!(*o, offset);
+                assert_eq!(error, "Frame item has no body header");
+            },
+            _ => panic!("Expected a bad item"),
+        }
+        offset += size;
+
+        let mut partial = RingItem::new_with_body_header(mikumari_format::MIKUMARI_FRAME_ITEM_TYPE, 0, 1, 0);
+        partial.add(1u64);
+        partial.add(7u32);
+        let size = partial.size() as u64;
+        match d.decode(partial).as_slice() {
+            [GlomOp::BadItem { offset : o, error }] => {
+                assert_eq!(*o, offset);
+                assert_eq!(error, "Frame item body of 12 bytes is not a whole number of 64 bit words");
+            },
+            _ => panic!("Expected a bad item"),
+        }
+        offset += size;
+
+        let short = RingItem::new_with_body_header(mikumari_format::MIKUMARI_FRAME_ITEM_TYPE, 0, 1, 0);
+        let size = short.size() as u64;
+        assert_eq!(describe(&d.decode(short)), vec![format!("bad item {}", offset)]);
+        offset += size;
+
+        // A body header that's cut short:
+
+        let truncated = raw_item(mikumari_format::MIKUMARI_FRAME_ITEM_TYPE, 20, &[0; 8]);
+        assert_eq!(describe(&d.decode(truncated)), vec![format!("bad item {}", offset)]);
+    }
+    #[test]
+    fn bad_item_2() {
+        // Cut short body headers don't stop decoding in either mode:
+
+        for mut d in [Decoder::new(), multi_decoder()] {
+            assert_eq!(describe(&d.decode(raw_item(BEGIN_RUN, 20, &[0; 4]))), vec!["begin 1"]);
+            assert_eq!(describe(&d.decode(raw_item(mikumari_format::MIKUMARI_FRAME_ITEM_TYPE, 20, &[0; 4]))), vec!["bad item 16"]);
+            assert_eq!(describe(&d.decode(raw_item(20, 20, &[]))), vec!["write 20"]);
+        }
+        let mut d = Decoder::new();
+        d.set_sources(Some(vec![1]));
+        assert_eq!(describe(&d.decode(raw_item(mikumari_format::MIKUMARI_FRAME_ITEM_TYPE, 20, &[0; 4]))), vec!["write 51"]);
+    }
+    #[test]
+    fn bad_item_3() {
+        // The builder counts, warns about and skips bad items or, if strict, stops:
+
+        let (mut b, _saved, warnings) = watched_builder();
+        b.apply(GlomOp::BadItem { offset : 96, error : String::from("broken") }).unwrap();
+        assert_eq!(warnings.borrow().as_slice(), ["Skipping bad frame item at offset 96: broken"]);
+        assert_eq!(b.report().bad_items, 1);
+
+        b.set_strict(true);
+        assert_eq!(
+            b.apply(GlomOp::BadItem { offset : 200, error : String::from("broken") }),
+            Err(PipelineError::BadItem { offset : 200, error : String::from("broken") })
+        );
+        assert_eq!(b.report().bad_items, 2);
+        assert_eq!(warnings.borrow().len(), 1);
+        assert_eq!(
+            PipelineError::BadItem { offset : 200, error : String::from("broken") }.to_string(),
+            "Bad frame item at offset 200: broken"
+        );
+        assert_eq!(
+            PipelineError::from(glom::GlomError::SinkWrite(String::from("full"))).to_string(),
+            "Unable to write a ring item: full"
+        );
+    }
+    #[test]
+    fn bad_item_4() {
+        // Through the pipeline: skipped and counted, or the strict error has the item's offset:
+
+        let with_bad = || {
+            let mut items = run_items();
+            let mut bad = RingItem::new_with_body_header(mikumari_format::MIKUMARI_FRAME_ITEM_TYPE, 0, 2, 0);
+            bad.add(1u64);
+            bad.add(7u8);
+            items.insert(5, bad);
+            items
+        };
+        let offset : u64 = with_bad()[..5].iter().map(|i| i.size() as u64).sum();
+
+        let saved = Arc::new(Mutex::new(Vec::new()));
+        let mut source = VecSource { items : with_bad().into_iter() };
+        let mut b = builder(Box::new(VecSink { items : saved.clone() }), false);
+        b.set_warning_handler(Box::new(|_| {}));
+        run_single(&mut source, &mut Decoder::new(), &mut b).unwrap();
+        assert_eq!(b.report().bad_items, 1);
+        assert_eq!(b.report().frames, 20);
+
+        let result = run_threaded(
+            || Ok(Box::new(VecSource { items : with_bad().into_iter() }) as Box<dyn DataSource>),
+            || Ok(Box::new(VecSink { items : Arc::new(Mutex::new(Vec::new())) }) as Box<dyn DataSink>),
+            &mut Decoder::new(),
+            |sink| {
+                let mut b = builder(sink, false);
+                b.set_strict(true);
+                b
+            },
+            1
+        );
+        assert_eq!(
+            result,
+            Err(format!("Bad frame item at offset {}: Frame item body of 9 bytes is not a whole number of 64 bit words", offset))
+        );
+    }
+    #[test]
+    fn decode_random_1() {
+        // Decoding random items doesn't panic (the fuzz targets in fuzz/ go further):
+
+        use rand::RngExt;
+        let mut r = rand::rng();
+        let mut single = Decoder::new();
+        let mut multi = multi_decoder();
+        assert_eq!(
+            describe(&single.decode(frame_item(1, 1, u64::MAX, 0, &[(1, 5)])))[2],
+            format!("hits 1:1@{}", u64::MAX)                  // Hit times saturate.
+        );
+        let types = [mikumari_format::MIKUMARI_FRAME_ITEM_TYPE, BEGIN_RUN, END_RUN, PAUSE_RUN, RESUME_RUN, 20];
+        for _ in 0..5000 {
+            let type_id = types[r.random_range(0..types.len())];
+            let header = [0u32, 4, 20][r.random_range(0..3)];
+            let payload : Vec<u8> = (0..r.random_range(0..64usize)).map(|_| r.random()).collect();
+            single.decode(raw_item(type_id, header, &payload));
+            multi.decode(raw_item(type_id, header, &payload));
+        }
+        single.finish();
+        multi.finish();
+    }
+
+    #[test]
+    fn merge_1() {
+        // Hits are held until all sources are past them:
+
+        let mut d = multi_decoder();
+        assert_eq!(describe(&d.decode(RingItem::new_with_body_header(BEGIN_RUN, 0, 1, 1))), vec!["sid 1", "begin 1"]);
+        assert_eq!(describe(&d.decode(RingItem::new_with_body_header(BEGIN_RUN, 0, 2, 1))), vec!["write 1"]);
+        assert!(d.decode(frame_item(1, 7, 0, 0, &[(1, 500), (1, 10)])).is_empty());
+        assert!(d.decode(frame_item(2, 7, 0, 0, &[(3, 20)])).is_empty());
+        assert!(d.decode(frame_item(1, 8, 1000, 0, &[(1, 10)])).is_empty());
+        assert_eq!(describe(&d.decode(frame_item(2, 8, 1000, 0, &[(3, 5)]))), vec![
+            "frame 7 0 0", "hits 1:1@10,2:3@20,1:1@500"
+        ]);
+
+        // End runs are held until all sources have ended:
+
+        assert!(d.decode(RingItem::new_with_body_header(END_RUN, 0, 1, 2)).is_empty());
+        assert_eq!(describe(&d.decode(RingItem::new_with_body_header(END_RUN, 0, 2, 2))), vec![
+            "frame 8 1000 0", "hits 2:3@1005,1:1@1010", "end run 2,2"
+        ]);
+        assert_eq!(describe(&d.finish()), vec!["end input"]);
+    }
+    #[test]
+    fn merge_2() {
+        // One boundary per frame number with the flags of all sources; sources
+        // without begin runs are found from their frames:
+
+        let mut d = multi_decoder();
+        assert!(d.decode(frame_item(1, 7, 0, 0x1, &[(1, 10)])).is_empty());
+        assert_eq!(describe(&d.decode(frame_item(1, 8, 1000, 0, &[]))), vec!["frame 7 0 1", "hits 1:1@10"]);
+        assert!(d.decode(frame_item(2, 8, 1000, 0x4, &[(2, 1)])).is_empty());
+        assert!(d.decode(frame_item(1, 9, 2000, 0, &[])).is_empty());
+        assert_eq!(describe(&d.decode(frame_item(2, 9, 2000, 0, &[]))), vec!["frame 8 1000 4", "hits 2:2@1001"]);
+    }
+    #[test]
+    fn merge_3() {
+        // finish releases what's held:
+
+        let mut d = multi_decoder();
+        assert!(d.decode(RingItem::new_with_body_header(BEGIN_RUN, 0, 2, 1)).len() == 2);
+        assert!(d.decode(frame_item(1, 7, 0, 0, &[(1, 10)])).is_empty());
+        assert_eq!(describe(&d.finish()), vec!["frame 7 0 0", "hits 1:1@10", "end input"]);
+        assert_eq!(describe(&d.finish()), vec!["end input"]);
+    }
+    // An event builder whose written item types and warnings can be looked at:
+
+    fn watched_builder() -> (EventBuilder, Saved, Rc<RefCell<Vec<String>>>) {
+        let saved = Arc::new(Mutex::new(Vec::new()));
+        let warnings = Rc::new(RefCell::new(Vec::new()));
+        let mut b = builder(Box::new(VecSink { items : saved.clone() }), false);
+        let w = warnings.clone();
+        b.set_warning_handler(Box::new(move |s| w.borrow_mut().push(String::from(s))));
+        (b, saved, warnings)
+    }
+    fn types(saved : &Saved) -> Vec<u32> {
+        saved.lock().unwrap().iter().map(|i| u32::from_ne_bytes(i[4..8].try_into().unwrap())).collect()
+    }
+    fn hits(times : &[u64]) -> GlomOp {
+        GlomOp::Hits(times.iter().map(|t| (true, glom::ChannelId::new(0, 1), *t, 0)).collect())
+    }
+
+    #[test]
+    fn run_state_1() {
+        // Pause flushes, end run writes the statistics and resets:
+
+        let (mut b, saved, warnings) = watched_builder();
+        b.apply(GlomOp::BeginRun(RingItem::new(BEGIN_RUN))).unwrap();
+        assert_eq!(b.state(), RunState::Active);
+        b.apply(hits(&[1000, 1010])).unwrap();
+        b.apply(GlomOp::PauseRun(RingItem::new(PAUSE_RUN))).unwrap();
+        assert_eq!(b.state(), RunState::Paused);
+        assert_eq!(types(&saved), vec![BEGIN_RUN, PHYSICS_EVENT, PAUSE_RUN]);
+        b.apply(GlomOp::ResumeRun(RingItem::new(RESUME_RUN))).unwrap();
+        b.apply(hits(&[2000])).unwrap();
+        b.apply(GlomOp::EndRun(vec![RingItem::new(END_RUN)])).unwrap();
+        assert_eq!(b.state(), RunState::Idle);
+        assert_eq!(types(&saved), vec![
+            BEGIN_RUN, PHYSICS_EVENT, PAUSE_RUN, RESUME_RUN, PHYSICS_EVENT, MONITORED_VARIABLES, END_RUN
+        ]);
+
+        // The next run's times start over:
+
+        b.apply(GlomOp::BeginRun(RingItem::new(BEGIN_RUN))).unwrap();
+        b.apply(hits(&[10])).unwrap();
+        b.apply(GlomOp::EndRun(vec![RingItem::new(END_RUN)])).unwrap();
+        assert_eq!(b.statistics().events_written, 3);
+        assert_eq!(b.statistics().dropped_hits, 0);
+        assert!(warnings.borrow().is_empty());
+    }
+    #[test]
+    fn run_state_2() {
+        // Out of sequence state changes are warned about:
+
+        let (mut b, saved, warnings) = watched_builder();
+        b.apply(GlomOp::PauseRun(RingItem::new(PAUSE_RUN))).unwrap();
+        b.apply(GlomOp::ResumeRun(RingItem::new(RESUME_RUN))).unwrap();
+        b.apply(GlomOp::ResumeRun(RingItem::new(RESUME_RUN))).unwrap();
+        b.apply(GlomOp::EndRun(vec![RingItem::new(END_RUN)])).unwrap();
+        b.apply(GlomOp::EndRun(vec![RingItem::new(END_RUN)])).unwrap();
+        b.apply(GlomOp::BeginRun(RingItem::new(BEGIN_RUN))).unwrap();
+        b.apply(hits(&[100])).unwrap();
+        b.apply(GlomOp::BeginRun(RingItem::new(BEGIN_RUN))).unwrap();
+        b.apply(GlomOp::EndInput).unwrap();
+        assert_eq!(*warnings.borrow(), vec![
+            "Pause run when the run is not active",
+            "Resume run when the run is not paused",
+            "End run without a begin run",
+            "Begin run during a run; ending that run",
+            "The input ended without an end run",
+        ]);
+        // The begin run in a run and the end of the input in a run wrote the event
+        // (if any) and the statistics but no end run:
+
+        assert_eq!(&types(&saved)[7..], &[BEGIN_RUN, PHYSICS_EVENT, MONITORED_VARIABLES, BEGIN_RUN, MONITORED_VARIABLES]);
+    }
+    #[test]
+    fn run_state_3() {
+        // The end of input writes the last event:
+
+        let (mut b, saved, _warnings) = watched_builder();
+        b.apply(hits(&[100, 120])).unwrap();
+        assert!(types(&saved).is_empty());
+        b.apply(GlomOp::EndInput).unwrap();
+        assert_eq!(types(&saved), vec![PHYSICS_EVENT]);
+    }
+    #[test]
+    fn run_state_4() {
+        // Run statistics are for the run:
+
+        let stats = glom::GlomStatistics { events_written : 3, events_rejected : 2, dropped_hits : 1, untriggered_hits : 0, ..Default::default() };
+        let item = run_statistics_item(&stats, None);
+        assert_eq!(item.type_id(), MONITORED_VARIABLES);
+        let text = String::from_utf8_lossy(item.payload());
+        assert!(text.contains("set run_statistics(events_written) 3"));
+        assert!(text.contains("set run_statistics(dropped_hits) 1"));
+    }
+    #[test]
+    fn merge_5() {
+        // Only the first source's pause/resume change the run state:
+
+        let mut d = multi_decoder();
+        d.decode(RingItem::new_with_body_header(BEGIN_RUN, 0, 1, 1));
+        assert_eq!(describe(&d.decode(RingItem::new_with_body_header(PAUSE_RUN, 0, 1, 0))), vec!["pause 3"]);
+        assert_eq!(describe(&d.decode(RingItem::new_with_body_header(PAUSE_RUN, 0, 2, 0))), vec!["write 3"]);
+        assert_eq!(describe(&d.decode(RingItem::new_with_body_header(RESUME_RUN, 0, 1, 0))), vec!["resume 4"]);
+        assert_eq!(describe(&d.decode(RingItem::new_with_body_header(RESUME_RUN, 0, 2, 0))), vec!["write 4"]);
+    }
+    #[test]
+    fn merge_6() {
+        // Unselected sources are not waited for and their frames aren't merged:
+
+        let mut d = multi_decoder();
+        d.set_sources(Some(vec![1]));
+        d.set_drop_others(true);
+        d.decode(RingItem::new_with_body_header(BEGIN_RUN, 0, 1, 1));
+        assert_eq!(describe(&d.decode(RingItem::new_with_body_header(BEGIN_RUN, 0, 2, 1))), vec!["write 1"]);
+        assert!(d.decode(frame_item(1, 7, 0, 0, &[(1, 10)])).is_empty());
+        assert!(d.decode(frame_item(2, 7, 0, 0, &[(1, 20)])).is_empty());
+        assert_eq!(describe(&d.decode(frame_item(1, 8, 1000, 0, &[]))), vec!["frame 7 0 0", "hits 1:1@10"]);
+    }
+    #[test]
+    fn merge_7() {
+        // A source that falls too far behind is not waited for until it sends a frame again:
+
+        let mut d = multi_decoder();
+        d.set_max_source_lag(1500);
+        d.decode(RingItem::new_with_body_header(BEGIN_RUN, 0, 1, 1));
+        d.decode(RingItem::new_with_body_header(BEGIN_RUN, 0, 2, 1));
+        assert!(d.decode(frame_item(1, 7, 0, 0, &[(1, 10)])).is_empty());
+        assert!(d.decode(frame_item(1, 8, 1000, 0, &[])).is_empty());
+        assert_eq!(describe(&d.decode(frame_item(1, 9, 2000, 0, &[(1, 5)]))), vec![
+            "warning Source 2 is 2000 ticks behind the other sources; not waiting for it",
+            "frame 7 0 0", "hits 1:1@10", "frame 8 1000 0",
+        ]);
+        assert_eq!(describe(&d.decode(frame_item(1, 10, 3000, 0, &[]))), vec!["frame 9 2000 0", "hits 1:1@2005"]);
+        assert_eq!(describe(&d.decode(frame_item(2, 10, 3000, 0, &[(3, 1)]))), vec![
+            "warning Source 2 is sending frames again; its hits may be out of order",
+        ]);
+        assert!(d.decode(frame_item(2, 11, 4000, 0, &[])).is_empty());
+        assert_eq!(describe(&d.decode(frame_item(1, 11, 4000, 0, &[]))), vec![
+            "frame 10 3000 0", "hits 2:3@3001",
+        ]);
+    }
+    #[test]
+    fn merge_8() {
+        // Once every source that's left is idle, nothing is held:
+
+        let mut d = multi_decoder();
+        d.set_max_source_lag(500);
+        d.decode(RingItem::new_with_body_header(BEGIN_RUN, 0, 1, 1));
+        d.decode(RingItem::new_with_body_header(BEGIN_RUN, 0, 2, 1));
+        assert!(d.decode(frame_item(1, 1, 0, 0, &[(1, 10)])).is_empty());
+        assert_eq!(describe(&d.decode(frame_item(2, 2, 1000, 0, &[(1, 20)]))), vec![
+            "warning Source 1 is 1000 ticks behind the other sources; not waiting for it",
+            "frame 1 0 0", "hits 1:1@10",
+        ]);
+        assert_eq!(describe(&d.decode(RingItem::new_with_body_header(END_RUN, 0, 2, 2))), vec![
+            "frame 2 1000 0", "hits 2:1@1020",
+        ]);
+        assert_eq!(describe(&d.decode(RingItem::new_with_body_header(END_RUN, 0, 1, 2))), vec!["end run 2,2"]);
+    }
+    #[test]
+    fn merge_4() {
+        // Events are built across sources, same output threaded or not:
+
+        let items = || vec![
+            RingItem::new_with_body_header(BEGIN_RUN, 0, 1, 1),
+            RingItem::new_with_body_header(BEGIN_RUN, 0, 2, 1),
+            frame_item(1, 0, 0, 0, &[(1, 10), (1, 300)]),
+            frame_item(2, 0, 0, 0, &[(1, 20), (1, 290)]),
+            frame_item(1, 1, 1000, 0, &[(1, 10)]),
+            frame_item(2, 1, 1000, 0, &[(1, 30)]),
+            RingItem::new_with_body_header(END_RUN, 0, 1, 2),
+            RingItem::new_with_body_header(END_RUN, 0, 2, 2),
+        ];
+        let saved = Arc::new(Mutex::new(Vec::new()));
+        let mut source = VecSource { items : items().into_iter() };
+        let mut b = builder(Box::new(VecSink { items : saved.clone() }), false);
+        run_single(&mut source, &mut multi_decoder(), &mut b).unwrap();
+        assert_eq!(b.statistics().events_written, 3);
+        drop(b);
+        let expected = Arc::try_unwrap(saved).unwrap().into_inner().unwrap();
+
+        let saved = Arc::new(Mutex::new(Vec::new()));
+        let sink_items = saved.clone();
+        run_threaded(
+            || Ok(Box::new(VecSource { items : items().into_iter() }) as Box<dyn DataSource>),
+            move || Ok(Box::new(VecSink { items : sink_items }) as Box<dyn DataSink>),
+            &mut multi_decoder(),
+            |sink| builder(sink, false),
+            1
+        ).unwrap();
+        assert_eq!(Arc::try_unwrap(saved).unwrap().into_inner().unwrap(), expected);
+    }
+
+    #[test]
+    fn monitor_1() {
+        // Status lines are made as ops are applied once they're due:
+
+        let (mut b, _saved, _warnings) = watched_builder();
+        let lines = Rc::new(RefCell::new(Vec::new()));
+        let l = lines.clone();
+        b.set_monitor(time::Duration::ZERO, Box::new(move |s| l.borrow_mut().push(String::from(s))));
+        b.apply(GlomOp::BeginRun(RingItem::new(BEGIN_RUN))).unwrap();
+        b.apply(hits(&[1000, 1010])).unwrap();
+        let lines = lines.borrow();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[1].contains(" backlog 2 "));
+        assert!(lines[1].contains(" | 0:1 "));
+    }
+
+    // A builder that keeps the run reports it's given:
+
+    fn reporting_builder() -> (EventBuilder, Rc<RefCell<Vec<report::RunReport>>>) {
+        let (mut b, _saved, _warnings) = watched_builder();
+        let reports = Rc::new(RefCell::new(Vec::new()));
+        let r = reports.clone();
+        b.set_report_handler(Box::new(move |run| r.borrow_mut().push(run.clone())));
+        (b, reports)
+    }
+    fn begin_run(run : u32) -> RingItem {
+        use rust_ringitem_format::state_change::{StateChange, StateChangeType};
+        let header = BodyHeader { timestamp : 0, source_id : 1, barrier_type : 1 };
+        StateChange::new_with_body_header(StateChangeType::Begin, &header, run, 0, 1, "test", Some(1)).to_raw()
+    }
+
+    #[test]
+    fn report_1() {
+        // Each run is reported at its end and the totals add up:
+
+        let (mut b, reports) = reporting_builder();
+        b.apply(GlomOp::BeginRun(begin_run(12))).unwrap();
+        b.apply(GlomOp::FrameBoundary { frame : 1, timestamp : 0, flags : 0 }).unwrap();
+        b.apply(hits(&[1000, 1010])).unwrap();
+        b.apply(GlomOp::Hits(vec![(false, glom::ChannelId::new(0, 2), 1020, 0)])).unwrap();
+        b.apply(GlomOp::Write(RingItem::new(MONITORED_VARIABLES))).unwrap();
+        b.apply(GlomOp::EndRun(vec![RingItem::new(END_RUN)])).unwrap();
+        {
+            let reports = reports.borrow();
+            assert_eq!(reports.len(), 1);
+            let run = &reports[0];
+            assert_eq!(run.run, Some(12));
+            assert_eq!(run.frames, 1);
+            assert_eq!(run.passthrough, 1);
+            assert_eq!(run.hits[&glom::ChannelId::new(0, 1)], report::EdgeCounts { leading : 2, trailing : 0 });
+            assert_eq!(run.hits[&glom::ChannelId::new(0, 2)], report::EdgeCounts { leading : 0, trailing : 1 });
+            assert_eq!(run.glom.events_written, 1);
+            assert_eq!(run.glom.multiplicity, vec![0, 0, 0, 1]);
+        }
+        b.apply(GlomOp::BeginRun(begin_run(13))).unwrap();
+        b.apply(hits(&[5000])).unwrap();
+        b.apply(GlomOp::EndRun(vec![RingItem::new(END_RUN)])).unwrap();
+        let reports = reports.borrow();
+        assert_eq!(reports.len(), 2);
+        assert_eq!(reports[1].run, Some(13));
+        assert_eq!(reports[1].frames, 0);
+        assert_eq!(reports[1].hits.len(), 1);
+        assert_eq!(reports[1].glom.multiplicity, vec![0, 1, 0, 0]);
+
+        let total = b.report();
+        assert_eq!(total.run, None);
+        assert_eq!(total.frames, 1);
+        assert_eq!(total.glom.events_written, 2);
+        assert_eq!(total.glom.multiplicity, vec![0, 1, 0, 1]);
+    }
+    #[test]
+    fn report_2() {
+        // The input ending in a run reports the run; items without run numbers have none:
+
+        let (mut b, reports) = reporting_builder();
+        b.apply(GlomOp::BeginRun(RingItem::new(BEGIN_RUN))).unwrap();
+        b.apply(hits(&[1000])).unwrap();
+        b.apply(GlomOp::EndInput).unwrap();
+        let reports = reports.borrow();
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].run, None);
+        assert_eq!(reports[0].glom.events_written, 1);
+    }
+    #[test]
+    fn report_3() {
+        // The threaded pipeline returns the totals:
+
+        let total = run_threaded(
+            || Ok(Box::new(VecSource { items : run_items().into_iter() }) as Box<dyn DataSource>),
+            || Ok(Box::new(VecSink { items : Arc::new(Mutex::new(Vec::new())) }) as Box<dyn DataSink>),
+            &mut Decoder::new(),
+            |sink| builder(sink, false),
+            1
+        ).unwrap();
+        assert_eq!(total.frames, 20);
+        assert_eq!(total.hits.values().map(|c| c.leading + c.trailing).sum::<u64>(), 200);
+        assert_eq!(total.glom.multiplicity.iter().sum::<u64>(), total.glom.events_written);
+    }
+}
@@ -0,0 +1,320 @@
+//! What defenestrator did in a run (or in all of its input):
+//! the frames read, the hits by channel and edge, the events built, their
+//! multiplicities, the events that span frames, the hits dropped and the items passed through.
+//!
+//! A report can be written as text for people or as a single line JSON object
+//! for programs (e.g. a run database).
+//!
+//! While data are being processed, a RateMonitor turns reports taken every few seconds
+//! into one line summaries of the rates.
+//!
+use crate::glom;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+
+/// Hits counted for a channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct EdgeCounts {
+    pub leading  : u64,
+    pub trailing : u64,
+}
+
+/// The counts of a run or of the whole input.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct RunReport {
+    pub run         : Option<u32>,                          // Run number, None for all runs.
+    pub frames      : u64,                                  // Frames read.
+    pub hits        : BTreeMap<glom::ChannelId, EdgeCounts>,// Hits read by channel.
+    pub passthrough : u64,                                  // Items written unaltered.
+    pub bad_items   : u64,                                  // Frame items that could not be decoded.
+    pub glom        : glom::GlomStatistics,                 // Counts of the event builder.
+}
+impl RunReport {
+    pub fn new() -> RunReport {
+        RunReport::default()
+    }
+    /// Count a hit.
+    ///
+    /// ### Parameters:
+    /// *  leading - true for a leading edge, false for a trailing edge.
+    /// *  id      - channel the hit is on.
+    pub fn add_hit(&mut self, leading : bool, id : glom::ChannelId) {
+        let counts = self.hits.entry(id).or_default();
+        if leading {
+            counts.leading += 1;
+        } else {
+            counts.trailing += 1;
+        }
+    }
+    /// Events built whether or not the event filter accepted them.
+    pub fn events_built(&self) -> u64 {
+        self.glom.events_written + self.glom.events_rejected
+    }
+    /// The report as text; each count on its own line.
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        match self.run {
+            Some(run) => writeln!(text, "Run {} statistics:", run),
+            None => writeln!(text, "Statistics for all runs:"),
+        }.unwrap();
+        let counts = [
+            ("Frames read", self.frames),
+            ("Items passed through", self.passthrough),
+            ("Bad frame items skipped", self.bad_items),
+            ("Events built", self.events_built()),
+            ("Events written", self.glom.events_written),
+            ("Events rejected by the filter", self.glom.events_rejected),
+            ("Events spanning frames", self.glom.crossing_events),
+            ("Out of order hits dropped", self.glom.dropped_hits),
+            ("Hits outside any trigger window", self.glom.untriggered_hits),
+        ];
+        for (name, value) in counts {
+            writeln!(text, "  {:<32} {}", name, value).unwrap();
+        }
+        if !self.hits.is_empty() {
+            writeln!(text, "  Hits by channel:      leading   trailing").unwrap();
+            for (id, counts) in &self.hits {
+                writeln!(text, "    {:<16} {:>10} {:>10}", id.to_string(), counts.leading, counts.trailing).unwrap();
+            }
+        }
+        if self.glom.multiplicity.iter().any(|&n| n > 0) {
+            writeln!(text, "  Events written by multiplicity:").unwrap();
+            for (n, &events) in self.glom.multiplicity.iter().enumerate().filter(|(_, e)| **e > 0) {
+                writeln!(text, "    {:<16} {:>10}", n, events).unwrap();
+            }
+        }
+        text
+    }
+    /// The report as a one line JSON object e.g.
+    ///
+    /// ```text
+    /// {"run":12,"frames":100,"passthrough_items":2,"bad_frame_items":0,"events_built":50,
+    ///  "events_written":48,"events_rejected":2,"events_spanning_frames":4,"dropped_hits":0,
+    ///  "untriggered_hits":0,"hits":[{"source":1,"channel":3,"leading":40,"trailing":40}],
+    ///  "multiplicity":[0,20,28]}
+    /// ```
+    ///
+    /// ### Notes:
+    /// *  run is null for the report of all runs.
+    /// *  multiplicity\[n\] is the number of events written with n hits.
+    pub fn to_json(&self) -> String {
+        let mut json = String::from("{");
+        match self.run {
+            Some(run) => write!(json, "\"run\":{}", run),
+            None => write!(json, "\"run\":null"),
+        }.unwrap();
+        let counts = [
+            ("frames", self.frames),
+            ("passthrough_items", self.passthrough),
+            ("bad_frame_items", self.bad_items),
+            ("events_built", self.events_built()),
+            ("events_written", self.glom.events_written),
+            ("events_rejected", self.glom.events_rejected),
+            ("events_spanning_frames", self.glom.crossing_events),
+            ("dropped_hits", self.glom.dropped_hits),
+            ("untriggered_hits", self.glom.untriggered_hits),
+        ];
+        for (name, value) in counts {
+            write!(json, ",\"{}\":{}", name, value).unwrap();
+        }
+        let hits : Vec<String> = self.hits.iter()
+            .map(|(id, c)| format!(
+                "{{\"source\":{},\"channel\":{},\"leading\":{},\"trailing\":{}}}",
+                id.source, id.channel, c.leading, c.trailing
+            ))
+            .collect();
+        let multiplicity : Vec<String> = self.glom.multiplicity.iter().map(|n| n.to_string()).collect();
+        write!(json, ",\"hits\":[{}],\"multiplicity\":[{}]}}", hits.join(","), multiplicity.join(",")).unwrap();
+        json
+    }
+}
+/// The counts of a run are the counts at its end - those at its beginning.
+/// The run number is that of the end.
+impl std::ops::Sub for RunReport {
+    type Output = RunReport;
+    fn sub(self, start : RunReport) -> RunReport {
+        let hits = self.hits.iter()
+            .map(|(id, c)| {
+                let s = start.hits.get(id).copied().unwrap_or_default();
+                (*id, EdgeCounts { leading : c.leading - s.leading, trailing : c.trailing - s.trailing })
+            })
+            .filter(|(_, c)| c.leading + c.trailing > 0)
+            .collect();
+        RunReport {
+            run         : self.run,
+            frames      : self.frames - start.frames,
+            hits,
+            passthrough : self.passthrough - start.passthrough,
+            bad_items   : self.bad_items - start.bad_items,
+            glom        : self.glom - start.glom,
+        }
+    }
+}
+
+/// Makes status lines of the rates at which things are done from reports taken
+/// every so often.
+pub struct RateMonitor {
+    interval : Duration,
+    last     : Instant,         // When the last status line was made.
+    previous : RunReport,       // The counts then.
+}
+impl RateMonitor {
+    /// ### Parameters:
+    /// *  interval - the time between status lines.
+    pub fn new(interval : Duration) -> RateMonitor {
+        RateMonitor { interval, last : Instant::now(), previous : RunReport::new() }
+    }
+    /// True if it's been at least the interval since the last status line.
+    pub fn due(&self) -> bool {
+        self.last.elapsed() >= self.interval
+    }
+    /// Make the status line for the counts now.  The rates are those since the last
+    /// status line (or since the monitor was made).
+    ///
+    /// ### Parameters:
+    /// *  now     - the counts of everything done so far.
+    /// *  backlog - the hits held waiting to be put in events.
+    pub fn status(&mut self, now : RunReport, backlog : usize) -> String {
+        let seconds = self.last.elapsed().as_secs_f64();
+        let line = status_line(&(now.clone() - std::mem::take(&mut self.previous)), &now, seconds, backlog);
+        self.previous = now;
+        self.last = Instant::now();
+        line
+    }
+}
+
+/// Format a status line e.g.
+///
+/// ```text
+/// frames 10.0/s hits 2000.0/s events 45.2/s written 45.0/s backlog 37 dropped 0 rejected 2 untriggered 0 | 0:0 1000.0/s 0:1 1000.0/s
+/// ```
+///
+/// ### Parameters:
+/// *  delta   - the counts in the interval.
+/// *  total   - the counts of everything done; the dropped, rejected and untriggered counts are these.
+/// *  seconds - length of the interval.
+/// *  backlog - hits held.
+///
+/// ### Notes:
+/// The hit rates of channels with hits in the interval follow the ```|```.
+pub fn status_line(delta : &RunReport, total : &RunReport, seconds : f64, backlog : usize) -> String {
+    let rate = |n : u64| if seconds > 0.0 { n as f64 / seconds } else { 0.0 };
+    let hits = delta.hits.values().map(|c| c.leading + c.trailing).sum();
+    let mut line = format!(
+        "frames {:.1}/s hits {:.1}/s events {:.1}/s written {:.1}/s backlog {} dropped {} rejected {} untriggered {}",
+        rate(delta.frames), rate(hits), rate(delta.events_built()), rate(delta.glom.events_written),
+        backlog, total.glom.dropped_hits, total.glom.events_rejected, total.glom.untriggered_hits
+    );
+    if !delta.hits.is_empty() {
+        line.push_str(" |");
+        for (id, c) in &delta.hits {
+            write!(line, " {} {:.1}/s", id, rate(c.leading + c.trailing)).unwrap();
+        }
+    }
+    line
+}
+
+#[cfg(test)]
+mod report_tests {
+    use super::*;
+    use crate::glom::ChannelId;
+
+    fn example() -> RunReport {
+        let mut report = RunReport::new();
+        report.run = Some(12);
+        report.frames = 100;
+        report.passthrough = 2;
+        report.bad_items = 1;
+        report.add_hit(true, ChannelId::new(1, 3));
+        report.add_hit(false, ChannelId::new(1, 3));
+        report.add_hit(true, ChannelId::new(0, 7));
+        report.glom.events_written = 2;
+        report.glom.events_rejected = 1;
+        report.glom.crossing_events = 1;
+        report.glom.multiplicity = vec![0, 1, 1];
+        report
+    }
+
+    #[test]
+    fn hits_1() {
+        let report = example();
+        assert_eq!(report.hits.len(), 2);
+        assert_eq!(report.hits[&ChannelId::new(1, 3)], EdgeCounts { leading : 1, trailing : 1 });
+        assert_eq!(report.hits[&ChannelId::new(0, 7)], EdgeCounts { leading : 1, trailing : 0 });
+        assert_eq!(report.events_built(), 3);
+    }
+    #[test]
+    fn json_1() {
+        assert_eq!(
+            example().to_json(),
+            "{\"run\":12,\"frames\":100,\"passthrough_items\":2,\"bad_frame_items\":1,\"events_built\":3,\"events_written\":2,\
+             \"events_rejected\":1,\"events_spanning_frames\":1,\"dropped_hits\":0,\"untriggered_hits\":0,\
+             \"hits\":[{\"source\":0,\"channel\":7,\"leading\":1,\"trailing\":0},\
+             {\"source\":1,\"channel\":3,\"leading\":1,\"trailing\":1}],\"multiplicity\":[0,1,1]}"
+        );
+        assert_eq!(
+            RunReport::new().to_json(),
+            "{\"run\":null,\"frames\":0,\"passthrough_items\":0,\"bad_frame_items\":0,\"events_built\":0,\"events_written\":0,\
+             \"events_rejected\":0,\"events_spanning_frames\":0,\"dropped_hits\":0,\"untriggered_hits\":0,\
+             \"hits\":[],\"multiplicity\":[]}"
+        );
+    }
+    #[test]
+    fn text_1() {
+        let text = example().to_text();
+        assert!(text.starts_with("Run 12 statistics:\n"));
+        assert!(text.contains("Frames read                      100\n"));
+        assert!(text.contains("Events spanning frames           1\n"));
+        assert!(text.contains("Bad frame items skipped          1\n"));
+        assert!(text.contains("    1:3                       1          1\n"));
+        assert!(text.contains("    2                         1\n"));
+        assert!(!text.contains("    0                "));        // Empty bins are left out.
+        assert!(RunReport::new().to_text().starts_with("Statistics for all runs:\n"));
+    }
+    #[test]
+    fn sub_1() {
+        let end = example();
+        let mut start = RunReport::new();
+        start.frames = 40;
+        start.add_hit(true, ChannelId::new(0, 7));
+        start.glom.events_written = 1;
+        start.glom.multiplicity = vec![0, 1];
+        let run = end - start;
+        assert_eq!(run.run, Some(12));
+        assert_eq!(run.frames, 60);
+        assert_eq!(run.bad_items, 1);
+        assert_eq!(run.hits.len(), 1);                  // Channel 0:7 had no hits in the run.
+        assert_eq!(run.glom.events_written, 1);
+        assert_eq!(run.glom.multiplicity, vec![0, 0, 1]);
+    }
+    #[test]
+    fn status_1() {
+        let mut delta = example();
+        delta.glom.dropped_hits = 1;
+        let mut total = example();
+        total.glom.dropped_hits = 5;
+        assert_eq!(
+            status_line(&delta, &total, 2.0, 37),
+            "frames 50.0/s hits 1.5/s events 1.5/s written 1.0/s backlog 37 dropped 5 rejected 1 untriggered 0 \
+             | 0:7 0.5/s 1:3 1.0/s"
+        );
+        assert_eq!(
+            status_line(&RunReport::new(), &RunReport::new(), 0.0, 0),
+            "frames 0.0/s hits 0.0/s events 0.0/s written 0.0/s backlog 0 dropped 0 rejected 0 untriggered 0"
+        );
+    }
+    #[test]
+    fn monitor_1() {
+        // Rates are of what's been done since the last status line:
+
+        let mut monitor = RateMonitor::new(Duration::from_secs(3600));
+        assert!(!monitor.due());
+        let first = example();
+        assert!(monitor.status(first.clone(), 0).contains(" | 0:7 "));
+        let line = monitor.status(first, 0);
+        assert!(line.starts_with("frames 0.0/s hits 0.0/s"));
+        assert!(!line.contains('|'));
+        assert!(RateMonitor::new(Duration::ZERO).due());
+    }
+}
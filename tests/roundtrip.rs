@@ -0,0 +1,158 @@
+// Round trips raw mikumari data through mikumarimaker and mikumariraw.
+
+use mikumarimaker::mikumari_format::{
+    Delimeter1, Delimeter2, HRTDCLeading, HRTDCTrailing, MIKUMARI_FRAME_ITEM_TYPE, INPUT_THROTTLE_T1_END,
+};
+use frib_datasource::{data_source_factory, data_sink_factory};
+use rust_ringitem_format::RingItem;
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+
+// A file in the temp directory that's removed when the test is done:
+
+struct TempFile(PathBuf);
+impl TempFile {
+    fn new(name : &str) -> TempFile {
+        TempFile(std::env::temp_dir().join(format!("mikumari-roundtrip-{}-{}", std::process::id(), name)))
+    }
+    fn path(&self) -> &str {
+        self.0.to_str().unwrap()
+    }
+    fn uri(&self) -> String {
+        format!("file://{}", self.path())
+    }
+}
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+fn write_raw(file : &TempFile, words : &[u64]) {
+    let bytes : Vec<u8> = words.iter().flat_map(|w| w.to_ne_bytes()).collect();
+    fs::write(&file.0, bytes).unwrap();
+}
+fn read_raw(file : &TempFile) -> Vec<u64> {
+    fs::read(&file.0).unwrap().chunks(8).map(|w| u64::from_ne_bytes(w.try_into().unwrap())).collect()
+}
+fn run(program : &str, args : &[&str]) {
+    let status = Command::new(program).args(args).status().unwrap();
+    assert!(status.success(), "{} {:?} failed", program, args);
+}
+// raw -> ring items:
+
+fn make(raw : &TempFile, items : &TempFile) {
+    run(env!("CARGO_BIN_EXE_mikumarimaker"), &["--source-id", "3", raw.path(), &items.uri()]);
+}
+// ring items -> raw:
+
+fn unmake(items : &TempFile, raw : &TempFile) {
+    run(env!("CARGO_BIN_EXE_mikumariraw"), &[&items.uri(), raw.path()]);
+}
+// The payloads of the frame items in a file:
+
+fn frame_payloads(items : &TempFile) -> Vec<Vec<u8>> {
+    let mut source = data_source_factory(&items.uri()).unwrap();
+    let mut result = Vec::new();
+    while let Some(item) = source.read() {
+        if item.type_id() == MIKUMARI_FRAME_ITEM_TYPE {
+            result.push(item.payload().clone());
+        }
+    }
+    result
+}
+
+fn d1(flags : u64, frame : u32) -> u64 {
+    Delimeter1::new(0, frame).get() | (flags << 40)
+}
+fn d2(user : u64, size : u32) -> u64 {
+    Delimeter2::new(size).get() | (user << 40)
+}
+fn le(chan : u8, time : u32) -> u64 {
+    HRTDCLeading::new(chan, 100, time).get()
+}
+fn te(chan : u8, time : u32) -> u64 {
+    HRTDCTrailing::new(chan, 100, time).get()
+}
+
+#[test]
+fn roundtrip_1() {
+    // Well formed frames come back as they were:
+
+    let frames = vec![
+        d1(0, 100), d2(0, 24), le(1, 10), te(1, 20), le(2, 30),
+        d1(0x840, 101), d2(5, 0),
+        d1(0, 102), d2(0, 8), le(7, 1000),
+    ];
+    let (raw, items, back) = (TempFile::new("r1.raw"), TempFile::new("r1.evt"), TempFile::new("r1.back"));
+    write_raw(&raw, &frames);
+    make(&raw, &items);
+    unmake(&items, &back);
+    assert_eq!(read_raw(&back), frames);
+}
+#[test]
+fn roundtrip_2() {
+    // The partial frame and throttle words are dropped by mikumarimaker and the
+    // second delimiter sizes are regenerated for what's left:
+
+    let throttle = (INPUT_THROTTLE_T1_END as u64) << 58;
+    let input = vec![
+        le(1, 5), te(1, 6),                                           // Partial frame.
+        d1(0, 100), d2(3, 64), le(1, 10), throttle, te(1, 20),        // Firmware size bug.
+        d1(0, 101), d2(0, 8), throttle,
+    ];
+    let expected = vec![
+        d1(0, 100), d2(3, 16), le(1, 10), te(1, 20),
+        d1(0, 101), d2(0, 0),
+    ];
+    let (raw, items, back) = (TempFile::new("r2.raw"), TempFile::new("r2.evt"), TempFile::new("r2.back"));
+    write_raw(&raw, &input);
+    make(&raw, &items);
+    unmake(&items, &back);
+    assert_eq!(read_raw(&back), expected);
+}
+#[test]
+fn roundtrip_3() {
+    // Items made from the regenerated raw data are the same as the originals:
+
+    let mut input = vec![le(0, 1)];
+    for frame in 0..50u32 {
+        input.push(d1(0, (0xfffff0 + frame) & 0xffffff));             // Frame numbers wrap.
+        input.push(d2(0, 8 * (frame % 4)));
+        for hit in 0..frame % 4 {
+            input.push(if hit % 2 == 0 { le(hit as u8, frame * 10 + hit) } else { te(hit as u8, frame * 10 + hit) });
+        }
+    }
+    let (raw, items, back, items2) = (
+        TempFile::new("r3.raw"), TempFile::new("r3.evt"), TempFile::new("r3.back"), TempFile::new("r3.evt2")
+    );
+    write_raw(&raw, &input);
+    make(&raw, &items);
+    unmake(&items, &back);
+    make(&back, &items2);
+
+    let original = frame_payloads(&items);
+    assert_eq!(original.len(), 50);
+    assert_eq!(frame_payloads(&items2), original);
+}
+#[test]
+fn roundtrip_4() {
+    // Frame items in the old layout (hits only after the frame number) get delimiters
+    // made from the frame number and sized for the hits:
+
+    let (items, back) = (TempFile::new("r4.evt"), TempFile::new("r4.back"));
+    let mut sink = data_sink_factory(&items.uri()).unwrap();
+    for (frame, hits) in [(0x1000064u64, vec![le(1, 10), te(1, 20)]), (0x1000065, vec![])] {
+        let mut item = RingItem::new_with_body_header(MIKUMARI_FRAME_ITEM_TYPE, 0, 3, 0);
+        item.add(frame);
+        for hit in hits {
+            item.add(hit);
+        }
+        sink.write(&item).unwrap();
+    }
+    sink.close();
+
+    unmake(&items, &back);
+    assert_eq!(read_raw(&back), vec![d1(0, 100), d2(0, 16), le(1, 10), te(1, 20), d1(0, 101), d2(0, 0)]);
+}